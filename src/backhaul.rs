@@ -8,8 +8,10 @@ use std::{
 use crate::buffer::{Buff, BuffMut};
 
 /// A trait that represents a datagram backhaul. This presents an interface similar to that of "PacketConn" in Go, and it is used to abstract over different kinds of datagram transports.
+///
+/// Implement this trait (with [async_trait](https://docs.rs/async-trait)) to run sosistab over your own transport, using [Protocol::Custom](crate::Protocol::Custom) on the client and [Listener::listen_custom](crate::Listener::listen_custom) on the server. Backhauls are unreliable: they may drop, reorder, or duplicate datagrams, but should not corrupt or truncate them.
 #[async_trait::async_trait]
-pub(crate) trait Backhaul: Send + Sync {
+pub trait Backhaul: Send + Sync {
     /// Sends a datagram
     async fn send_to(&self, to_send: Buff, dest: SocketAddr) -> io::Result<()>;
 
@@ -18,7 +20,7 @@ pub(crate) trait Backhaul: Send + Sync {
 }
 
 /// A structure that wraps a Backhaul with statistics.
-pub(crate) struct StatsBackhaul<B: Backhaul + 'static> {
+pub struct StatsBackhaul<B: Backhaul + 'static> {
     haul: Arc<B>,
     on_recv: Box<dyn Fn(usize, SocketAddr) + Send + Sync>,
     on_send: Box<dyn Fn(usize, SocketAddr) + Send + Sync>,
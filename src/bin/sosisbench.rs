@@ -10,7 +10,7 @@ use argh::FromArgs;
 
 use once_cell::sync::Lazy;
 
-use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
 use smol::prelude::*;
 use sosistab::{Buff, ClientConfig, Protocol};
 
@@ -29,6 +29,7 @@ enum Subcmds {
     Server(ServerArgs),
     Flood(FloodArgs),
     SelfTest(SelfTestArgs),
+    Aead(AeadArgs),
 }
 
 /// Client
@@ -67,7 +68,23 @@ struct ServerArgs {
 /// Self test
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "selftest")]
-struct SelfTestArgs {}
+struct SelfTestArgs {
+    #[argh(option)]
+    /// run over an in-memory network losing this fraction of packets, instead of over loopback UDP
+    memory_loss: Option<f64>,
+}
+
+/// Compare the per-packet cost of the AEAD suites
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "aead")]
+struct AeadArgs {
+    #[argh(option, default = "1400")]
+    /// packet size in bytes
+    packet_size: usize,
+    #[argh(option, default = "1000000")]
+    /// number of packets to encrypt with each suite
+    count: usize,
+}
 
 // #[global_allocator]
 // static ALLOCATOR: dhat::DhatAlloc = dhat::DhatAlloc;
@@ -78,6 +95,10 @@ fn main() -> anyhow::Result<()> {
         Subcmds::Flood(flood) => smolscale::block_on(flood_main(flood)),
         Subcmds::Client(client) => smolscale::block_on(client_main(client)),
         Subcmds::Server(server) => smolscale::block_on(server_main(server)),
+        Subcmds::Aead(aead) => aead_main(aead),
+        Subcmds::SelfTest(SelfTestArgs {
+            memory_loss: Some(loss),
+        }) => smolscale::block_on(memory_selftest(loss)),
         Subcmds::SelfTest(_) => {
             let client_args = ClientArgs {
                 connect: "127.0.0.1:19999".into(),
@@ -96,8 +117,38 @@ fn main() -> anyhow::Result<()> {
     }
 }
 
-static SNAKEOIL_SK: Lazy<x25519_dalek::StaticSecret> =
-    Lazy::new(|| x25519_dalek::StaticSecret::new(&mut rand_chacha::ChaCha8Rng::seed_from_u64(0)));
+static SNAKEOIL_SK: Lazy<x25519_dalek::StaticSecret> = Lazy::new(|| {
+    let mut bytes = [0u8; 32];
+    rand_chacha::ChaCha8Rng::seed_from_u64(0).fill_bytes(&mut bytes);
+    x25519_dalek::StaticSecret::from(bytes)
+});
+
+fn aead_main(args: AeadArgs) -> anyhow::Result<()> {
+    for suite in [
+        sosistab::AeadSuite::ChaCha20Poly1305,
+        sosistab::AeadSuite::Aes256Gcm,
+    ] {
+        // sessions encrypt every packet like this, so this includes the cost of the nonce masking
+        let aead = sosistab::NgAead::with_suite(suite, &[0u8; 32]);
+        let packet = vec![0u8; args.packet_size];
+        let start = Instant::now();
+        for i in 0..args.count {
+            std::hint::black_box(aead.encrypt_counter(i as u64, &packet));
+        }
+        let elapsed = start.elapsed();
+        eprintln!(
+            "{:?}: {:.1} ns/packet ({:.1} MB/s)",
+            suite,
+            elapsed.as_nanos() as f64 / args.count as f64,
+            (args.packet_size * args.count) as f64 / 1048576.0 / elapsed.as_secs_f64()
+        );
+    }
+    eprintln!(
+        "preferred on this machine: {:?}",
+        sosistab::AeadSuite::preferred()
+    );
+    Ok(())
+}
 
 async fn flood_main(args: FloodArgs) -> anyhow::Result<()> {
     let server_addr = smol::net::resolve(&args.connect)
@@ -139,10 +190,34 @@ async fn flood_main(args: FloodArgs) -> anyhow::Result<()> {
     smol::future::pending().await
 }
 
+async fn memory_selftest(loss: f64) -> anyhow::Result<()> {
+    let network = sosistab::MemoryNetwork::new(0);
+    let mut impairments = sosistab::Impairments::new();
+    impairments.loss = sosistab::LossModel::Bernoulli(loss);
+    impairments.delay = Duration::from_millis(25);
+    let server_addr: SocketAddr = "10.0.0.1:19999".parse().unwrap();
+    let listener = sosistab::Listener::listen_custom(
+        server_addr,
+        network.bind(server_addr, impairments.clone()),
+        SNAKEOIL_SK.clone(),
+        |_, _| (),
+        |_, _| (),
+    )
+    .await?;
+    let backhaul_gen: sosistab::BackhaulGen =
+        Arc::new(move || Arc::new(network.bind_ephemeral(impairments.clone())));
+    let cfg = ClientConfig::new(
+        Protocol::Custom(backhaul_gen),
+        server_addr,
+        (&*SNAKEOIL_SK).into(),
+        Default::default(),
+    );
+    smolscale::spawn(download(cfg)).detach();
+    serve(|| listener.accept_session()).await
+}
+
 async fn client_main(args: ClientArgs) -> anyhow::Result<()> {
-    // smolscale::permanently_single_threaded();
-    let start = Instant::now();
-    let mut cfg = ClientConfig::new(
+    let cfg = ClientConfig::new(
         if args.use_tcp {
             Protocol::DirectTcp
         } else {
@@ -154,6 +229,12 @@ async fn client_main(args: ClientArgs) -> anyhow::Result<()> {
         (&*SNAKEOIL_SK).into(),
         Default::default(),
     );
+    download(cfg).await
+}
+
+async fn download(mut cfg: ClientConfig) -> anyhow::Result<()> {
+    // smolscale::permanently_single_threaded();
+    let start = Instant::now();
     cfg.shard_count = 1;
     cfg.reset_interval = Some(Duration::from_secs(30));
     let session = cfg.connect().await.context("cannot connect to sosistab")?;
@@ -191,10 +272,19 @@ async fn server_main(args: ServerArgs) -> anyhow::Result<()> {
     let listener_tcp =
         sosistab::Listener::listen_tcp(args.listen, SNAKEOIL_SK.clone(), |_, _| (), |_, _| ())
             .await?;
-    for count in 1u128..3 {
-        let session = listener_udp
+    serve(|| {
+        listener_udp
             .accept_session()
             .race(listener_tcp.accept_session())
+    })
+    .await
+}
+
+async fn serve<F: Future<Output = Option<sosistab::Session>>>(
+    accept: impl Fn() -> F,
+) -> anyhow::Result<()> {
+    for count in 1u128..3 {
+        let session = accept()
             .await
             .ok_or_else(|| anyhow::anyhow!("failed to accept"))?;
         eprintln!("accepted session {}", count);
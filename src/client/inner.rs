@@ -1,181 +1,430 @@
-use crate::{buffer::Buff, crypt};
-use crate::{protocol, runtime, Backhaul, Session, SessionConfig, StatsGatherer};
+use crate::session::{ResumeTicket, SessionKeys, REKEY_FRAMES};
+use crate::{buffer::Buff, crypt, AeadSuite, CoverTraffic, HandshakePadding, PaddingProfile};
+use crate::{protocol, runtime, BackhaulGen, Session, SessionBack, SessionConfig, StatsGatherer};
+use crate::{transform::TransformBackhaul, PacketTransform};
+use crate::{ConnectError, RetryPolicy};
 
 use probability::distribution::{Binomial, Distribution};
 use smallvec::SmallVec;
-use smol::{prelude::*, Task};
+use smol::{channel::Receiver, prelude::*, Task};
 use std::{
     collections::VecDeque,
     net::SocketAddr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
-use super::worker::ClientWorker;
+use super::{
+    path::{usable_paths, PathStats},
+    worker::ClientWorker,
+};
 
 /// Configures the client.
 #[derive(Clone)]
 pub(crate) struct LowlevelClientConfig {
     pub server_addr: SocketAddr,
     pub server_pubkey: x25519_dalek::PublicKey,
-    pub backhaul_gen: Arc<dyn Fn() -> Arc<dyn Backhaul> + 'static + Send + Sync>,
+    pub client_sk: x25519_dalek::StaticSecret,
+    pub backhaul_gen: BackhaulGen,
     pub num_shards: usize,
     pub reset_interval: Option<Duration>,
     pub gather: Arc<StatsGatherer>,
+    pub post_quantum: bool,
+    pub aead_suites: Vec<AeadSuite>,
+    pub padding: PaddingProfile,
+    pub cover: Option<CoverTraffic>,
+    pub handshake_padding: HandshakePadding,
+    pub transforms: Vec<Arc<dyn PacketTransform>>,
+    pub retry: RetryPolicy,
+    pub deadline: Option<Duration>,
+    pub extra_routes: Vec<Route>,
+    pub migrations: Option<Receiver<Migration>>,
+    /// How long paths replaced by a migration keep receiving.
+    pub migration_grace: Duration,
+    /// Whether servers answering with a bare ServerHello are accepted.
+    pub allow_legacy_servers: bool,
+    /// Round-trip time and loss estimates for the path this configures.
+    pub path_stats: Arc<PathStats>,
+    /// Whether the session has several paths to choose between, so that it's worth probing them. Shared by all of a session's paths.
+    pub probe_paths: Arc<AtomicBool>,
+}
+
+/// A way of reaching the server, which a session has a path over.
+#[derive(Clone)]
+pub(crate) struct Route {
+    pub backhaul_gen: BackhaulGen,
+    pub server_addr: SocketAddr,
+}
+
+/// A change to the paths a session sends and receives over.
+pub(crate) enum Migration {
+    /// Replaces the primary path, keeping any others.
+    Primary(Route),
+    /// Replaces every path. The first route becomes the primary path.
+    All(Vec<Route>),
+}
+
+/// Wraps every backhaul from the given function with the given transforms.
+fn with_transforms(
+    backhaul_gen: BackhaulGen,
+    transforms: &[Arc<dyn PacketTransform>],
+) -> BackhaulGen {
+    if transforms.is_empty() {
+        return backhaul_gen;
+    }
+    let transforms = transforms.to_vec();
+    Arc::new(move || Arc::new(TransformBackhaul::new(backhaul_gen(), transforms.clone())))
 }
 
 /// Connects to a remote server, given a closure that generates socket addresses.
-pub(crate) async fn connect_custom(cfg: LowlevelClientConfig) -> std::io::Result<Session> {
-    let my_long_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
-    let my_eph_sk = x25519_dalek::StaticSecret::new(rand::thread_rng());
+pub(crate) async fn connect_custom(cfg: LowlevelClientConfig) -> Result<Session, ConnectError> {
+    if let Some(deadline) = cfg.deadline {
+        handshake(cfg)
+            .or(async {
+                smol::Timer::after(deadline).await;
+                Err(ConnectError::Timeout)
+            })
+            .await
+    } else {
+        handshake(cfg).await
+    }
+}
+
+async fn handshake(cfg: LowlevelClientConfig) -> Result<Session, ConnectError> {
+    // disguise every backhaul with the configured transforms
+    let backhaul_gen = with_transforms(cfg.backhaul_gen.clone(), &cfg.transforms);
+    let my_long_sk = cfg.client_sk.clone();
+    let my_eph_sk = x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>());
     // do the handshake
     let cookie = crypt::Cookie::new(cfg.server_pubkey);
     let init_hello = protocol::HandshakeFrame::ClientHello {
         long_pk: (&my_long_sk).into(),
         eph_pk: (&my_eph_sk).into(),
-        version: VERSION,
+        version: protocol::MIN_VERSION,
+    };
+    // ML-KEM secret for a hybrid post-quantum handshake
+    let kem_secret: Option<(crypt::KemSecret, Buff)> = if cfg.post_quantum {
+        #[cfg(feature = "post-quantum")]
+        {
+            Some(crypt::KemSecret::generate())
+        }
+        #[cfg(not(feature = "post-quantum"))]
+        return Err(ConnectError::NegotiationFailed(
+            "built without the post-quantum feature",
+        ));
+    } else {
+        None
     };
-    for timeout_factor in (0u32..).map(|x| 2u64.pow(x.min(10))) {
-        let backhaul = (cfg.backhaul_gen)();
+    // challenge the server asked us to echo, if any
+    let mut challenge: Option<Buff> = None;
+    let mut timeout = cfg.retry.initial_timeout;
+    let mut unanswered = 0u32;
+    loop {
+        let backhaul = backhaul_gen();
         // send hello
+        let mut hello_frames = vec![
+            init_hello.clone(),
+            protocol::HandshakeFrame::SupportedVersions {
+                min: protocol::MIN_VERSION,
+                max: protocol::MAX_VERSION,
+            },
+            protocol::HandshakeFrame::AeadOffer {
+                suites: cfg.aead_suites.clone(),
+            },
+        ];
+        if let Some((_, encaps_key)) = &kem_secret {
+            hello_frames.push(protocol::HandshakeFrame::KemOffer {
+                encaps_key: encaps_key.clone(),
+            });
+        }
+        if let Some(challenge) = challenge.clone() {
+            hello_frames.push(protocol::HandshakeFrame::ChallengeEcho { challenge });
+        }
+        // a hybrid hello is longer than the bottom of the default padding range, and would otherwise always have the same length. the server also ignores hellos shorter than its answer
+        let hello_len = hello_frames
+            .iter()
+            .map(|frame| frame.to_bytes().len())
+            .sum::<usize>()
+            .max(protocol::max_server_hello_len(cfg.post_quantum));
         let init_hello = crypt::LegacyAead::new(&cookie.generate_c2s().next().unwrap())
-            .pad_encrypt_v1(std::slice::from_ref(&init_hello), 1000);
+            .pad_encrypt_v1(&hello_frames, cfg.handshake_padding.sample_above(hello_len));
         backhaul.send_to(init_hello, cfg.server_addr).await?;
         tracing::trace!("sent client hello");
-        // wait for response
-        let res = backhaul
-            .recv_from()
-            .or(async {
-                smol::Timer::after(Duration::from_secs(timeout_factor.min(10))).await;
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::TimedOut,
-                    "timed out",
-                ))
-            })
-            .await;
-        match res {
-            Ok((buf, _)) => {
-                for possible_key in cookie.generate_s2c() {
-                    let decrypter = crypt::LegacyAead::new(&possible_key);
-                    let response = decrypter.pad_decrypt_v1(&buf);
-                    for response in response.unwrap_or_default() {
-                        if let protocol::HandshakeFrame::ServerHello {
-                            long_pk,
-                            eph_pk,
-                            resume_token,
-                        } = response
-                        {
-                            tracing::trace!("obtained response from server");
-                            if long_pk.as_bytes() != cfg.server_pubkey.as_bytes() {
-                                return Err(std::io::Error::new(
-                                    std::io::ErrorKind::ConnectionRefused,
-                                    "bad pubkey",
-                                ));
+        // wait out the timeout for a reply we can use, ignoring anything else that arrives
+        let wait_until = Instant::now() + timeout;
+        let mut challenged = false;
+        while !challenged {
+            let res = backhaul
+                .recv_from()
+                .or(async {
+                    smol::Timer::at(wait_until).await;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "timed out",
+                    ))
+                })
+                .await;
+            let buf = match res {
+                Ok((buf, _)) => buf,
+                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => break,
+                Err(err) => return Err(err.into()),
+            };
+            for possible_key in cookie.generate_s2c() {
+                let decrypter = crypt::LegacyAead::new(&possible_key);
+                let response = decrypter.pad_decrypt_v1(&buf).unwrap_or_default();
+                let kem_reply = response.iter().find_map(|frame| match frame {
+                    protocol::HandshakeFrame::KemReply { ciphertext } => Some(ciphertext.clone()),
+                    _ => None,
+                });
+                // servers that predate negotiation leave these out
+                let version = response.iter().find_map(|frame| match frame {
+                    protocol::HandshakeFrame::ChosenVersion { version } => Some(*version),
+                    _ => None,
+                });
+                let aead = response.iter().find_map(|frame| match frame {
+                    protocol::HandshakeFrame::ChosenAead { suite } => Some(*suite),
+                    _ => None,
+                });
+                for response in response {
+                    if let protocol::HandshakeFrame::ServerChallenge {
+                        challenge: new_challenge,
+                    } = response
+                    {
+                        tracing::trace!("server challenged us; echoing the challenge");
+                        challenge = Some(new_challenge);
+                        challenged = true;
+                        continue;
+                    }
+                    if let protocol::HandshakeFrame::ServerHello {
+                        long_pk,
+                        eph_pk,
+                        resume_token,
+                    } = response
+                    {
+                        // whoever sent this knows the cookie, but that doesn't make them our server
+                        if long_pk.as_bytes() != cfg.server_pubkey.as_bytes() {
+                            tracing::debug!("ignoring a ServerHello with the wrong long-term key");
+                            continue;
+                        }
+                        tracing::trace!("obtained response from server");
+                        let (version, aead, negotiated) = match (version, aead) {
+                            (Some(version), Some(aead)) => (version, aead, true),
+                            // they only ever read our ClientHello, and use its version with ChaCha20/Poly1305
+                            (None, None) if cfg.allow_legacy_servers => {
+                                (protocol::MIN_VERSION, AeadSuite::ChaCha20Poly1305, false)
+                            }
+                            _ => {
+                                return Err(ConnectError::NegotiationFailed(
+                                    "server did not confirm the version and AEAD suite",
+                                ))
                             }
-                            let shared_sec =
-                                crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
-                            return Ok(init_session(cookie, resume_token, shared_sec, cfg.clone()));
+                        };
+                        if !(protocol::MIN_VERSION..=protocol::MAX_VERSION).contains(&version) {
+                            return Err(ConnectError::VersionRejected(version));
+                        }
+                        if !cfg.aead_suites.contains(&aead) {
+                            return Err(ConnectError::NegotiationFailed(
+                                "server chose an unsupported AEAD suite",
+                            ));
+                        }
+                        let mut shared_sec =
+                            crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
+                        if let Some((kem_secret, _)) = &kem_secret {
+                            // we must never silently fall back to a classical handshake, or an active attacker could strip the KEM
+                            let kem_secret = kem_reply
+                                .and_then(|ciphertext| kem_secret.decapsulate(&ciphertext))
+                                .ok_or(ConnectError::NegotiationFailed(
+                                    "server did not complete the post-quantum handshake",
+                                ))?;
+                            shared_sec = crypt::hybrid_mix(shared_sec, &kem_secret);
+                        }
+                        // the server mixes in what it saw us offer, so tampering with the negotiation leaves us with different keys
+                        if negotiated {
+                            shared_sec = crypt::negotiation_mix(
+                                shared_sec,
+                                (protocol::MIN_VERSION, protocol::MAX_VERSION),
+                                &cfg.aead_suites,
+                                version,
+                                aead,
+                            );
                         }
+                        return Ok(init_session(
+                            cookie,
+                            resume_token,
+                            shared_sec,
+                            version,
+                            aead,
+                            cfg.clone(),
+                        ));
                     }
                 }
             }
-            Err(err) => {
-                if err.kind() == std::io::ErrorKind::TimedOut {
-                    tracing::trace!(
-                        "timed out to {} with {}s timeout; trying again",
-                        cfg.server_addr,
-                        timeout_factor
-                    );
-                    continue;
-                }
-                return Err(err);
-            }
         }
+        // the server answered, so echo its challenge straight away
+        if challenged {
+            continue;
+        }
+        unanswered += 1;
+        if cfg.retry.max_attempts.map(|max| unanswered >= max) == Some(true) {
+            return Err(ConnectError::Timeout);
+        }
+        tracing::trace!(
+            "timed out to {} with {:?} timeout; trying again",
+            cfg.server_addr,
+            timeout
+        );
+        timeout = (timeout * 2).min(cfg.retry.max_timeout);
     }
-    unimplemented!()
 }
-const VERSION: u64 = 3;
 
 fn init_session(
     cookie: crypt::Cookie,
     resume_token: Buff,
     shared_sec: blake3::Hash,
-    cfg: LowlevelClientConfig,
+    version: u64,
+    aead: AeadSuite,
+    mut cfg: LowlevelClientConfig,
 ) -> Session {
-    let (mut session, back) = Session::new(SessionConfig {
-        version: VERSION,
-        gather: cfg.gather.clone(),
-        session_key: shared_sec.as_bytes().to_vec(),
-        role: crate::Role::Client,
-    });
+    let keys = SessionKeys::from_session_key(shared_sec.as_bytes());
+    let ticket = ResumeTicket {
+        resume_token,
+        resume_key: keys.resume_key,
+        up_epoch: 0,
+        down_epoch: 0,
+    };
+    let (mut session, back) = Session::new(
+        SessionConfig {
+            version,
+            aead,
+            gather: cfg.gather.clone(),
+            role: crate::Role::Client,
+            initial_frame_no: 0,
+            padding: cfg.padding.clone(),
+            cover: cfg.cover.clone(),
+            progress: Default::default(),
+            rekey_frames: REKEY_FRAMES,
+            ticket: Some(ticket),
+            token_issuer: None,
+        },
+        keys,
+    );
     let back = Arc::new(back);
+    let migrations = cfg.migrations.take();
     let uploader: Task<anyhow::Result<()>> = runtime::spawn(async move {
-        let mut workers: Vec<ClientWorker> = (0..cfg.num_shards)
-            .map(|shard_id| {
-                ClientWorker::start(
-                    cookie.clone(),
-                    resume_token.clone(),
-                    back.clone(),
-                    shard_id as u8,
-                    cfg.clone(),
-                )
-            })
+        let primary = Route {
+            backhaul_gen: cfg.backhaul_gen.clone(),
+            server_addr: cfg.server_addr,
+        };
+        // shard IDs must be unique across the whole session, so every path gets its own slot of them
+        let max_paths = (256 / cfg.num_shards.max(1)).max(1);
+        let start_path = |slot: usize, route: Route| -> ClientPath {
+            let mut cfg = cfg.clone();
+            cfg.backhaul_gen = with_transforms(route.backhaul_gen, &cfg.transforms);
+            cfg.server_addr = route.server_addr;
+            ClientPath::start(&cookie, &back, slot * cfg.num_shards, cfg)
+        };
+        let routes: Vec<Route> = std::iter::once(primary)
+            .chain(cfg.extra_routes.iter().cloned())
+            .collect();
+        if routes.len() > max_paths {
+            tracing::warn!("only using {} of {} paths", max_paths, routes.len());
+        }
+        let mut paths: Vec<ClientPath> = routes
+            .into_iter()
+            .take(max_paths)
+            .enumerate()
+            .map(|(slot, route)| start_path(slot, route))
             .collect();
-        let mut fired_workers: VecDeque<ClientWorker> = VecDeque::new();
-        let mut last_reset = Instant::now();
-        let mut just_respawned = false;
-        for ctr in (0..).cycle() {
-            let to_upload = back.next_outgoing().await?;
-            let random_worker = ctr % workers.len();
-            workers[random_worker].send_upload(to_upload).await;
-            if cfg
-                .reset_interval
-                .map(|dur| last_reset.elapsed() > dur)
-                .unwrap_or_default()
-            {
-                tracing::debug!("reset timer expired!");
-                last_reset = Instant::now();
-                if just_respawned {
-                    for worker in workers.iter() {
-                        worker.reset_received_count();
+        cfg.probe_paths.store(paths.len() > 1, Ordering::Relaxed);
+        // paths replaced by a migration keep their slots until they stop receiving
+        let mut retired_until = vec![Instant::now(); max_paths];
+        let migration_grace = cfg.migration_grace;
+        let retire = |path: ClientPath, retired_until: &mut [Instant]| {
+            retired_until[path.first_shard / cfg.num_shards.max(1)] =
+                Instant::now() + migration_grace;
+            // the old path keeps receiving whatever is still in flight on it
+            runtime::spawn(async move {
+                smol::Timer::after(migration_grace).await;
+                drop(path);
+            })
+            .detach();
+        };
+        let free_slot = |paths: &[ClientPath], retired_until: &[Instant]| {
+            let now = Instant::now();
+            (0..max_paths).find(|&slot| {
+                retired_until[slot] <= now
+                    && paths
+                        .iter()
+                        .all(|path| path.first_shard != slot * cfg.num_shards)
+            })
+        };
+        let mut usable = ClientPath::usable(&paths);
+        let mut last_schedule = Instant::now();
+        enum Evt {
+            Upload(Buff),
+            Migrate(Migration),
+        }
+        for ctr in 0usize.. {
+            let event = async { Ok::<_, anyhow::Error>(Evt::Upload(back.next_outgoing().await?)) }
+                .or(async {
+                    if let Some(migrations) = &migrations {
+                        if let Ok(migration) = migrations.recv().await {
+                            return Ok(Evt::Migrate(migration));
+                        }
                     }
-                    just_respawned = false;
-                } else {
-                    // check: are we even that bad?
-                    let worker_packet_count: SmallVec<[usize; 16]> =
-                        workers.iter().map(|w| w.get_received_count()).collect();
-                    let p_value = uniform_pvalue(&worker_packet_count);
-                    tracing::debug!("p-value = {}; {:?}", p_value, worker_packet_count);
-                    if p_value < 0.01 {
-                        // find the worst worker and fire it
-                        let worst_worker_id = workers
-                            .iter()
-                            .enumerate()
-                            .min_by_key(|(worker_id, worker)| {
-                                let count = worker.get_received_count();
-                                tracing::debug!("worker {} has {}", worker_id, count);
-                                count
-                            })
-                            .map(|x| x.0)
-                            .expect("must have a worst worker");
-                        tracing::debug!("replacing worst worker {}", worst_worker_id);
-                        let new_worker = ClientWorker::start(
-                            cookie.clone(),
-                            resume_token.clone(),
-                            back.clone(),
-                            worst_worker_id as u8,
-                            cfg.clone(),
-                        );
-                        let worst_worker =
-                            std::mem::replace(&mut workers[worst_worker_id], new_worker);
-                        fired_workers.push_back(worst_worker);
-                        if fired_workers.len() > workers.len() {
-                            fired_workers.pop_front();
+                    // nobody can migrate us anymore
+                    smol::future::pending().await
+                });
+            let to_upload = match event.await? {
+                Evt::Upload(to_upload) => to_upload,
+                Evt::Migrate(Migration::Primary(route)) => {
+                    tracing::debug!("migrating primary path to {}", route.server_addr);
+                    let old_primary = paths.remove(0);
+                    let old_slot = old_primary.first_shard / cfg.num_shards.max(1);
+                    retire(old_primary, &mut retired_until);
+                    let slot = free_slot(&paths, &retired_until).unwrap_or_else(|| {
+                        tracing::warn!("no free shard IDs, reusing a migrating path's");
+                        old_slot
+                    });
+                    paths.insert(0, start_path(slot, route));
+                    cfg.probe_paths.store(paths.len() > 1, Ordering::Relaxed);
+                    usable = ClientPath::usable(&paths);
+                    continue;
+                }
+                Evt::Migrate(Migration::All(routes)) => {
+                    tracing::debug!("migrating session onto {} paths", routes.len());
+                    for path in paths.drain(..) {
+                        retire(path, &mut retired_until);
+                    }
+                    for route in routes {
+                        match free_slot(&paths, &retired_until) {
+                            Some(slot) => paths.push(start_path(slot, route)),
+                            None if paths.is_empty() => {
+                                tracing::warn!("no free shard IDs, reusing a migrating path's");
+                                paths.push(start_path(0, route))
+                            }
+                            None => {
+                                tracing::warn!("no free shard IDs, dropping a path");
+                                break;
+                            }
                         }
-                        just_respawned = true;
                     }
+                    cfg.probe_paths.store(paths.len() > 1, Ordering::Relaxed);
+                    usable = ClientPath::usable(&paths);
+                    continue;
                 }
+            };
+            if last_schedule.elapsed() > SCHEDULE_INTERVAL {
+                last_schedule = Instant::now();
+                usable = ClientPath::usable(&paths);
             }
+            // spread packets over the usable paths, and then over each path's workers
+            let path = &mut paths[usable[ctr % usable.len()]];
+            path.send_upload(to_upload, ctr / usable.len()).await;
+            path.check_workers(&cookie, &back);
         }
         unreachable!()
     });
@@ -185,6 +434,125 @@ fn init_session(
     session
 }
 
+/// How often the client re-evaluates which paths to send on.
+const SCHEDULE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// One of the paths a session sends and receives over: a set of workers, each with its own backhaul, to one server address.
+struct ClientPath {
+    cfg: LowlevelClientConfig,
+    first_shard: usize,
+    workers: Vec<ClientWorker>,
+    fired_workers: VecDeque<ClientWorker>,
+    last_reset: Instant,
+    just_respawned: bool,
+}
+
+impl ClientPath {
+    /// Starts the workers of a path, with shard IDs beginning at the given one.
+    fn start(
+        cookie: &crypt::Cookie,
+        back: &Arc<SessionBack>,
+        first_shard: usize,
+        mut cfg: LowlevelClientConfig,
+    ) -> Self {
+        cfg.path_stats = Arc::new(PathStats::default());
+        let workers = (0..cfg.num_shards)
+            .map(|shard| {
+                ClientWorker::start(
+                    cookie.clone(),
+                    back.clone(),
+                    (first_shard + shard) as u8,
+                    cfg.clone(),
+                )
+            })
+            .collect();
+        Self {
+            cfg,
+            first_shard,
+            workers,
+            fired_workers: VecDeque::new(),
+            last_reset: Instant::now(),
+            just_respawned: false,
+        }
+    }
+
+    /// Picks the paths worth sending on.
+    fn usable(paths: &[ClientPath]) -> SmallVec<[usize; 4]> {
+        let usable = usable_paths(paths.iter().map(|path| &*path.cfg.path_stats));
+        for path in paths {
+            tracing::trace!(
+                "path to {}: {:?}",
+                path.cfg.server_addr,
+                path.cfg.path_stats.estimates()
+            );
+        }
+        usable
+    }
+
+    /// Sends an upload through one of the workers.
+    async fn send_upload(&self, to_upload: Buff, ctr: usize) {
+        let random_worker = ctr % self.workers.len();
+        self.workers[random_worker].send_upload(to_upload).await;
+    }
+
+    /// Replaces the worst worker if the workers are getting suspiciously uneven amounts of traffic, which suggests that its backhaul is being interfered with.
+    fn check_workers(&mut self, cookie: &crypt::Cookie, back: &Arc<SessionBack>) {
+        if !self
+            .cfg
+            .reset_interval
+            .map(|dur| self.last_reset.elapsed() > dur)
+            .unwrap_or_default()
+        {
+            return;
+        }
+        tracing::debug!("reset timer expired!");
+        self.last_reset = Instant::now();
+        if self.just_respawned {
+            for worker in self.workers.iter() {
+                worker.reset_received_count();
+            }
+            self.just_respawned = false;
+        } else {
+            // check: are we even that bad?
+            let worker_packet_count: SmallVec<[usize; 16]> = self
+                .workers
+                .iter()
+                .map(|w| w.get_received_count())
+                .collect();
+            let p_value = uniform_pvalue(&worker_packet_count);
+            tracing::debug!("p-value = {}; {:?}", p_value, worker_packet_count);
+            if p_value < 0.01 {
+                // find the worst worker and fire it
+                let worst_worker_id = self
+                    .workers
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(worker_id, worker)| {
+                        let count = worker.get_received_count();
+                        tracing::debug!("worker {} has {}", worker_id, count);
+                        count
+                    })
+                    .map(|x| x.0)
+                    .expect("must have a worst worker");
+                tracing::debug!("replacing worst worker {}", worst_worker_id);
+                let new_worker = ClientWorker::start(
+                    cookie.clone(),
+                    back.clone(),
+                    (self.first_shard + worst_worker_id) as u8,
+                    self.cfg.clone(),
+                );
+                let worst_worker =
+                    std::mem::replace(&mut self.workers[worst_worker_id], new_worker);
+                self.fired_workers.push_back(worst_worker);
+                if self.fired_workers.len() > self.workers.len() {
+                    self.fired_workers.pop_front();
+                }
+                self.just_respawned = true;
+            }
+        }
+    }
+}
+
 // guess whether the given slice is uniformly distributed
 fn uniform_pvalue(vals: &[usize]) -> f64 {
     if vals.is_empty() {
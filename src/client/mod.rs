@@ -1,12 +1,21 @@
 use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use smol::{future::Boxed, net::TcpStream};
+use smol::{channel::Sender, future::Boxed, net::TcpStream, prelude::*};
+use thiserror::Error;
 
-use crate::{runtime, tcp::TcpClientBackhaul, Session, StatsGatherer};
+use crate::{
+    runtime,
+    tcp::{TcpClientBackhaul, WsClientBackhaul},
+    AeadSuite, Backhaul, CoverTraffic, HandshakePadding, PacketTransform, PaddingProfile,
+    QuicTransform, Session, SessionError, StatsGatherer, TransformBackhaul,
+};
 
 mod inner;
+mod path;
 mod worker;
 
+pub use crate::tcp::{ProxyAuth, TlsClientConfig, UpstreamProxy};
+
 /// Configuration of a client.
 #[derive(Clone)]
 pub struct ClientConfig {
@@ -16,6 +25,34 @@ pub struct ClientConfig {
     pub protocol: Protocol,
     pub shard_count: usize,
     pub reset_interval: Option<Duration>,
+    /// Long-term client secret key. If this is not set, a random one is generated on every connection; set it to authenticate to listeners that only accept registered clients.
+    pub client_sk: Option<x25519_dalek::StaticSecret>,
+    /// Whether to use a hybrid handshake that mixes ML-KEM into the key exchange, protecting recorded sessions against future quantum computers. The server must support this, or connecting fails. Requires the `post-quantum` feature.
+    pub post_quantum: bool,
+    /// AEAD suites we are willing to use for the session, and over TCP also for the connection carrying it. The server picks one of them according to its own preferences.
+    pub aead_suites: Vec<AeadSuite>,
+    /// How the session pads outgoing packets. The server pads according to its own configuration.
+    pub padding: PaddingProfile,
+    /// If set, the session shapes the timing of outgoing packets and sends cover traffic when idle. Only the client-to-server direction is affected; the server shapes according to its own configuration.
+    pub cover: Option<CoverTraffic>,
+    /// How long handshake packets, including the ClientResumes sent when roaming, are padded to.
+    pub handshake_padding: HandshakePadding,
+    /// Transforms applied to every packet sent to and received from the server, in order for outgoing packets and in reverse for incoming ones. The server must use the same transforms.
+    pub transforms: Vec<Arc<dyn PacketTransform>>,
+    /// If set, TCP-based protocols connect to the server through this proxy. UDP is never proxied, and [Protocol::ProxiedTcp] and [WebsocketConfig::connector] take precedence.
+    pub proxy: Option<UpstreamProxy>,
+    /// How TLS is set up by [Protocol::DirectTls], and by [Protocol::Websocket] over TLS.
+    pub tls: TlsClientConfig,
+    /// How the handshake is retried when the server doesn't answer.
+    pub retry: RetryPolicy,
+    /// If set, connecting fails with [ConnectError::Timeout] if it takes longer than this overall.
+    pub connect_deadline: Option<Duration>,
+    /// Paths the session uses alongside [ClientConfig::protocol] to [ClientConfig::server_addr], for example over another network interface or to another server address. Every path gets `shard_count` backhauls, and their round-trip time and loss are measured continually, so that packets are spread over the best paths. The listeners reached must share sessions through [SharedSessions](crate::SharedSessions).
+    pub extra_paths: Vec<SessionPath>,
+    /// How long a session moved by a [SessionMigrator] keeps receiving on the paths it moved off, so that packets still in flight on them aren't lost.
+    pub migration_grace: Duration,
+    /// Whether to connect to servers that predate version negotiation, which answer with a bare ServerHello. Sessions with them use version 3 and ChaCha20/Poly1305. Stripping the negotiation from a newer server's answer doesn't downgrade anything, since newer servers bind the negotiation into the session key, so the keys just don't match. On by default, so that clients and servers can be upgraded in any order; turn it off to require negotiation.
+    pub allow_legacy_servers: bool,
 }
 
 impl ClientConfig {
@@ -33,52 +70,325 @@ impl ClientConfig {
             protocol,
             shard_count: 1,
             reset_interval: None,
+            client_sk: None,
+            post_quantum: false,
+            aead_suites: AeadSuite::preferred(),
+            padding: PaddingProfile::default(),
+            cover: None,
+            handshake_padding: HandshakePadding::default(),
+            transforms: Vec::new(),
+            proxy: None,
+            tls: TlsClientConfig::default(),
+            retry: RetryPolicy::default(),
+            connect_deadline: None,
+            extra_paths: Vec::new(),
+            migration_grace: MIGRATION_GRACE,
+            allow_legacy_servers: true,
         }
     }
 
     /// Builds a Session out of this ClientConfig.
-    pub async fn connect(self) -> std::io::Result<Session> {
-        let server_addr = self.server_addr;
-        let server_pk = self.server_pk;
-        inner::connect_custom(inner::LowlevelClientConfig {
-            server_addr,
-            server_pubkey: server_pk,
-            backhaul_gen: match self.protocol {
-                Protocol::DirectTcp => Arc::new(move || {
-                    Arc::new(
-                        TcpClientBackhaul::new(None, false).add_remote_key(server_addr, server_pk),
-                    )
-                }),
-                Protocol::DirectTls => Arc::new(move || {
-                    Arc::new(
-                        TcpClientBackhaul::new(None, true).add_remote_key(server_addr, server_pk),
-                    )
-                }),
-                Protocol::ProxiedTcp(cnctr) => Arc::new(move || {
-                    Arc::new(
-                        TcpClientBackhaul::new(Some(cnctr.clone()), false)
-                            .add_remote_key(server_addr, server_pk),
-                    )
-                }),
-                Protocol::DirectUdp => Arc::new(move || {
-                    let addr = if server_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }
-                        .parse::<SocketAddr>().unwrap();
-
-                    #[cfg(not(any(target_os = "linux", target_os = "android")))]
-                    let socket = runtime::new_udp_socket_bind(addr).unwrap();
-
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    let socket =
-                        fastudp::FastUdpSocket::from(std::net::UdpSocket::bind(addr).unwrap());
-                    Arc::new(socket)
-                }),
-            },
+    pub async fn connect(self) -> Result<Session, ConnectError> {
+        Ok(self.connect_migratable().await?.0)
+    }
+
+    /// Builds a Session out of this ClientConfig, along with a [SessionMigrator] that can later move it to another protocol or server address.
+    pub async fn connect_migratable(mut self) -> Result<(Session, SessionMigrator), ConnectError> {
+        // migrated backhauls must authenticate with the same key
+        let client_sk = self
+            .client_sk
+            .get_or_insert_with(|| x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>()))
+            .clone();
+        let (send_migration, recv_migration) = smol::channel::unbounded();
+        let session = inner::connect_custom(inner::LowlevelClientConfig {
+            server_addr: self.server_addr,
+            server_pubkey: self.server_pk,
+            client_sk,
+            backhaul_gen: self.backhaul_gen(),
             num_shards: self.shard_count,
             reset_interval: self.reset_interval,
-            gather: self.gather,
+            gather: self.gather.clone(),
+            post_quantum: self.post_quantum,
+            aead_suites: self.aead_suites.clone(),
+            padding: self.padding.clone(),
+            cover: self.cover.clone(),
+            handshake_padding: self.handshake_padding,
+            transforms: self.transforms.clone(),
+            retry: self.retry.clone(),
+            deadline: self.connect_deadline,
+            extra_routes: self
+                .extra_paths
+                .iter()
+                .map(|path| self.route(path.protocol.clone(), path.server_addr))
+                .collect(),
+            migrations: Some(recv_migration),
+            migration_grace: self.migration_grace,
+            allow_legacy_servers: self.allow_legacy_servers,
+            path_stats: Default::default(),
+            probe_paths: Default::default(),
         })
+        .await?;
+        Ok((
+            session,
+            SessionMigrator {
+                cfg: self,
+                send_migration,
+            },
+        ))
+    }
+
+    /// Creates a route to the server over the given protocol and address, with all other settings taken from this ClientConfig.
+    fn route(&self, protocol: Protocol, server_addr: SocketAddr) -> inner::Route {
+        let mut cfg = self.clone();
+        cfg.protocol = protocol;
+        cfg.server_addr = server_addr;
+        inner::Route {
+            backhaul_gen: cfg.backhaul_gen(),
+            server_addr,
+        }
+    }
+
+    /// Creates the function that generates backhauls for [ClientConfig::protocol].
+    fn backhaul_gen(&self) -> BackhaulGen {
+        let server_addr = self.server_addr;
+        let server_pk = self.server_pk;
+        let tcp_client_sk = self.client_sk.clone().expect("client key must be set");
+        let handshake_padding = self.handshake_padding;
+        let aead_suites = self.aead_suites.clone();
+        let proxy_connector = self.proxy.as_ref().map(|proxy| proxy.connector());
+        let tls = self.tls.clone();
+        match self.protocol.clone() {
+            Protocol::DirectTcp => Arc::new(move || {
+                Arc::new(
+                    TcpClientBackhaul::new(proxy_connector.clone(), false)
+                        .add_remote_key(server_addr, server_pk)
+                        .set_client_key(tcp_client_sk.clone())
+                        .set_handshake_padding(handshake_padding)
+                        .set_aead_suites(aead_suites.clone()),
+                )
+            }),
+            Protocol::DirectTls => Arc::new(move || {
+                Arc::new(
+                    TcpClientBackhaul::new(proxy_connector.clone(), true)
+                        .set_tls_config(tls.clone())
+                        .add_remote_key(server_addr, server_pk)
+                        .set_client_key(tcp_client_sk.clone())
+                        .set_handshake_padding(handshake_padding)
+                        .set_aead_suites(aead_suites.clone()),
+                )
+            }),
+            Protocol::ProxiedTcp(cnctr) => Arc::new(move || {
+                Arc::new(
+                    TcpClientBackhaul::new(Some(cnctr.clone()), false)
+                        .add_remote_key(server_addr, server_pk)
+                        .set_client_key(tcp_client_sk.clone())
+                        .set_handshake_padding(handshake_padding)
+                        .set_aead_suites(aead_suites.clone()),
+                )
+            }),
+            Protocol::DirectUdp => Arc::new(move || udp_backhaul(server_addr)),
+            Protocol::Quic => Arc::new(move || {
+                // every backhaul gets its own transform, and so its own connection IDs, like separate QUIC connections
+                Arc::new(TransformBackhaul::new(
+                    udp_backhaul(server_addr),
+                    vec![Arc::new(QuicTransform::client())],
+                ))
+            }),
+            Protocol::Websocket(ws) => Arc::new(move || {
+                let connector = ws.connector.clone().or_else(|| proxy_connector.clone());
+                let mut backhaul = WsClientBackhaul::new(connector, ws.tls)
+                    .set_path(ws.path.clone())
+                    .set_tls_config(tls.clone());
+                if let Some(host) = &ws.host {
+                    backhaul = backhaul.set_host(host.clone());
+                }
+                Arc::new(backhaul)
+            }),
+            Protocol::Custom(backhaul_gen) => backhaul_gen,
+        }
+    }
+
+    /// Connects by trying several ways of reaching the server, happy-eyeballs style, instead of only [ClientConfig::protocol] to [ClientConfig::server_addr]. Candidates are started in order, each one after the previous one fails or [FALLBACK_STAGGER] after it starts, and run concurrently until one of them connects. Every candidate is given up on after its own timeout.
+    ///
+    /// Returns the session along with the index of the candidate that won, or the error of the last candidate to fail. All other settings are taken from this ClientConfig, so every candidate must reach a server with the same key.
+    pub async fn connect_fallback(
+        self,
+        candidates: Vec<FallbackCandidate>,
+    ) -> Result<(Session, usize), ConnectError> {
+        let (send_result, recv_result) = smol::channel::unbounded();
+        // attempts are cancelled when we return
+        let mut attempts = Vec::new();
+        let mut outstanding = 0;
+        let mut candidates = candidates.into_iter().enumerate().peekable();
+        let mut last_err = ConnectError::Timeout;
+        while let Some((idx, candidate)) = candidates.next() {
+            let mut cfg = self.clone();
+            cfg.protocol = candidate.protocol;
+            cfg.server_addr = candidate.server_addr;
+            let send_result = send_result.clone();
+            attempts.push(runtime::spawn(async move {
+                let result = cfg
+                    .connect()
+                    .or(async {
+                        smol::Timer::after(candidate.timeout).await;
+                        Err(ConnectError::Timeout)
+                    })
+                    .await;
+                let _ = send_result.send((idx, result)).await;
+            }));
+            outstanding += 1;
+            let more_candidates = candidates.peek().is_some();
+            // wait until it's time to start the next candidate, or for a winner if there are no more
+            loop {
+                let result = async { recv_result.recv().await.ok() }
+                    .or(async {
+                        if more_candidates {
+                            smol::Timer::after(FALLBACK_STAGGER).await;
+                        } else {
+                            smol::future::pending::<()>().await;
+                        }
+                        None
+                    })
+                    .await;
+                match result {
+                    None => break,
+                    Some((idx, Ok(session))) => {
+                        tracing::debug!("connected with fallback candidate {}", idx);
+                        return Ok((session, idx));
+                    }
+                    Some((idx, Err(err))) => {
+                        tracing::debug!("fallback candidate {} failed: {}", idx, err);
+                        last_err = err;
+                        outstanding -= 1;
+                        if more_candidates {
+                            break;
+                        }
+                        if outstanding == 0 {
+                            return Err(last_err);
+                        }
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+/// How long [ClientConfig::connect_fallback] waits for a candidate before starting the next one alongside it. This is the connection attempt delay recommended for happy eyeballs.
+pub const FALLBACK_STAGGER: Duration = Duration::from_millis(250);
+
+/// One way of reaching the server, for [ClientConfig::connect_fallback].
+#[derive(Clone)]
+pub struct FallbackCandidate {
+    pub protocol: Protocol,
+    pub server_addr: SocketAddr,
+    /// How long to try this candidate for before giving up on it.
+    pub timeout: Duration,
+}
+
+/// An additional path for a multipath session, in [ClientConfig::extra_paths].
+#[derive(Clone)]
+pub struct SessionPath {
+    pub protocol: Protocol,
+    pub server_addr: SocketAddr,
+}
+
+/// Moves an established client session onto another protocol or server address, without disturbing the [Session] or anything built on it, such as a [Multiplex](crate::Multiplex). Obtained from [ClientConfig::connect_migratable].
+///
+/// The new path resumes the session with its existing resume token, so the listener it reaches must share sessions with the original one through [SharedSessions](crate::SharedSessions), or be the same listener. Packets still in flight on the old path are received for [ClientConfig::migration_grace] afterwards.
+#[derive(Clone)]
+pub struct SessionMigrator {
+    cfg: ClientConfig,
+    send_migration: Sender<inner::Migration>,
+}
+
+impl SessionMigrator {
+    /// Moves the session's primary path onto the given protocol and server address, keeping any [ClientConfig::extra_paths] as they are. All other settings stay as they were when connecting.
+    pub async fn migrate(
+        &self,
+        protocol: Protocol,
+        server_addr: SocketAddr,
+    ) -> Result<(), SessionError> {
+        self.send(inner::Migration::Primary(
+            self.cfg.route(protocol, server_addr),
+        ))
         .await
     }
+
+    /// Moves the session onto a whole new set of paths: a primary path over the given protocol and server address, and the given extra paths, replacing all the paths it had.
+    pub async fn migrate_all(
+        &self,
+        protocol: Protocol,
+        server_addr: SocketAddr,
+        extra_paths: Vec<SessionPath>,
+    ) -> Result<(), SessionError> {
+        let routes = std::iter::once(self.cfg.route(protocol, server_addr))
+            .chain(
+                extra_paths
+                    .into_iter()
+                    .map(|path| self.cfg.route(path.protocol, path.server_addr)),
+            )
+            .collect();
+        self.send(inner::Migration::All(routes)).await
+    }
+
+    async fn send(&self, migration: inner::Migration) -> Result<(), SessionError> {
+        self.send_migration
+            .send(migration)
+            .await
+            .map_err(|_| SessionError::SessionDropped)
+    }
+}
+
+/// Default for [ClientConfig::migration_grace].
+const MIGRATION_GRACE: Duration = Duration::from_secs(10);
+
+/// How a client retries its handshake when the server doesn't answer. The hello is resent after every timeout, and the timeout doubles every time. Packets that arrive in the meantime without answering the hello are ignored.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How long to wait for an answer to the first hello.
+    pub initial_timeout: Duration,
+    /// The longest the timeout grows to.
+    pub max_timeout: Duration,
+    /// If set, connecting fails with [ConnectError::Timeout] after this many hellos go unanswered. Defaults to 6, which with the default timeouts gives up after about 35 seconds.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_timeout: Duration::from_secs(1),
+            max_timeout: Duration::from_secs(10),
+            max_attempts: Some(6),
+        }
+    }
+}
+
+/// Why connecting to a server failed.
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    /// The server never answered, within the [RetryPolicy] or [ClientConfig::connect_deadline]. Servers silently drop hellos they can't handle, such as ones with no version in common, so these also end up as timeouts. So does a server whose long-term key isn't the one we expected, since the hello is encrypted for the expected key and the server can't read it.
+    #[error("timed out connecting to the server")]
+    Timeout,
+    /// The server chose a protocol version that we don't speak.
+    #[error("server chose unsupported version {0}")]
+    VersionRejected(u64),
+    /// The server answered with an AEAD suite we didn't offer, without confirming the version and suite (see [ClientConfig::allow_legacy_servers]), or without completing the post-quantum handshake. Also returned when asking for a post-quantum handshake without the `post-quantum` feature.
+    #[error("handshake negotiation failed: {0}")]
+    NegotiationFailed(&'static str),
+    /// The backhaul failed to send or receive.
+    #[error("backhaul failed: {0}")]
+    Backhaul(#[from] std::io::Error),
+}
+
+impl From<ConnectError> for std::io::Error {
+    fn from(err: ConnectError) -> Self {
+        match err {
+            ConnectError::Backhaul(err) => err,
+            ConnectError::Timeout => std::io::Error::new(std::io::ErrorKind::TimedOut, err),
+            err => std::io::Error::new(std::io::ErrorKind::ConnectionRefused, err),
+        }
+    }
 }
 
 /// Underlying protocol for a sosistab session.
@@ -92,11 +402,58 @@ pub enum Protocol {
     ProxiedTcp(Connector),
     /// "Direct UDP that does not go through a proxy.
     DirectUdp,
+    /// UDP disguised as QUIC v1 with a [QuicTransform], for networks that only let through UDP they recognize. The listener must have [QuicTransform::server] as its last transform.
+    Quic,
+    /// WebSocket, which gets through CDNs and proxies that only pass HTTP.
+    Websocket(WebsocketConfig),
+    /// A user-supplied backhaul. The function is called whenever the client needs a fresh backhaul, such as for every shard, and packets are addressed to the server address.
+    Custom(BackhaulGen),
 }
 
 pub type Connector =
     Arc<dyn Fn(SocketAddr) -> Boxed<std::io::Result<TcpStream>> + Send + Sync + 'static>;
 
+/// Configuration of [Protocol::Websocket].
+#[derive(Clone)]
+pub struct WebsocketConfig {
+    /// Whether to run the WebSocket over TLS.
+    pub tls: bool,
+    /// Host header, and SNI when using TLS. If this is not set, a random domain is used for every connection.
+    pub host: Option<String>,
+    /// Path to upgrade at. This must match the listener's `websocket_path`.
+    pub path: String,
+    /// If set, TCP connections are opened by calling this function instead of directly, for example to go through a proxy.
+    pub connector: Option<Connector>,
+}
+
+impl WebsocketConfig {
+    /// Creates a new WebsocketConfig for plain WebSockets at the given path.
+    pub fn new(path: String) -> Self {
+        Self {
+            tls: false,
+            host: None,
+            path,
+            connector: None,
+        }
+    }
+}
+
+/// Creates fresh backhauls for [Protocol::Custom].
+pub type BackhaulGen = Arc<dyn Fn() -> Arc<dyn Backhaul> + Send + Sync + 'static>;
+
+/// Binds a fresh UDP socket for talking to the given server.
+fn udp_backhaul(server_addr: SocketAddr) -> Arc<dyn Backhaul> {
+    let addr = if server_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }
+        .parse::<SocketAddr>().unwrap();
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let socket = runtime::new_udp_socket_bind(addr).unwrap();
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let socket = fastudp::FastUdpSocket::from(std::net::UdpSocket::bind(addr).unwrap());
+    Arc::new(socket)
+}
+
 /// Connects to a remote server over UDP.
 #[deprecated]
 pub async fn connect_udp(
@@ -107,6 +464,7 @@ pub async fn connect_udp(
     inner::connect_custom(inner::LowlevelClientConfig {
         server_addr,
         server_pubkey: pubkey,
+        client_sk: x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>()),
         backhaul_gen: Arc::new(move || {
             Arc::new(
                 runtime::new_udp_socket_bind(
@@ -118,8 +476,23 @@ pub async fn connect_udp(
         num_shards: 4,
         reset_interval: Some(Duration::from_secs(3)),
         gather,
+        post_quantum: false,
+        aead_suites: AeadSuite::preferred(),
+        padding: PaddingProfile::default(),
+        cover: None,
+        handshake_padding: HandshakePadding::default(),
+        transforms: Vec::new(),
+        retry: RetryPolicy::default(),
+        deadline: None,
+        extra_routes: Vec::new(),
+        migrations: None,
+        migration_grace: MIGRATION_GRACE,
+        allow_legacy_servers: true,
+        path_stats: Default::default(),
+        probe_paths: Default::default(),
     })
     .await
+    .map_err(|err| err.into())
 }
 
 /// Connects to a remote server over UDP.
@@ -132,12 +505,355 @@ pub async fn connect_tcp(
     inner::connect_custom(inner::LowlevelClientConfig {
         server_addr,
         server_pubkey: pubkey,
+        client_sk: x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>()),
         backhaul_gen: Arc::new(move || {
             Arc::new(TcpClientBackhaul::new(None, false).add_remote_key(server_addr, pubkey))
         }),
         num_shards: 16,
         reset_interval: None,
         gather,
+        post_quantum: false,
+        aead_suites: AeadSuite::preferred(),
+        padding: PaddingProfile::default(),
+        cover: None,
+        handshake_padding: HandshakePadding::default(),
+        transforms: Vec::new(),
+        retry: RetryPolicy::default(),
+        deadline: None,
+        extra_routes: Vec::new(),
+        migrations: None,
+        migration_grace: MIGRATION_GRACE,
+        allow_legacy_servers: true,
+        path_stats: Default::default(),
+        probe_paths: Default::default(),
     })
     .await
+    .map_err(|err| err.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Weak;
+
+    use parking_lot::Mutex;
+
+    use smol_timeout::TimeoutExt;
+
+    use super::*;
+    use crate::{
+        protocol::HandshakeFrame, Backhaul, Buff, Impairments, Listener, MemoryBackhaul,
+        MemoryNetwork, Multiplex, RelConn,
+    };
+
+    /// Backhauls made for one protocol.
+    #[derive(Clone, Default)]
+    struct Made(Arc<Mutex<Vec<Weak<MemoryBackhaul>>>>);
+
+    impl Made {
+        fn total(&self) -> usize {
+            self.0.lock().len()
+        }
+
+        fn live(&self) -> usize {
+            self.0
+                .lock()
+                .iter()
+                .filter(|backhaul| backhaul.strong_count() > 0)
+                .count()
+        }
+    }
+
+    /// A protocol over the network that keeps track of the backhauls it makes.
+    fn tracked(network: &MemoryNetwork) -> (Protocol, Made) {
+        let network = network.clone();
+        let made = Made::default();
+        let tracker = made.clone();
+        let protocol = Protocol::Custom(Arc::new(move || {
+            let backhaul = Arc::new(network.bind_ephemeral(Impairments::new()));
+            tracker.0.lock().push(Arc::downgrade(&backhaul));
+            backhaul
+        }));
+        (protocol, made)
+    }
+
+    /// Sends a message over one end of a connection, checking that it comes out of the other.
+    async fn exchange(conn: &mut RelConn, accepted: &mut RelConn, msg: &[u8]) {
+        conn.write_all(msg).await.unwrap();
+        let mut got = vec![0u8; msg.len()];
+        accepted
+            .read_exact(&mut got)
+            .timeout(Duration::from_secs(10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(got, msg);
+    }
+
+    #[test]
+    fn migration_keeps_extra_paths() {
+        smolscale::block_on(async {
+            let network = MemoryNetwork::new(0);
+            let server_sk = x25519_dalek::StaticSecret::from([42; 32]);
+            let server_addr: SocketAddr = "10.0.0.1:19999".parse().unwrap();
+            let listener = Listener::listen_custom(
+                server_addr,
+                network.bind(server_addr, Impairments::new()),
+                server_sk.clone(),
+                |_, _| (),
+                |_, _| (),
+            )
+            .await
+            .unwrap();
+            let (primary, primary_made) = tracked(&network);
+            let (extra, extra_made) = tracked(&network);
+            let mut cfg = ClientConfig::new(
+                primary,
+                server_addr,
+                (&server_sk).into(),
+                Default::default(),
+            );
+            cfg.shard_count = 1;
+            // short enough for the test to see old paths go away
+            cfg.migration_grace = Duration::from_secs(1);
+            cfg.extra_paths = vec![SessionPath {
+                protocol: extra.clone(),
+                server_addr,
+            }];
+            let (client, migrator) = cfg.connect_migratable().await.unwrap();
+            let client = Multiplex::new(client);
+            let server = Multiplex::new(listener.accept_session().await.unwrap());
+            let (conn, accepted) = smol::future::zip(client.open_conn(None), server.accept_conn())
+                .timeout(Duration::from_secs(10))
+                .await
+                .unwrap();
+            let (mut conn, mut accepted) = (conn.unwrap(), accepted.unwrap());
+            exchange(&mut conn, &mut accepted, b"before migrating").await;
+            // moving the primary path leaves the extra path alone, and retires the old primary path after the grace period
+            let (moved, moved_made) = tracked(&network);
+            migrator.migrate(moved, server_addr).await.unwrap();
+            exchange(&mut conn, &mut accepted, b"after moving the primary path").await;
+            smol::Timer::after(Duration::from_secs(2)).await;
+            exchange(&mut conn, &mut accepted, b"after the grace period").await;
+            assert_eq!(primary_made.live(), 0);
+            assert_eq!((moved_made.total(), moved_made.live()), (1, 1));
+            assert_eq!((extra_made.total(), extra_made.live()), (1, 1));
+            // replacing every path restarts the extra path too
+            let (moved, moved_made) = tracked(&network);
+            migrator
+                .migrate_all(
+                    moved,
+                    server_addr,
+                    vec![SessionPath {
+                        protocol: extra,
+                        server_addr,
+                    }],
+                )
+                .await
+                .unwrap();
+            exchange(&mut conn, &mut accepted, b"after replacing every path").await;
+            smol::Timer::after(Duration::from_secs(2)).await;
+            assert_eq!(moved_made.live(), 1);
+            assert_eq!((extra_made.total(), extra_made.live()), (2, 1));
+        })
+    }
+
+    #[test]
+    fn junk_replies_dont_cause_resends() {
+        smolscale::block_on(async {
+            let network = MemoryNetwork::new(0);
+            let server_sk = x25519_dalek::StaticSecret::from([42; 32]);
+            let server_addr: SocketAddr = "10.0.0.1:19999".parse().unwrap();
+            let backhaul = network.bind(server_addr, Impairments::new());
+            let hellos = Arc::new(Mutex::new(0));
+            let counter = hellos.clone();
+            // answers every hello with several packets that aren't handshake replies
+            let _server = smolscale::spawn(async move {
+                loop {
+                    let (_, addr) = backhaul.recv_from().await.unwrap();
+                    *counter.lock() += 1;
+                    for _ in 0..3 {
+                        let junk: Vec<u8> = (0..500).map(|_| rand::random()).collect();
+                        backhaul
+                            .send_to(Buff::copy_from_slice(&junk), addr)
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+            let (protocol, _) = tracked(&network);
+            let mut cfg = ClientConfig::new(
+                protocol,
+                server_addr,
+                (&server_sk).into(),
+                Default::default(),
+            );
+            cfg.retry = RetryPolicy {
+                initial_timeout: Duration::from_millis(100),
+                max_timeout: Duration::from_millis(200),
+                max_attempts: Some(3),
+            };
+            cfg.connect_deadline = Some(Duration::from_secs(10));
+            assert!(matches!(cfg.connect().await, Err(ConnectError::Timeout)));
+            assert_eq!(*hellos.lock(), 3);
+        })
+    }
+
+    #[test]
+    fn wrong_server_key_times_out() {
+        smolscale::block_on(async {
+            let network = MemoryNetwork::new(0);
+            let server_addr: SocketAddr = "10.0.0.1:19999".parse().unwrap();
+            let _listener = Listener::listen_custom(
+                server_addr,
+                network.bind(server_addr, Impairments::new()),
+                x25519_dalek::StaticSecret::from([42; 32]),
+                |_, _| (),
+                |_, _| (),
+            )
+            .await
+            .unwrap();
+            let (protocol, _) = tracked(&network);
+            let mut cfg = ClientConfig::new(
+                protocol,
+                server_addr,
+                (&x25519_dalek::StaticSecret::from([43; 32])).into(),
+                Default::default(),
+            );
+            cfg.retry = RetryPolicy {
+                initial_timeout: Duration::from_millis(100),
+                max_timeout: Duration::from_millis(200),
+                max_attempts: Some(3),
+            };
+            assert!(matches!(cfg.connect().await, Err(ConnectError::Timeout)));
+        })
+    }
+
+    /// Sends a message over one end of a session until it comes out of the other.
+    async fn deliver(from: &Session, to: &Session, msg: &[u8]) {
+        async {
+            loop {
+                from.send_bytes(Buff::copy_from_slice(msg)).await.unwrap();
+                if let Some(got) = to.recv_bytes().timeout(Duration::from_millis(200)).await {
+                    assert_eq!(&got.unwrap()[..], msg);
+                    return;
+                }
+            }
+        }
+        .timeout(Duration::from_secs(10))
+        .await
+        .expect("message never arrived")
+    }
+
+    #[test]
+    fn sessions_survive_listener_restarts() {
+        smolscale::block_on(async {
+            let network = MemoryNetwork::new(0);
+            let server_sk = x25519_dalek::StaticSecret::from([42; 32]);
+            let server_addr: SocketAddr = "10.0.0.1:19999".parse().unwrap();
+            let mut listener_cfg = crate::ListenerConfig::new(server_sk.clone());
+            listener_cfg.token_key = Some(crate::ListenerConfig::generate_token_key());
+            let listen = || {
+                Listener::listen_custom(
+                    server_addr,
+                    network.bind(server_addr, Impairments::new()),
+                    listener_cfg.clone(),
+                    |_, _| (),
+                    |_, _| (),
+                )
+            };
+            let listener = listen().await.unwrap();
+            let (protocol, _) = tracked(&network);
+            let client = ClientConfig::new(
+                protocol,
+                server_addr,
+                (&server_sk).into(),
+                Default::default(),
+            )
+            .connect()
+            .await
+            .unwrap();
+            let server = listener.accept_session().await.unwrap();
+            deliver(&client, &server, b"up").await;
+            deliver(&server, &client, b"down").await;
+            drop((server, listener));
+            // the new listener rebuilds the session from the resume token, under a salt the client has to pick up
+            let listener = listen().await.unwrap();
+            // clients only resume once they notice the server has gone quiet
+            let server = async {
+                loop {
+                    client
+                        .send_bytes(Buff::copy_from_slice(b"hello?"))
+                        .await
+                        .unwrap();
+                    if let Some(server) = listener
+                        .accept_session()
+                        .timeout(Duration::from_millis(200))
+                        .await
+                    {
+                        return server.unwrap();
+                    }
+                }
+            }
+            .timeout(Duration::from_secs(10))
+            .await
+            .expect("session never resumed");
+            deliver(&client, &server, b"up again").await;
+            deliver(&server, &client, b"down again").await;
+        })
+    }
+
+    /// Answers every ClientHello like servers from before version negotiation, with nothing but a ServerHello.
+    async fn legacy_server(backhaul: MemoryBackhaul, server_sk: x25519_dalek::StaticSecret) {
+        let cookie = crate::crypt::Cookie::new((&server_sk).into());
+        loop {
+            let (pkt, addr) = backhaul.recv_from().await.unwrap();
+            let hello = cookie.generate_c2s().find_map(|key| {
+                crate::crypt::LegacyAead::new(&key).pad_decrypt_v1::<HandshakeFrame>(&pkt)
+            });
+            if !matches!(
+                hello.as_deref(),
+                Some([HandshakeFrame::ClientHello { .. }, ..])
+            ) {
+                continue;
+            }
+            let eph_sk = x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>());
+            let reply = crate::crypt::LegacyAead::new(&cookie.generate_s2c().next().unwrap())
+                .pad_encrypt_v1(
+                    &[HandshakeFrame::ServerHello {
+                        long_pk: (&server_sk).into(),
+                        eph_pk: (&eph_sk).into(),
+                        resume_token: Buff::copy_from_slice(b"legacy token"),
+                    }],
+                    1000,
+                );
+            backhaul.send_to(reply, addr).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn legacy_servers_can_be_refused() {
+        smolscale::block_on(async {
+            let network = MemoryNetwork::new(0);
+            let server_sk = x25519_dalek::StaticSecret::from([42; 32]);
+            let server_addr: SocketAddr = "10.0.0.1:19999".parse().unwrap();
+            let _server = smolscale::spawn(legacy_server(
+                network.bind(server_addr, Impairments::new()),
+                server_sk.clone(),
+            ));
+            let (protocol, _) = tracked(&network);
+            let mut cfg = ClientConfig::new(
+                protocol,
+                server_addr,
+                (&server_sk).into(),
+                Default::default(),
+            );
+            cfg.connect_deadline = Some(Duration::from_secs(10));
+            cfg.clone().connect().await.unwrap();
+            cfg.allow_legacy_servers = false;
+            assert!(matches!(
+                cfg.connect().await,
+                Err(ConnectError::NegotiationFailed(_))
+            ));
+        })
+    }
 }
@@ -0,0 +1,169 @@
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rand::Rng;
+use smallvec::SmallVec;
+
+use crate::{EmaCalculator, HandshakePadding};
+
+/// How often, on average, every worker probes its path.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Probes are padded to a length in this range. They only need to fit a nonce and a MAC, so keeping them small keeps probing cheap and doesn't stand out next to handshakes.
+pub(crate) const PROBE_PADDING: HandshakePadding = HandshakePadding {
+    min_len: 48,
+    max_len: 160,
+};
+
+/// How long to wait before the next probe. The interval is jittered, so that probes don't form a regular pattern.
+pub(crate) fn probe_delay() -> Duration {
+    PROBE_INTERVAL.mul_f64(rand::thread_rng().gen_range(0.5, 1.5))
+}
+
+/// Paths whose score is within this factor of the best path's, plus [USABLE_SLACK] seconds, are used alongside it.
+const USABLE_FACTOR: f64 = 2.0;
+
+/// Slack on top of [USABLE_FACTOR], so that jitter on very fast paths doesn't rule any of them out.
+const USABLE_SLACK: f64 = 0.02;
+
+/// Round-trip time and loss estimates for one of a session's paths, fed by the probes of all its workers.
+#[derive(Default)]
+pub(crate) struct PathStats {
+    estimates: Mutex<Option<Estimates>>,
+}
+
+struct Estimates {
+    rtt: EmaCalculator,
+    loss: EmaCalculator,
+}
+
+impl PathStats {
+    /// Records a probe answered after the given round-trip time.
+    pub fn record_reply(&self, rtt: Duration) {
+        let mut estimates = self.estimates.lock();
+        let estimates = estimates.get_or_insert_with(|| Estimates {
+            rtt: EmaCalculator::new_unset(0.2),
+            loss: EmaCalculator::new(0.0, 0.2),
+        });
+        estimates.rtt.update(rtt.as_secs_f64());
+        estimates.loss.update(0.0);
+    }
+
+    /// Records a probe that was never answered. Paths that have never answered a probe stay unmeasured.
+    pub fn record_loss(&self) {
+        if let Some(estimates) = self.estimates.lock().as_mut() {
+            estimates.loss.update(1.0);
+        }
+    }
+
+    /// Returns the estimated round-trip time and loss rate, or None if no probe has been answered yet.
+    pub fn estimates(&self) -> Option<(Duration, f64)> {
+        let estimates = self.estimates.lock();
+        let estimates = estimates.as_ref()?;
+        Some((
+            Duration::from_secs_f64(estimates.rtt.mean()),
+            estimates.loss.mean(),
+        ))
+    }
+
+    /// Roughly how long it takes to get a packet through this path, counting retransmissions. Lower is better.
+    fn score(&self) -> Option<f64> {
+        let (rtt, loss) = self.estimates()?;
+        Some(rtt.as_secs_f64() / (1.0 - loss).max(0.01))
+    }
+}
+
+/// Picks the indices of the paths worth sending on: the best path, and every measured path that isn't much worse. Until some path has been measured, only the first one, which the handshake went over, is used.
+pub(crate) fn usable_paths<'a>(paths: impl Iterator<Item = &'a PathStats>) -> SmallVec<[usize; 4]> {
+    let scores: SmallVec<[Option<f64>; 4]> = paths.map(|path| path.score()).collect();
+    let best = scores.iter().flatten().copied().reduce(f64::min);
+    match best {
+        Some(best) => scores
+            .iter()
+            .enumerate()
+            .filter(|(_, score)| {
+                score.map(|score| score <= best * USABLE_FACTOR + USABLE_SLACK) == Some(true)
+            })
+            .map(|(idx, _)| idx)
+            .collect(),
+        None => smallvec::smallvec![0],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path that has answered probes after the given round-trip time.
+    fn measured(rtt_ms: u64) -> PathStats {
+        let stats = PathStats::default();
+        stats.record_reply(Duration::from_millis(rtt_ms));
+        stats
+    }
+
+    #[test]
+    fn stats_start_unmeasured() {
+        let stats = PathStats::default();
+        // losses before the first reply don't make a path measured
+        stats.record_loss();
+        assert!(stats.estimates().is_none());
+        stats.record_reply(Duration::from_millis(50));
+        let (rtt, loss) = stats.estimates().unwrap();
+        assert_eq!(rtt, Duration::from_millis(50));
+        assert_eq!(loss, 0.0);
+    }
+
+    #[test]
+    fn stats_track_loss() {
+        let stats = measured(50);
+        for _ in 0..10 {
+            stats.record_loss();
+        }
+        let (_, loss) = stats.estimates().unwrap();
+        assert!(loss > 0.5);
+        // and recover once probes get through again
+        for _ in 0..30 {
+            stats.record_reply(Duration::from_millis(50));
+        }
+        let (_, recovered) = stats.estimates().unwrap();
+        assert!(recovered < 0.1);
+    }
+
+    #[test]
+    fn unmeasured_paths_use_the_first() {
+        let paths = [PathStats::default(), PathStats::default()];
+        assert_eq!(usable_paths(paths.iter()).as_slice(), &[0]);
+    }
+
+    #[test]
+    fn usable_paths_near_the_best() {
+        // once anything is measured, unmeasured paths are left out
+        let paths = [PathStats::default(), measured(100)];
+        assert_eq!(usable_paths(paths.iter()).as_slice(), &[1]);
+        // paths much slower than the best are left out, and similar ones used alongside it
+        let paths = [measured(100), measured(150), measured(500)];
+        assert_eq!(usable_paths(paths.iter()).as_slice(), &[0, 1]);
+        // the slack keeps jitter between very fast paths from ruling any of them out
+        let paths = [measured(1), measured(10)];
+        assert_eq!(usable_paths(paths.iter()).as_slice(), &[0, 1]);
+    }
+
+    #[test]
+    fn lossy_paths_score_worse() {
+        let lossy = measured(100);
+        for _ in 0..20 {
+            lossy.record_loss();
+        }
+        let paths = [lossy, measured(150)];
+        assert_eq!(usable_paths(paths.iter()).as_slice(), &[1]);
+    }
+
+    #[test]
+    fn probe_delays_are_jittered() {
+        let delays: Vec<Duration> = (0..100).map(|_| probe_delay()).collect();
+        assert!(delays
+            .iter()
+            .all(|delay| *delay >= PROBE_INTERVAL / 2 && *delay <= PROBE_INTERVAL * 3 / 2));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+}
@@ -12,7 +12,10 @@ use smol::channel::{Receiver, Sender};
 
 use crate::{backhaul::Backhaul, protocol::HandshakeFrame, runtime, Buff, SessionBack};
 
-use super::inner::LowlevelClientConfig;
+use super::{
+    inner::LowlevelClientConfig,
+    path::{probe_delay, PROBE_PADDING},
+};
 
 /// Encapsulates a worker "actor".
 pub(crate) struct ClientWorker {
@@ -25,7 +28,6 @@ impl ClientWorker {
     /// Spins off a new ClientWorker.
     pub fn start(
         cookie: crate::crypt::Cookie,
-        resume_token: Buff,
         session_back: Arc<SessionBack>,
         shard_id: u8,
         cfg: LowlevelClientConfig,
@@ -38,7 +40,6 @@ impl ClientWorker {
             runtime::spawn(async move {
                 while let Err(err) = client_backhaul_once(
                     cookie.clone(),
-                    resume_token.clone(),
                     session_back.clone(),
                     recv_upload.clone(),
                     shard_id,
@@ -78,7 +79,6 @@ impl ClientWorker {
 
 async fn client_backhaul_once(
     cookie: crate::crypt::Cookie,
-    resume_token: Buff,
     session_back: Arc<SessionBack>,
     recv_upload: Receiver<Buff>,
     shard_id: u8,
@@ -93,10 +93,14 @@ async fn client_backhaul_once(
     enum Evt {
         Incoming((Buff, SocketAddr)),
         Outgoing(Buff),
+        Probe,
     }
     // last remind time
     let mut last_incoming_time: Option<Instant> = None;
     let mut last_outgoing_time: Option<Instant> = None;
+    // the probe we're waiting for an answer to
+    let mut next_probe = Instant::now() + probe_delay();
+    let mut outstanding_probe: Option<(u64, Instant)> = None;
 
     loop {
         let down = {
@@ -113,13 +117,42 @@ async fn client_backhaul_once(
             let raw_upload = recv_upload.recv().await?;
             Ok::<_, anyhow::Error>(Evt::Outgoing(raw_upload))
         };
+        let probe = async {
+            smol::Timer::at(next_probe).await;
+            Ok::<_, anyhow::Error>(Evt::Probe)
+        };
 
-        match smol::future::race(down, up).await {
+        match smol::future::race(down, smol::future::race(up, probe)).await {
             Ok(Evt::Incoming((bts, src))) => {
                 tracing::trace!("received on shard {} from {}", shard_id, src);
                 if src == cfg.server_addr {
-                    received_count.fetch_add(1, Ordering::Relaxed);
-                    let _ = session_back.inject_incoming(&bts);
+                    // probe replies don't count, since they say nothing about whether the server sends us session traffic
+                    if session_back.inject_incoming(&bts).is_ok() {
+                        received_count.fetch_add(1, Ordering::Relaxed);
+                    } else if let Some(frames) = handshake_reply(&cookie, &bts) {
+                        for frame in frames {
+                            match frame {
+                                HandshakeFrame::PathProbeReply { nonce, mac }
+                                    if blake3::Hash::from(mac)
+                                        == session_back.probe_mac(true, nonce) =>
+                                {
+                                    if let Some((sent_nonce, sent)) = outstanding_probe {
+                                        if nonce == sent_nonce {
+                                            cfg.path_stats.record_reply(sent.elapsed());
+                                            outstanding_probe = None;
+                                        }
+                                    }
+                                }
+                                // the server rebuilt the session, for example after restarting
+                                HandshakeFrame::ResumeSalt { salt, mac } => {
+                                    session_back.adopt_salt(salt, mac)
+                                }
+                                _ => {}
+                            }
+                        }
+                    } else {
+                        received_count.fetch_add(1, Ordering::Relaxed);
+                    }
                 } else {
                     tracing::warn!("stray packet from {}", src)
                 }
@@ -138,30 +171,82 @@ async fn client_backhaul_once(
                 {
                     updated = true;
                     last_outgoing_time = Some(now);
-                    let g_encrypt =
-                        crate::crypt::LegacyAead::new(&cookie.generate_c2s().next().unwrap());
-                    drop(
-                        socket
-                            .send_to(
-                                g_encrypt.pad_encrypt_v1(
-                                    &[HandshakeFrame::ClientResume {
-                                        resume_token: resume_token.clone(),
-                                        shard_id,
-                                    }],
-                                    1000,
-                                ),
-                                cfg.server_addr,
-                            )
-                            .await,
-                    );
+                    send_resume(&socket, &cookie, &session_back, shard_id, &cfg).await;
                 }
                 if let Err(err) = socket.send_to(bts, cfg.server_addr).await {
                     tracing::warn!("error sending packet: {:?}", err)
                 }
             }
+            Ok(Evt::Probe) => {
+                next_probe = Instant::now() + probe_delay();
+                // the server only accepts the session, and answers probes, once we've bound a path with a ClientResume, and paths the scheduler isn't using yet never send one on their own
+                if !updated {
+                    updated = true;
+                    last_outgoing_time = Some(Instant::now());
+                    send_resume(&socket, &cookie, &session_back, shard_id, &cfg).await;
+                }
+                // with only one path, there's nothing to choose between
+                if !cfg.probe_paths.load(Ordering::Relaxed) {
+                    outstanding_probe = None;
+                    continue;
+                }
+                if outstanding_probe.is_some() {
+                    cfg.path_stats.record_loss();
+                }
+                let nonce = rand::random();
+                outstanding_probe = Some((nonce, Instant::now()));
+                let g_encrypt =
+                    crate::crypt::LegacyAead::new(&cookie.generate_c2s().next().unwrap());
+                drop(
+                    socket
+                        .send_to(
+                            g_encrypt.pad_encrypt_v1(
+                                &[HandshakeFrame::PathProbe {
+                                    nonce,
+                                    mac: *session_back.probe_mac(false, nonce).as_bytes(),
+                                }],
+                                PROBE_PADDING.sample(),
+                            ),
+                            cfg.server_addr,
+                        )
+                        .await,
+                );
+            }
             Err(err) => {
                 anyhow::bail!("FATAL error in down/up: {:?}", err);
             }
         }
     }
 }
+
+/// Binds this worker's shard to the session on the server.
+async fn send_resume(
+    socket: &Arc<dyn Backhaul>,
+    cookie: &crate::crypt::Cookie,
+    session_back: &SessionBack,
+    shard_id: u8,
+    cfg: &LowlevelClientConfig,
+) {
+    let g_encrypt = crate::crypt::LegacyAead::new(&cookie.generate_c2s().next().unwrap());
+    // the server may have lost the session, in which case the frames also tell it where we are
+    let frames = if let Some(frames) = session_back.resume_frames(shard_id) {
+        frames
+    } else {
+        return;
+    };
+    drop(
+        socket
+            .send_to(
+                g_encrypt.pad_encrypt_v1(&frames, cfg.handshake_padding.sample()),
+                cfg.server_addr,
+            )
+            .await,
+    );
+}
+
+/// Decodes a handshake packet from the server, such as a PathProbeReply or a ResumeSalt, which aren't part of the session's traffic.
+fn handshake_reply(cookie: &crate::crypt::Cookie, pkt: &[u8]) -> Option<Vec<HandshakeFrame>> {
+    cookie
+        .generate_s2c()
+        .find_map(|key| crate::crypt::LegacyAead::new(&key).pad_decrypt_v1::<HandshakeFrame>(pkt))
+}
@@ -1,17 +1,62 @@
+#[cfg(feature = "post-quantum")]
+use ml_kem::{
+    ml_kem_512::{DecapsulationKey, EncapsulationKey},
+    Decapsulate, Encapsulate, Kem, KeyExport, MlKem512, TryKeyInit,
+};
 use bincode::{DefaultOptions, Options};
 use c2_chacha::stream_cipher::{NewStreamCipher, SyncStreamCipher};
 use c2_chacha::ChaCha12;
 use rand::prelude::*;
-use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::{sync::Arc, time::SystemTime};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use thiserror::Error;
 
 use crate::buffer::{Buff, BuffMut};
 
 pub const UP_KEY: &[u8; 32] = b"upload--------------------------";
 pub const DN_KEY: &[u8; 32] = b"download------------------------";
+pub const RATCHET_KEY: &[u8; 32] = b"ratchet-------------------------";
+pub const COUNTER_MASK_KEY: &[u8; 32] = b"counter-mask--------------------";
+pub const PROBE_KEY: &[u8; 32] = b"path-probe----------------------";
+pub const RESUME_KEY: &[u8; 32] = b"resume-progress-----------------";
+pub const SALT_KEY: &[u8; 32] = b"resume-salt---------------------";
+
+/// Length of the masked nonce counter at the start of every counter-mode packet.
+const COUNTER_LEN: usize = 8;
+
+/// Authenticates a path probe, or with `is_reply`, the reply to one. The probe key is derived from the session key, since anybody can encrypt with the cookie. Hashes compare in constant time.
+pub fn probe_mac(probe_key: &[u8; 32], is_reply: bool, nonce: u64) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_keyed(probe_key);
+    hasher.update(&[is_reply as u8]);
+    hasher.update(&nonce.to_be_bytes());
+    hasher.finalize()
+}
+
+/// Authenticates the salt that a server which rebuilt a session from a resume token sends under, with the session's probe key.
+pub fn salt_mac(probe_key: &[u8; 32], salt: &[u8; 32]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_keyed(probe_key);
+    hasher.update(SALT_KEY);
+    hasher.update(salt);
+    hasher.finalize()
+}
+
+/// Salts a per-direction session key. A session rebuilt from a resume token starts its nonce counters over, so it sends under its key salted with a fresh random salt, and never reuses a nonce of an earlier session from the same handshake, whatever their counters reached.
+pub fn salt_key(key: &[u8; 32], salt: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_keyed(SALT_KEY);
+    hasher.update(salt);
+    hasher.update(key);
+    *hasher.finalize().as_bytes()
+}
+
+/// Ratchets a per-direction session key forward. The previous key cannot be derived from the new one, so wiping it gives forward secrecy, as long as no resume token sealed over it turns up along with the token key.
+pub fn ratchet_key(key: &[u8; 32]) -> [u8; 32] {
+    *blake3::keyed_hash(RATCHET_KEY, key).as_bytes()
+}
 
 /// A structure for encrypting or decrypting Chacha12/Blake3-64.
 #[derive(Debug, Copy, Clone)]
@@ -81,18 +126,22 @@ impl LegacyAead {
 
     /// Pad and encrypt.
     pub fn pad_encrypt_v1(&self, msgs: &[impl Serialize], target_len: usize) -> Buff {
-        // let mut target_len = rand::thread_rng().gen_range(0, target_len);
         let mut plain = Vec::with_capacity(1500);
         for msg in msgs {
             bincode::serialize_into(&mut plain, &msg).unwrap();
         }
         let plainlen = plain.len();
-        plain.extend_from_slice(&vec![0xff; target_len - plain.len()]);
+        plain.extend_from_slice(&vec![0xff; target_len.saturating_sub(plain.len())]);
         let encrypted = self.encrypt(&plain, rand::thread_rng().gen());
         tracing::trace!("PAD and ENCRYPT {} => {}", plainlen, encrypted.len());
         encrypted
     }
 
+    /// Returns the overhead.
+    pub fn overhead() -> usize {
+        24
+    }
+
     /// Decrypt and depad.
     pub fn pad_decrypt_v1<T: DeserializeOwned>(&self, ctext: &[u8]) -> Option<Vec<T>> {
         let plain = self.decrypt(ctext)?;
@@ -117,25 +166,78 @@ impl LegacyAead {
     }
 }
 
-/// Next generation AEAD, based on `ring`'s ChaCha20/Poly1305, used in versions 3 and above
+/// AEAD algorithms that sessions can negotiate during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum AeadSuite {
+    /// ChaCha20/Poly1305. Fast everywhere, and the default.
+    #[default]
+    ChaCha20Poly1305,
+    /// AES-256-GCM. Considerably cheaper than ChaCha20/Poly1305 on CPUs with AES acceleration.
+    Aes256Gcm,
+}
+
+impl AeadSuite {
+    /// Every supported suite, in our order of preference on this machine.
+    pub fn preferred() -> Vec<Self> {
+        if aes_accelerated() {
+            vec![Self::Aes256Gcm, Self::ChaCha20Poly1305]
+        } else {
+            vec![Self::ChaCha20Poly1305, Self::Aes256Gcm]
+        }
+    }
+
+    fn algorithm(self) -> &'static Algorithm {
+        match self {
+            Self::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+            Self::Aes256Gcm => &AES_256_GCM,
+        }
+    }
+}
+
+/// Whether this CPU can do AES-GCM in hardware.
+fn aes_accelerated() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::is_x86_feature_detected!("aes") && std::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Next generation AEAD, based on `ring`, used in versions 3 and above. Uses ChaCha20/Poly1305 unless another [AeadSuite] was negotiated.
 #[derive(Debug, Clone)]
 pub struct NgAead {
     key: Arc<LessSafeKey>,
+    mask_key: [u8; 32],
 }
 
 impl NgAead {
-    pub fn new(key: &[u8]) -> Self {
-        let ubk = UnboundKey::new(&CHACHA20_POLY1305, key).unwrap();
+    /// Creates an AEAD using a particular suite.
+    pub fn with_suite(suite: AeadSuite, key: &[u8]) -> Self {
+        let ubk = UnboundKey::new(suite.algorithm(), key).unwrap();
         Self {
             key: Arc::new(LessSafeKey::new(ubk)),
+            mask_key: *blake3::keyed_hash(COUNTER_MASK_KEY, key).as_bytes(),
         }
     }
 
-    /// Returns the overhead.
+    /// Returns the overhead of [NgAead::encrypt]. Every suite has a 12-byte nonce and a 16-byte tag.
     pub fn overhead() -> usize {
         CHACHA20_POLY1305.nonce_len() + CHACHA20_POLY1305.tag_len()
     }
 
+    /// Returns the overhead of [NgAead::encrypt_counter]: an 8-byte counter and a 16-byte tag.
+    pub fn counter_overhead() -> usize {
+        COUNTER_LEN + CHACHA20_POLY1305.tag_len()
+    }
+
     /// Encrypts a message with a random nonce.
     pub fn encrypt(&self, msg: &[u8]) -> Buff {
         let mut nonce = [0; 12];
@@ -154,11 +256,12 @@ impl NgAead {
 
     /// Decrypts a message.
     pub fn decrypt(&self, ctext: &[u8]) -> Result<Buff, AeadError> {
-        if ctext.len() < CHACHA20_POLY1305.nonce_len() + CHACHA20_POLY1305.tag_len() {
+        let algorithm = self.key.algorithm();
+        if ctext.len() < algorithm.nonce_len() + algorithm.tag_len() {
             return Err(AeadError::BadLength);
         }
         // nonce is last 12 bytes
-        let (ctext, nonce) = ctext.split_at(ctext.len() - CHACHA20_POLY1305.nonce_len());
+        let (ctext, nonce) = ctext.split_at(ctext.len() - algorithm.nonce_len());
         // we now open
         let mut ctext = BuffMut::copy_from_slice(ctext);
         self.key
@@ -169,9 +272,57 @@ impl NgAead {
             )
             .ok()
             .ok_or(AeadError::DecryptionFailure)?;
-        let truncate_to = ctext.len() - CHACHA20_POLY1305.tag_len();
+        let truncate_to = ctext.len() - algorithm.tag_len();
         Ok(ctext.freeze().slice(0..truncate_to))
     }
+
+    /// Encrypts a message with a nonce derived from a counter, which the caller must never repeat for the same key. The counter is sent along with the message, masked by a function of the tag so that it looks random on the wire.
+    pub fn encrypt_counter(&self, counter: u64, msg: &[u8]) -> Buff {
+        let mut output = BuffMut::new();
+        output.extend_from_slice(&[0; COUNTER_LEN]);
+        output.extend_from_slice(msg);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                counter_nonce(counter),
+                Aad::empty(),
+                &mut output[COUNTER_LEN..],
+            )
+            .unwrap();
+        output.extend_from_slice(tag.as_ref());
+        let masked = counter ^ self.counter_mask(tag.as_ref());
+        output[..COUNTER_LEN].copy_from_slice(&masked.to_le_bytes());
+        output.into()
+    }
+
+    /// Decrypts a message encrypted with [NgAead::encrypt_counter], returning its counter along with the plaintext.
+    pub fn decrypt_counter(&self, ctext: &[u8]) -> Result<(u64, Buff), AeadError> {
+        let algorithm = self.key.algorithm();
+        if ctext.len() < COUNTER_LEN + algorithm.tag_len() {
+            return Err(AeadError::BadLength);
+        }
+        let (masked, ctext) = ctext.split_at(COUNTER_LEN);
+        let tag = &ctext[ctext.len() - algorithm.tag_len()..];
+        let counter = u64::from_le_bytes(masked.try_into().unwrap()) ^ self.counter_mask(tag);
+        let mut ctext = BuffMut::copy_from_slice(ctext);
+        self.key
+            .open_in_place(counter_nonce(counter), Aad::empty(), &mut ctext)
+            .ok()
+            .ok_or(AeadError::DecryptionFailure)?;
+        let truncate_to = ctext.len() - algorithm.tag_len();
+        Ok((counter, ctext.freeze().slice(0..truncate_to)))
+    }
+
+    fn counter_mask(&self, tag: &[u8]) -> u64 {
+        let mask = blake3::keyed_hash(&self.mask_key, tag);
+        u64::from_le_bytes(mask.as_bytes()[..COUNTER_LEN].try_into().unwrap())
+    }
+}
+
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    Nonce::assume_unique_for_key(nonce)
 }
 
 #[derive(Error, Debug)]
@@ -182,6 +333,16 @@ pub enum AeadError {
     DecryptionFailure,
 }
 
+/// Length of a cookie epoch.
+pub const COOKIE_EPOCH_SECS: u64 = 60;
+/// Number of epochs before and after the current one for which cookie keys are still accepted.
+pub const COOKIE_EPOCH_SLACK: u64 = 2;
+
+/// Total length of time for which a handshake encrypted under a cookie key can be accepted, and so must be protected against replays.
+pub fn cookie_window() -> Duration {
+    Duration::from_secs(COOKIE_EPOCH_SECS * (COOKIE_EPOCH_SLACK * 2 + 1))
+}
+
 #[derive(Debug, Clone)]
 /// Cookie is a generator of temporary symmetric keys.
 pub struct Cookie(x25519_dalek::PublicKey);
@@ -193,14 +354,11 @@ impl Cookie {
     }
 
     fn generate_temp_keys(&self, ctx: &str, start_epoch: u64) -> Vec<[u8; 32]> {
-        let mut vec = Vec::with_capacity(5);
-        for epoch in &[
-            start_epoch,
-            start_epoch - 1,
-            start_epoch + 1,
-            start_epoch - 2,
-            start_epoch + 2,
-        ] {
+        let mut vec = Vec::with_capacity(COOKIE_EPOCH_SLACK as usize * 2 + 1);
+        let epochs = std::iter::once(start_epoch).chain(
+            (1..=COOKIE_EPOCH_SLACK).flat_map(|delta| [start_epoch - delta, start_epoch + delta]),
+        );
+        for epoch in epochs {
             let mut key = [0u8; 32];
             blake3::derive_key(&format!("{}-{}", ctx, epoch), self.0.as_bytes(), &mut key);
             vec.push(key)
@@ -226,7 +384,7 @@ fn curr_epoch() -> u64 {
         .duration_since(std::time::UNIX_EPOCH)
         .expect("must be after Unix epoch")
         .as_secs()
-        / 60
+        / COOKIE_EPOCH_SECS
 }
 
 #[tracing::instrument(skip(my_long_sk, my_eph_sk), level = "trace")]
@@ -253,3 +411,198 @@ pub fn triple_ecdh(
     };
     blake3::hash(&to_hash)
 }
+
+/// Mixes a post-quantum KEM shared secret into a classical triple-ECDH shared secret, so that the result is secure as long as either is.
+pub fn hybrid_mix(classical: blake3::Hash, kem_secret: &[u8]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_derive_key("sosistab-1 hybrid handshake");
+    hasher.update(classical.as_bytes());
+    hasher.update(kem_secret);
+    hasher.finalize()
+}
+
+/// Mixes what the client offered and the server chose during version and AEAD suite negotiation into the session key. The negotiation frames are only encrypted with the public cookie key, so an active attacker could otherwise rewrite them to downgrade the session; this way, tampering just leaves the two sides with different keys.
+pub fn negotiation_mix(
+    key: blake3::Hash,
+    offered_versions: (u64, u64),
+    offered_suites: &[AeadSuite],
+    version: u64,
+    suite: AeadSuite,
+) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_derive_key("sosistab-1 negotiation transcript");
+    hasher.update(key.as_bytes());
+    hasher.update(
+        &bincode::serialize(&(offered_versions, offered_suites, version, suite))
+            .expect("must serialize"),
+    );
+    hasher.finalize()
+}
+
+/// Length of an ML-KEM-512 ciphertext.
+pub const KEM_CIPHERTEXT_LEN: usize = 768;
+
+/// Client-side state of the ML-KEM part of a hybrid handshake.
+///
+/// We use ML-KEM-512 because both its encapsulation key and its ciphertext fit within the default handshake padding. They still make hybrid hellos and replies at least around 960 bytes long, so with padding ranges reaching below that, an observer can tell hybrid handshakes apart by their lengths. Clients offering the KEM pad their hellos starting from that length instead, which keeps the lengths spread out, but only raising `min_len` on every client hides which ones are hybrid.
+#[cfg(feature = "post-quantum")]
+pub struct KemSecret(DecapsulationKey);
+
+/// Stand-in for the KEM secret when built without the `post-quantum` feature, which can never exist.
+#[cfg(not(feature = "post-quantum"))]
+pub enum KemSecret {}
+
+#[cfg(not(feature = "post-quantum"))]
+impl KemSecret {
+    /// Decapsulates the ciphertext sent by the server.
+    pub fn decapsulate(&self, _ciphertext: &[u8]) -> Option<[u8; 32]> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "post-quantum")]
+impl KemSecret {
+    /// Generates a new KEM secret, returning it along with the encapsulation key to send to the server.
+    pub fn generate() -> (Self, Buff) {
+        let (dk, ek) = MlKem512::generate_keypair();
+        (Self(dk), Buff::copy_from_slice(&ek.to_bytes()))
+    }
+
+    /// Decapsulates the ciphertext sent by the server.
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> Option<[u8; 32]> {
+        let secret = self.0.decapsulate_slice(ciphertext).ok()?;
+        secret.as_slice().try_into().ok()
+    }
+}
+
+/// Server-side part of the ML-KEM part of a hybrid handshake. Returns the ciphertext to send back, and the shared secret.
+#[cfg(feature = "post-quantum")]
+pub fn kem_encapsulate(encaps_key: &[u8]) -> Option<(Buff, [u8; 32])> {
+    let ek = EncapsulationKey::new_from_slice(encaps_key).ok()?;
+    let (ciphertext, secret) = ek.encapsulate();
+    Some((
+        Buff::copy_from_slice(&ciphertext),
+        secret.as_slice().try_into().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Per-direction keys of a session, like sessions derive them.
+    fn direction_keys(suite: AeadSuite) -> (NgAead, NgAead) {
+        let session_key = [42u8; 32];
+        (
+            NgAead::with_suite(suite, blake3::keyed_hash(UP_KEY, &session_key).as_bytes()),
+            NgAead::with_suite(suite, blake3::keyed_hash(DN_KEY, &session_key).as_bytes()),
+        )
+    }
+
+    #[test]
+    fn counter_round_trip() {
+        for suite in [AeadSuite::ChaCha20Poly1305, AeadSuite::Aes256Gcm] {
+            let (up, _) = direction_keys(suite);
+            for (counter, msg) in [(0, &b""[..]), (1, b"hello"), (u64::MAX, &[7u8; 1400][..])] {
+                let ctext = up.encrypt_counter(counter, msg);
+                assert_eq!(ctext.len(), msg.len() + NgAead::counter_overhead());
+                // the counter is masked on the wire
+                assert_ne!(&ctext[..COUNTER_LEN], &counter.to_le_bytes()[..]);
+                let (got_counter, plain) = up.decrypt_counter(&ctext).unwrap();
+                assert_eq!(got_counter, counter);
+                assert_eq!(&plain[..], msg);
+            }
+        }
+    }
+
+    #[test]
+    fn counter_replay_is_identical() {
+        // decryption is stateless, so a replayed packet decrypts to the same counter. RecvMachine's counter filter is what rejects it
+        let (up, _) = direction_keys(AeadSuite::ChaCha20Poly1305);
+        let ctext = up.encrypt_counter(5, b"hello");
+        let first = up.decrypt_counter(&ctext).unwrap();
+        let second = up.decrypt_counter(&ctext).unwrap();
+        assert_eq!(first.0, 5);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn counter_wrong_direction() {
+        for suite in [AeadSuite::ChaCha20Poly1305, AeadSuite::Aes256Gcm] {
+            let (up, down) = direction_keys(suite);
+            let ctext = up.encrypt_counter(1, b"hello");
+            assert!(matches!(
+                down.decrypt_counter(&ctext),
+                Err(AeadError::DecryptionFailure)
+            ));
+            let ctext = down.encrypt_counter(1, b"hello");
+            assert!(up.decrypt_counter(&ctext).is_err());
+        }
+        // the same key under another suite doesn't work either
+        let (chacha, _) = direction_keys(AeadSuite::ChaCha20Poly1305);
+        let (aes, _) = direction_keys(AeadSuite::Aes256Gcm);
+        assert!(aes
+            .decrypt_counter(&chacha.encrypt_counter(1, b"hello"))
+            .is_err());
+    }
+
+    #[test]
+    fn counter_tampering() {
+        let (up, _) = direction_keys(AeadSuite::ChaCha20Poly1305);
+        let ctext = up.encrypt_counter(1, b"hello");
+        // flipping a bit of the masked counter changes the nonce, and anywhere else breaks the tag
+        for i in 0..ctext.len() {
+            let mut tampered = ctext.to_vec();
+            tampered[i] ^= 1;
+            assert!(up.decrypt_counter(&tampered).is_err());
+        }
+        assert!(matches!(
+            up.decrypt_counter(&ctext[..COUNTER_LEN + 15]),
+            Err(AeadError::BadLength)
+        ));
+    }
+
+    #[cfg(feature = "post-quantum")]
+    #[test]
+    fn kem_ciphertext_len() {
+        let (secret, encaps_key) = KemSecret::generate();
+        let (ciphertext, shared) = kem_encapsulate(&encaps_key).unwrap();
+        assert_eq!(ciphertext.len(), KEM_CIPHERTEXT_LEN);
+        assert_eq!(secret.decapsulate(&ciphertext), Some(shared));
+        // garbage from the network is rejected rather than panicking
+        assert!(kem_encapsulate(&encaps_key[1..]).is_none());
+        assert!(secret.decapsulate(&ciphertext[1..]).is_none());
+    }
+
+    #[test]
+    fn probe_mac_binds_everything() {
+        let mac = probe_mac(&[1; 32], false, 7);
+        assert_eq!(mac, probe_mac(&[1; 32], false, 7));
+        // a reply can't be passed off as a probe, nor a probe for another session or nonce
+        assert_ne!(mac, probe_mac(&[1; 32], true, 7));
+        assert_ne!(mac, probe_mac(&[2; 32], false, 7));
+        assert_ne!(mac, probe_mac(&[1; 32], false, 8));
+    }
+
+    #[test]
+    fn negotiation_mix_binds_transcript() {
+        let key = blake3::hash(b"shared secret");
+        let suites = [AeadSuite::Aes256Gcm, AeadSuite::ChaCha20Poly1305];
+        let honest = negotiation_mix(key, (3, 5), &suites, 5, AeadSuite::Aes256Gcm);
+        assert_eq!(
+            honest,
+            negotiation_mix(key, (3, 5), &suites, 5, AeadSuite::Aes256Gcm)
+        );
+        // an attacker narrowing the offer, or swapping the choice, changes the key
+        assert_ne!(
+            honest,
+            negotiation_mix(key, (3, 3), &suites, 3, AeadSuite::Aes256Gcm)
+        );
+        assert_ne!(
+            honest,
+            negotiation_mix(key, (3, 5), &suites[1..], 5, AeadSuite::ChaCha20Poly1305)
+        );
+        assert_ne!(
+            honest,
+            negotiation_mix(key, (3, 5), &suites, 5, AeadSuite::ChaCha20Poly1305)
+        );
+    }
+}
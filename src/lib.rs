@@ -8,7 +8,7 @@
 //!
 //! - State-of-the-art reliable streaming protocol with selective ACKs and BIC-based congestion control. Notably, it has better fairness *and* performance in modern networks than protocols like KCP that ape 1980s TCP specifications.
 //! - Strong, state-of-the-art (obfs4-like) obfuscation. Sosistab servers cannot be detected by active probing, and Sosistab traffic is reasonably indistinguishable from random. We also make a best-effort attempt at hiding side-channels through random padding.
-//! - Strong yet lightweight authenticated encryption with chacha20-poly1305
+//! - Strong yet lightweight authenticated encryption with chacha20-poly1305, or AES-256-GCM on hardware that accelerates it
 //! - Deniable public-key encryption with triple-x25519, with servers having long-term public keys that must be provided out-of-band. Similar to decent encrypted transports like TLS and DTLS --- but not to the whole Shadowsocks/Vmess family of protocols --- different clients have different session keys and cannot spy on each other.
 //! - Reed-Solomon error correction that targets a certain application packet loss level. Intelligent autotuning and dynamic batch sizes make performance much better than other FEC-based tools like udpspeeder. This lets Sosistab turns high-bandwidth, high-loss links to medium-bandwidth, low-loss links, which is generally much more useful.
 //! - Avoids last-mile congestive collapse but works around lossy links. Shamelessly unfair in permanently congested WANs --- but that's really their problem, not yours. In any case, permanently congested WANs are observationally identical to lossy links, and any solution for the latter will cause unfairness in the former.
@@ -24,6 +24,9 @@ mod pacer;
 pub use buffer::*;
 mod client;
 mod crypt;
+pub use crypt::AeadSuite;
+#[doc(hidden)]
+pub use crypt::NgAead;
 mod fec;
 mod listener;
 use bincode::Options;
@@ -33,6 +36,7 @@ use serde::de::DeserializeOwned;
 use smallvec::SmallVec;
 use std::{future::Future, pin::Pin, task::Poll};
 mod protocol;
+pub use protocol::HandshakePadding;
 pub mod runtime;
 mod session;
 pub use session::*;
@@ -40,8 +44,12 @@ mod backhaul;
 mod mux;
 pub use mux::*;
 mod tcp;
-use backhaul::*;
+pub use backhaul::*;
+mod memory;
+pub use memory::*;
 mod recfilter;
+mod transform;
+pub use transform::*;
 mod stats;
 pub use stats::*;
 
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::crypt::Cookie;
+
+/// The set of long-term secret keys a listener answers to. The first key is the primary key, while the rest are retiring keys that are still accepted so that clients holding old public keys are not stranded.
+#[derive(Clone)]
+pub(crate) struct KeyRing {
+    keys: Arc<RwLock<Vec<(Cookie, x25519_dalek::StaticSecret)>>>,
+}
+
+impl KeyRing {
+    /// Creates a new key ring from a primary key and some retiring keys.
+    pub fn new(
+        primary: x25519_dalek::StaticSecret,
+        retiring: impl IntoIterator<Item = x25519_dalek::StaticSecret>,
+    ) -> Self {
+        Self {
+            keys: Arc::new(RwLock::new(Self::with_cookies(primary, retiring))),
+        }
+    }
+
+    /// Atomically replaces all the keys in the key ring.
+    pub fn replace(
+        &self,
+        primary: x25519_dalek::StaticSecret,
+        retiring: impl IntoIterator<Item = x25519_dalek::StaticSecret>,
+    ) {
+        *self.keys.write() = Self::with_cookies(primary, retiring);
+    }
+
+    /// Gets a snapshot of all the active keys, primary key first.
+    pub fn snapshot(&self) -> Vec<(Cookie, x25519_dalek::StaticSecret)> {
+        self.keys.read().clone()
+    }
+
+    fn with_cookies(
+        primary: x25519_dalek::StaticSecret,
+        retiring: impl IntoIterator<Item = x25519_dalek::StaticSecret>,
+    ) -> Vec<(Cookie, x25519_dalek::StaticSecret)> {
+        std::iter::once(primary)
+            .chain(retiring)
+            .map(|sk| (Cookie::new((&sk).into()), sk))
+            .collect()
+    }
+}
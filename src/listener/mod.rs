@@ -1,17 +1,28 @@
+#[cfg(feature = "post-quantum")]
+use crate::crypt::{hybrid_mix, kem_encapsulate};
 use crate::tcp::TcpServerBackhaul;
 use crate::{
     backhaul::{Backhaul, StatsBackhaul},
-    crypt::{triple_ecdh, Cookie, LegacyAead},
-    protocol::HandshakeFrame,
-    runtime, safe_deserialize, Role,
+    crypt::{negotiation_mix, triple_ecdh, AeadSuite, LegacyAead},
+    protocol::{negotiate_version, HandshakeFrame, HandshakePadding},
+    runtime, safe_deserialize,
+    transform::{PacketTransform, TransformBackhaul},
+    Role,
 };
 use crate::{buffer::Buff, protocol::HandshakeFrame::*};
 use crate::{
-    recfilter::RECENT_FILTER,
-    session::{Session, SessionConfig},
+    crypt::cookie_window,
+    recfilter::RecentFilter,
+    session::{
+        unix_time_ms, CoverTraffic, PaddingProfile, Session, SessionConfig, SessionKeys,
+        SessionProgress, TokenIssuer, COUNTER_NONCE_VERSION, MAX_RESUME_EPOCH, REKEY_FRAMES,
+        REKEY_VERSION,
+    },
+    tcp::TcpServerCtx,
 };
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rand::prelude::*;
+use rustc_hash::FxHashSet;
 use serde::{Deserialize, Serialize};
 use smol::net::AsyncToSocketAddrs;
 use smol::{
@@ -25,8 +36,11 @@ use std::{
 };
 use table::ShardedAddrs;
 
-use table::SessionTable;
+pub use crate::tcp::TlsIdentity;
+pub(crate) use keys::KeyRing;
+use table::{SessionId, SessionTable};
 
+mod keys;
 mod table;
 
 /// Statistics for a sosistab listener.
@@ -35,16 +49,154 @@ pub struct ListenerStats {
     pub packets_processed: AtomicUsize,
     pub packets_failed: AtomicUsize,
     pub packets_replay: AtomicUsize,
+    pub packets_unauthorized: AtomicUsize,
+    pub packets_challenged: AtomicUsize,
+    /// Number of handshakes in the newest generation of the replay filter. The filter's false-positive rate climbs once this exceeds `replay_filter_capacity`.
+    pub replay_filter_items: AtomicUsize,
+    pub replay_filter_capacity: AtomicUsize,
     pub injecting: AtomicBool,
     pub handshaking: AtomicBool,
     pub sessions_queued: AtomicUsize,
 }
 
+/// A closure that decides whether a client, identified by its long-term public key, may establish sessions.
+pub type ClientAuthorizer = Arc<dyn Fn(&x25519_dalek::PublicKey) -> bool + Send + Sync + 'static>;
+
+/// Configuration of a listener.
+#[derive(Clone)]
+pub struct ListenerConfig {
+    pub long_sk: x25519_dalek::StaticSecret,
+    /// Old long-term keys that are being rotated out, but are still accepted for new handshakes.
+    pub retiring_sks: Vec<x25519_dalek::StaticSecret>,
+    /// If set, only clients whose long-term public keys are accepted by this closure can establish sessions. Everybody else is silently ignored.
+    pub client_auth: Option<ClientAuthorizer>,
+    /// Key used to encrypt resume tokens. If this is not set, a random key is used, so resume tokens don't survive a restart of the listener.
+    ///
+    /// Resume tokens contain their session's keys at the time they were issued and travel in the clear, so anybody holding this key can decrypt whatever recorded sessions sent after the tokens they recorded. Sessions reissue tokens whenever their keys ratchet, so what was sent before the oldest recorded token stays safe. To have sessions survive restarts, set this to a key from [ListenerConfig::generate_token_key] that is stored apart from `long_sk` and rotated on its own schedule.
+    pub token_key: Option<[u8; 32]>,
+    /// How many handshakes the replay filter is sized for, per generation. This bounds the memory used by the replay filter.
+    pub replay_filter_size: usize,
+    /// If set, a ClientHello is first answered with a small stateless challenge bound to the client's IP address, and the expensive handshake is only done once the client echoes it. This prevents spoofed ClientHellos from using the listener for CPU exhaustion or reflection attacks. Only UDP listeners use this, since TCP already verifies the client's address.
+    pub require_challenge: bool,
+    /// AEAD suites we are willing to use for sessions, and for the TCP connections carrying them, most preferred first. The first one the client also supports is picked. Defaults to preferring AES-256-GCM on CPUs that accelerate it.
+    pub aead_suites: Vec<AeadSuite>,
+    /// How sessions pad outgoing packets.
+    pub padding: PaddingProfile,
+    /// If set, sessions shape the timing of outgoing packets and send cover traffic when idle.
+    pub cover: Option<CoverTraffic>,
+    /// How long ServerHellos are padded to. Over UDP, a reply is never padded beyond the length of the ClientHello it answers, so that the listener cannot be used for amplification.
+    pub handshake_padding: HandshakePadding,
+    /// Transforms applied to every packet the listener sends and receives, in order for outgoing packets and in reverse for incoming ones. Clients must use the same transforms.
+    pub transforms: Vec<Arc<dyn PacketTransform>>,
+    /// If set, TCP listeners also accept WebSocket clients upgrading at this path, on the same port and with or without TLS. Other HTTP requests are answered with a 404.
+    pub websocket_path: Option<String>,
+    /// Certificate chain that TCP listeners present to TLS clients. If this is not set, a fresh self-signed certificate for a random domain is generated for every connection, which is easy to tell apart from a real HTTPS server.
+    pub tls_identity: Option<TlsIdentity>,
+    /// host:port of a web server that TCP listeners hand connections failing the sosistab handshake to, such as active probes. The connection is transparently forwarded there, including what the client already sent, so probers see an ordinary website. Connections that used TLS are forwarded after TLS is terminated, so this should be a plain HTTP server for the site the certificate is for. If this is not set, such connections are dropped.
+    pub fallback: Option<String>,
+    /// If set, the listener shares its sessions with every other listener given the same [SharedSessions], so that clients can migrate sessions between them, for example from UDP to TLS. Unless `token_key` is set, the listeners also share a resume token key.
+    pub shared_sessions: Option<SharedSessions>,
+}
+
+/// Sessions shared between several listeners, for [ListenerConfig::shared_sessions]. A session accepted by one of the listeners is only returned by that listener's [Listener::accept_session], but its client may move it onto any of the others with a [SessionMigrator](crate::SessionMigrator).
+#[derive(Clone)]
+pub struct SharedSessions {
+    table: SessionTable,
+    token_key: [u8; 32],
+}
+
+impl SharedSessions {
+    /// Creates a new, empty set of shared sessions.
+    pub fn new() -> Self {
+        Self {
+            table: SessionTable::default(),
+            token_key: rand::thread_rng().gen(),
+        }
+    }
+}
+
+impl Default for SharedSessions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ListenerConfig {
+    /// Creates a new ListenerConfig that accepts any client.
+    pub fn new(long_sk: x25519_dalek::StaticSecret) -> Self {
+        Self {
+            long_sk,
+            retiring_sks: Vec::new(),
+            client_auth: None,
+            token_key: None,
+            replay_filter_size: 100_000,
+            require_challenge: false,
+            aead_suites: AeadSuite::preferred(),
+            padding: PaddingProfile::default(),
+            cover: None,
+            handshake_padding: HandshakePadding::default(),
+            transforms: Vec::new(),
+            websocket_path: None,
+            tls_identity: None,
+            fallback: None,
+            shared_sessions: None,
+        }
+    }
+
+    /// Only accepts clients whose long-term public key is in the given list.
+    pub fn allow_clients(
+        mut self,
+        clients: impl IntoIterator<Item = x25519_dalek::PublicKey>,
+    ) -> Self {
+        let allowed: FxHashSet<[u8; 32]> = clients.into_iter().map(|pk| pk.to_bytes()).collect();
+        self.client_auth = Some(Arc::new(move |pk| allowed.contains(pk.as_bytes())));
+        self
+    }
+
+    /// Generates a random resume token key, to be stored and passed to every incarnation of the listener as [ListenerConfig::token_key]. This is the recommended way of having sessions survive restarts.
+    pub fn generate_token_key() -> [u8; 32] {
+        rand::thread_rng().gen()
+    }
+
+    /// Derives the resume token key from the long-term secret key and an epoch. Listeners with the same long-term key and epoch accept each other's resume tokens, so sessions survive restarts; bumping the epoch invalidates all outstanding tokens.
+    ///
+    /// This is convenient, since there is no extra secret to store, but resume tokens contain their session's keys, so whoever obtains the token key can decrypt recorded sessions from the first token they recorded onwards. Since every session's first token is in its ServerHello, `long_sk` alone then opens every recorded session. Prefer a separate key from [ListenerConfig::generate_token_key] unless that is acceptable.
+    pub fn derive_token_key(mut self, epoch: u64) -> Self {
+        let mut key = [0u8; 32];
+        blake3::derive_key(
+            &format!("sosistab-token-key-{}", epoch),
+            &self.long_sk.to_bytes(),
+            &mut key,
+        );
+        self.token_key = Some(key);
+        self
+    }
+
+    fn keyring(&self) -> KeyRing {
+        KeyRing::new(self.long_sk.clone(), self.retiring_sks.iter().cloned())
+    }
+
+    fn recent_filter(&self, stats: Arc<ListenerStats>) -> Arc<Mutex<RecentFilter>> {
+        Arc::new(Mutex::new(RecentFilter::new(
+            self.replay_filter_size,
+            cookie_window(),
+            stats,
+        )))
+    }
+}
+
+impl From<x25519_dalek::StaticSecret> for ListenerConfig {
+    fn from(long_sk: x25519_dalek::StaticSecret) -> Self {
+        Self::new(long_sk)
+    }
+}
+
 /// A sosistab listener.
 pub struct Listener {
     accepted: Receiver<Session>,
     local_addr: SocketAddr,
     stats: Arc<ListenerStats>,
+    keys: KeyRing,
     _task: Vec<smol::Task<()>>,
 }
 
@@ -57,7 +209,7 @@ impl Listener {
     /// Creates a new listener given the parameters.
     pub async fn listen_udp(
         addr: SocketAddr,
-        long_sk: x25519_dalek::StaticSecret,
+        cfg: impl Into<ListenerConfig>,
         on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
         on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
     ) -> std::io::Result<Self> {
@@ -66,13 +218,30 @@ impl Listener {
         #[cfg(target_os = "linux")]
         let socket = fastudp::FastUdpSocket::from(std::net::UdpSocket::bind(addr)?);
         let local_addr = socket.get_ref().local_addr().unwrap();
-        let cookie = Cookie::new((&long_sk).into());
+        Self::listen_custom(local_addr, socket, cfg, on_recv, on_send).await
+    }
+
+    /// Creates a new listener over a user-supplied backhaul. `local_addr` is only used as the value returned by [Listener::local_addr].
+    pub async fn listen_custom(
+        local_addr: SocketAddr,
+        socket: impl Backhaul + 'static,
+        cfg: impl Into<ListenerConfig>,
+        on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+        on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+    ) -> std::io::Result<Self> {
+        let cfg: ListenerConfig = cfg.into();
+        let keys = cfg.keyring();
         let (send, recv) = smol::channel::unbounded();
         let stats: Arc<ListenerStats> = Default::default();
         let la = ListenerActor::new(
-            Arc::new(StatsBackhaul::new(socket, on_recv, on_send)),
-            cookie,
-            long_sk,
+            Arc::new(StatsBackhaul::new(
+                TransformBackhaul::new(Arc::new(socket), cfg.transforms.clone()),
+                on_recv,
+                on_send,
+            )),
+            cfg.clone(),
+            keys.clone(),
+            cfg.recent_filter(stats.clone()),
             stats.clone(),
         );
         // let task = (0..std::thread::available_parallelism().unwrap().get())
@@ -82,6 +251,7 @@ impl Listener {
             accepted: recv,
             local_addr,
             stats,
+            keys,
             _task: vec![runtime::spawn(la.run(send))],
         })
     }
@@ -89,22 +259,44 @@ impl Listener {
     /// Creates a new listener given the parameters.
     pub async fn listen_tcp(
         addr: impl AsyncToSocketAddrs,
-        long_sk: x25519_dalek::StaticSecret,
+        cfg: impl Into<ListenerConfig>,
         on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
         on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
     ) -> std::io::Result<Self> {
         // let addr = async_net::resolve(addr).await;
         let listener = TcpListener::bind(addr).await?;
         let local_addr = listener.local_addr().unwrap();
-        let cookie = Cookie::new((&long_sk).into());
-        let socket = TcpServerBackhaul::new(listener, long_sk.clone());
-        let (send, recv) = smol::channel::unbounded();
+        let cfg: ListenerConfig = cfg.into();
+        let keys = cfg.keyring();
         let stats: Arc<ListenerStats> = Default::default();
+        let recent_filter = cfg.recent_filter(stats.clone());
+        let socket = TcpServerBackhaul::new(
+            listener,
+            TcpServerCtx {
+                keys: keys.clone(),
+                client_auth: cfg.client_auth.clone(),
+                recent_filter: recent_filter.clone(),
+                handshake_padding: cfg.handshake_padding,
+                aead_suites: cfg.aead_suites.clone(),
+                websocket_path: cfg.websocket_path.clone(),
+                tls_identity: cfg.tls_identity.clone(),
+                fallback: cfg.fallback.clone(),
+            },
+        );
+        let (send, recv) = smol::channel::unbounded();
         let task = runtime::spawn(
             ListenerActor::new(
-                Arc::new(StatsBackhaul::new(socket, on_recv, on_send)),
-                cookie,
-                long_sk,
+                Arc::new(StatsBackhaul::new(
+                    TransformBackhaul::new(Arc::new(socket), cfg.transforms.clone()),
+                    on_recv,
+                    on_send,
+                )),
+                ListenerConfig {
+                    require_challenge: false,
+                    ..cfg
+                },
+                keys.clone(),
+                recent_filter,
                 stats.clone(),
             )
             .run(send),
@@ -113,6 +305,7 @@ impl Listener {
             accepted: recv,
             local_addr,
             stats,
+            keys,
             _task: vec![task],
         })
     }
@@ -122,6 +315,15 @@ impl Listener {
         self.stats.clone()
     }
 
+    /// Replaces the long-term keys of this listener. New handshakes are accepted for the primary key as well as all the retiring keys, while existing sessions are unaffected.
+    pub fn rotate_keys(
+        &self,
+        primary: x25519_dalek::StaticSecret,
+        retiring: impl IntoIterator<Item = x25519_dalek::StaticSecret>,
+    ) {
+        self.keys.replace(primary, retiring)
+    }
+
     /// Gets the local address.
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
@@ -131,58 +333,84 @@ impl Listener {
 #[derive(Clone)]
 struct ListenerActor {
     socket: Arc<dyn Backhaul>,
-    cookie: Cookie,
-    long_sk: x25519_dalek::StaticSecret,
+    keys: KeyRing,
+    client_auth: Option<ClientAuthorizer>,
+    recent_filter: Arc<Mutex<RecentFilter>>,
+    challenge_key: Option<[u8; 32]>,
     token_key: [u8; 32],
+    aead_suites: Vec<AeadSuite>,
+    padding: PaddingProfile,
+    cover: Option<CoverTraffic>,
+    handshake_padding: HandshakePadding,
 
     session_table: SessionTable,
+    // channel for dropping sessions
+    send_dead: Sender<SessionId>,
+    recv_dead: Receiver<SessionId>,
 
     stats: Arc<ListenerStats>,
 }
 impl ListenerActor {
     fn new(
         socket: Arc<dyn Backhaul>,
-        cookie: Cookie,
-        long_sk: x25519_dalek::StaticSecret,
+        cfg: ListenerConfig,
+        keys: KeyRing,
+        recent_filter: Arc<Mutex<RecentFilter>>,
         stats: Arc<ListenerStats>,
     ) -> Self {
-        let token_key = {
+        let token_key = cfg.token_key.unwrap_or_else(|| {
+            if let Some(shared) = &cfg.shared_sessions {
+                return shared.token_key;
+            }
             let mut buf = [0u8; 32];
             rand::thread_rng().fill_bytes(&mut buf);
             buf
-        };
+        });
+        let (send_dead, recv_dead) = smol::channel::unbounded();
 
         Self {
             socket,
-            cookie,
-            long_sk,
+            keys,
+            client_auth: cfg.client_auth,
+            recent_filter,
+            challenge_key: if cfg.require_challenge {
+                Some(rand::thread_rng().gen())
+            } else {
+                None
+            },
             token_key,
-            session_table: SessionTable::default(),
+            aead_suites: cfg.aead_suites,
+            padding: cfg.padding,
+            cover: cfg.cover,
+            handshake_padding: cfg.handshake_padding,
+            session_table: cfg
+                .shared_sessions
+                .map(|shared| shared.table)
+                .unwrap_or_default(),
+            send_dead,
+            recv_dead,
             stats,
         }
     }
 
     async fn run(mut self, accepted: Sender<Session>) {
-        // channel for dropping sessions
-        let (send_dead, recv_dead) = smol::channel::unbounded();
-
         // two possible events
         enum Evt {
             NewRecv((Buff, SocketAddr)),
-            DeadSess(Buff),
+            DeadSess(SessionId),
         }
 
         loop {
             let event = smol::future::race(
                 async { Evt::NewRecv(self.socket.recv_from().await.unwrap()) },
-                async { Evt::DeadSess(recv_dead.recv().await.unwrap()) },
+                async { Evt::DeadSess(self.recv_dead.recv().await.unwrap()) },
             );
             self.stats
                 .sessions_queued
                 .store(accepted.len(), Ordering::Relaxed);
             match event.await {
-                Evt::DeadSess(resume_token) => {
-                    self.session_table.delete(resume_token);
+                Evt::DeadSess(session_id) => {
+                    self.session_table.delete(session_id);
                 }
                 Evt::NewRecv((buffer, addr)) => {
                     self.stats.packets_processed.fetch_add(1, Ordering::Relaxed);
@@ -198,32 +426,53 @@ impl ListenerActor {
                     let stats = self.stats.clone();
                     stats.handshaking.store(true, Ordering::Relaxed);
                     scopeguard::defer!(stats.handshaking.store(false, Ordering::Relaxed));
-                    let s2c_key = self.cookie.generate_s2c().next().unwrap();
                     let mut failed = true;
-                    for possible_key in self.cookie.generate_c2s() {
-                        let crypter = LegacyAead::new(&possible_key);
-                        if let Some(handshake) = crypter.pad_decrypt_v1::<HandshakeFrame>(&buffer) {
-                            if !RECENT_FILTER.lock().check(&buffer) {
-                                tracing::error!(
-                                    "discarding replay attempt with len {} from {addr}: {:?}",
-                                    buffer.len(),
-                                    handshake
-                                );
-                                self.stats.packets_replay.fetch_add(1, Ordering::Relaxed);
-                                break;
+                    // try every active long-term key, answering with whichever one the client targeted
+                    'outer: for (cookie, long_sk) in self.keys.snapshot() {
+                        let s2c_key = cookie.generate_s2c().next().unwrap();
+                        for possible_key in cookie.generate_c2s() {
+                            let crypter = LegacyAead::new(&possible_key);
+                            if let Some(handshake) =
+                                crypter.pad_decrypt_v1::<HandshakeFrame>(&buffer)
+                            {
+                                failed = false;
+                                // unchallenged hellos get a cheap challenge, without touching the replay filter
+                                if let Some(challenge) = self.challenge_needed(&handshake, addr) {
+                                    let reply = LegacyAead::new(&s2c_key).pad_encrypt_v1(
+                                        &[HandshakeFrame::ServerChallenge { challenge }],
+                                        CHALLENGE_LEN,
+                                    );
+                                    self.stats
+                                        .packets_challenged
+                                        .fetch_add(1, Ordering::Relaxed);
+                                    if let Err(err) = self.socket.send_to(reply, addr).await {
+                                        tracing::error!("weird socket error {:?}", err);
+                                    }
+                                    break 'outer;
+                                }
+                                // replaying a probe only gets it answered again, so probes don't need to fill up the replay filter
+                                let is_probe = matches!(handshake.first(), Some(PathProbe { .. }));
+                                if !is_probe && !self.recent_filter.lock().check(&buffer) {
+                                    tracing::error!(
+                                        "discarding replay attempt with len {} from {addr}: {:?}",
+                                        buffer.len(),
+                                        handshake
+                                    );
+                                    self.stats.packets_replay.fetch_add(1, Ordering::Relaxed);
+                                    break 'outer;
+                                }
+                                tracing::trace!("decoded some sort of handshake: {:?}", handshake);
+                                self.handle_handshake(
+                                    handshake,
+                                    buffer.len().saturating_sub(LegacyAead::overhead()),
+                                    &long_sk,
+                                    s2c_key,
+                                    addr,
+                                    accepted.clone(),
+                                )
+                                .await;
+                                break 'outer;
                             }
-                            tracing::trace!("decoded some sort of handshake: {:?}", handshake);
-                            let handshake = handshake[0].clone();
-                            self.handle_handshake(
-                                handshake,
-                                s2c_key,
-                                addr,
-                                send_dead.clone(),
-                                accepted.clone(),
-                            )
-                            .await;
-                            failed = false;
-                            break;
                         }
                     }
                     if failed {
@@ -234,72 +483,247 @@ impl ListenerActor {
         }
     }
 
+    /// If this listener requires challenges and the handshake is a ClientHello that doesn't echo a valid one, returns a fresh challenge to send back.
+    fn challenge_needed(&self, handshake: &[HandshakeFrame], addr: SocketAddr) -> Option<Buff> {
+        let challenge_key = self.challenge_key.as_ref()?;
+        if !matches!(handshake.first(), Some(ClientHello { .. })) {
+            return None;
+        }
+        let window = unix_time_ms() / 1000 / CHALLENGE_WINDOW_SECS;
+        let echoed = handshake.iter().find_map(|frame| match frame {
+            ChallengeEcho { challenge } => Some(challenge),
+            _ => None,
+        });
+        if let Some(echoed) = echoed {
+            // challenges from the previous window are still accepted, so that they don't expire in flight
+            for window in [window, window.saturating_sub(1)] {
+                if constant_time_eq::constant_time_eq(
+                    echoed,
+                    &challenge_for(challenge_key, addr, window),
+                ) {
+                    return None;
+                }
+            }
+            tracing::debug!("bad challenge echoed by {}", addr);
+        }
+        Some(Buff::copy_from_slice(&challenge_for(
+            challenge_key,
+            addr,
+            window,
+        )))
+    }
+
     async fn handle_handshake(
         &mut self,
-        handshake: HandshakeFrame,
+        handshake: Vec<HandshakeFrame>,
+        request_len: usize,
+        long_sk: &x25519_dalek::StaticSecret,
         s2c_key: [u8; 32],
         addr: SocketAddr,
-        send_dead: Sender<Buff>,
         accepted: Sender<Session>,
     ) {
-        match handshake {
-            ClientHello {
+        // a client asking for a hybrid handshake sends its ML-KEM key alongside the hello
+        #[cfg(feature = "post-quantum")]
+        let kem_offer = handshake.iter().find_map(|frame| match frame {
+            KemOffer { encaps_key } => Some(encaps_key.clone()),
+            _ => None,
+        });
+        // newer clients advertise every version they speak, while older ones only send the version in the hello
+        let supported_versions = handshake.iter().find_map(|frame| match frame {
+            SupportedVersions { min, max } => Some((*min, *max)),
+            _ => None,
+        });
+        // clients that don't offer AEAD suites only speak ChaCha20/Poly1305
+        let aead_offer = handshake.iter().find_map(|frame| match frame {
+            AeadOffer { suites } => Some(suites.clone()),
+            _ => None,
+        });
+        // clients say where their session stands alongside resumes
+        let resume_progress = handshake.iter().find_map(|frame| match frame {
+            ResumeProgress {
+                up_epoch,
+                down_epoch,
+                up_frame_no,
+                up_counter,
+                time_ms,
+                mac,
+            } => Some((
+                SessionProgress {
+                    up_epoch: *up_epoch,
+                    down_epoch: *down_epoch,
+                    up_frame_no: *up_frame_no,
+                    up_counter: *up_counter,
+                },
+                *time_ms,
+                *mac,
+            )),
+            _ => None,
+        });
+        match handshake.into_iter().next() {
+            Some(ClientHello {
                 long_pk,
                 eph_pk,
                 version,
-            } => {
-                if version != 3 {
-                    tracing::warn!("got packet with incorrect version {}", version);
+            }) => {
+                let (min, max) = supported_versions.unwrap_or((version, version));
+                let version = if let Some(version) = negotiate_version(min, max) {
+                    version
+                } else {
+                    tracing::warn!("got packet with unsupported versions {}..={}", min, max);
+                    return;
+                };
+                let offered = aead_offer
+                    .clone()
+                    .unwrap_or_else(|| vec![AeadSuite::ChaCha20Poly1305]);
+                let aead = if let Some(aead) = self
+                    .aead_suites
+                    .iter()
+                    .copied()
+                    .find(|suite| offered.contains(suite))
+                {
+                    aead
+                } else {
+                    tracing::warn!("no common AEAD suite with {}: {:?}", addr, offered);
                     return;
+                };
+                if let Some(client_auth) = &self.client_auth {
+                    if !client_auth(&long_pk) {
+                        tracing::debug!(
+                            "ignoring ClientHello from unauthorized client at {}",
+                            addr
+                        );
+                        self.stats
+                            .packets_unauthorized
+                            .fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
                 }
                 // generate session key
-                let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+                let my_eph_sk = x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>());
+                let sess_key = triple_ecdh(long_sk, &my_eph_sk, &long_pk, &eph_pk);
+                // without post-quantum support we ignore KemOffers, and clients that asked for a hybrid handshake refuse the classical one we answer with
+                #[cfg(not(feature = "post-quantum"))]
+                let kem_reply: Option<HandshakeFrame> = None;
+                #[cfg(feature = "post-quantum")]
+                let (sess_key, kem_reply) = match kem_offer {
+                    Some(encaps_key) => match kem_encapsulate(&encaps_key) {
+                        Some((ciphertext, kem_secret)) => (
+                            hybrid_mix(sess_key, &kem_secret),
+                            Some(KemReply { ciphertext }),
+                        ),
+                        None => {
+                            tracing::warn!("got malformed ML-KEM key from {}", addr);
+                            return;
+                        }
+                    },
+                    None => (sess_key, None),
+                };
+                // clients that send SupportedVersions also bind the negotiation into the session key, so that it can't be tampered with
+                let sess_key = if supported_versions.is_some() {
+                    negotiation_mix(sess_key, (min, max), &offered, version, aead)
+                } else {
+                    sess_key
+                };
                 let token = TokenInfo {
-                    sess_key: Buff::copy_from_slice(
-                        triple_ecdh(&self.long_sk, &my_eph_sk, &long_pk, &eph_pk).as_bytes(),
-                    ),
-                    init_time_ms: std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as u64,
+                    keys: SessionKeys::from_session_key(sess_key.as_bytes()),
+                    session_id: rand::random(),
+                    init_time_ms: unix_time_ms(),
                     version,
+                    aead,
                 }
                 .encrypt(&self.token_key);
                 let reply = HandshakeFrame::ServerHello {
-                    long_pk: (&self.long_sk).into(),
+                    long_pk: long_sk.into(),
                     eph_pk: (&my_eph_sk).into(),
                     resume_token: token,
                 };
-                let reply = LegacyAead::new(&s2c_key).pad_encrypt_v1(&[reply], 1000);
+                let chosen_version = supported_versions.map(|_| ChosenVersion { version });
+                let chosen_aead = aead_offer.map(|_| ChosenAead { suite: aead });
+                let reply = std::iter::once(reply)
+                    .chain(kem_reply)
+                    .chain(chosen_version)
+                    .chain(chosen_aead)
+                    .collect::<Vec<_>>();
+                // padding never truncates, so we can only keep replies from exceeding requests by not answering short ones. real clients pad their hellos to fit any reply
+                let reply_len: usize = reply.iter().map(|frame| frame.to_bytes().len()).sum();
+                if reply_len > request_len {
+                    tracing::debug!(
+                        "ignoring ClientHello from {} too short for our reply ({} < {})",
+                        addr,
+                        request_len,
+                        reply_len
+                    );
+                    return;
+                }
+                let reply = LegacyAead::new(&s2c_key)
+                    .pad_encrypt_v1(&reply, self.handshake_padding.sample().min(request_len));
                 tracing::debug!("GONNA reply to ClientHello from {}", addr);
                 if let Err(err) = self.socket.send_to(reply, addr).await {
                     tracing::error!("weird socket error {:?}", err);
                 }
                 tracing::debug!("replied to ClientHello from {}", addr);
             }
-            ClientResume {
+            Some(ClientResume {
                 resume_token,
                 shard_id,
-            } => {
+            }) => {
                 tracing::trace!("Got ClientResume-{} from {}!", shard_id, addr);
                 let tokinfo = TokenInfo::decrypt(&self.token_key, &resume_token);
                 if let Some(tokinfo) = tokinfo {
-                    // first check whether we know about the resume token
-                    if !self
-                        .session_table
-                        .rebind(addr, shard_id, resume_token.clone())
-                    {
+                    // first check whether we know about the session
+                    if !self.session_table.rebind(
+                        addr,
+                        shard_id,
+                        tokinfo.session_id,
+                        self.socket.clone(),
+                    ) {
                         tracing::debug!("ClientResume from {} ({:?}) is new!", addr, resume_token);
+                        let progress = match resume_progress {
+                            Some((progress, time_ms, mac))
+                                if progress.verify(&tokinfo.keys.resume_key, time_ms, mac) =>
+                            {
+                                progress
+                            }
+                            // older clients don't ratchet their keys, so they are always at the first epochs, and can't tell us what to reject as replays
+                            None if tokinfo.version < REKEY_VERSION => SessionProgress::default(),
+                            _ => {
+                                tracing::warn!("ClientResume from {} with bad progress", addr);
+                                return;
+                            }
+                        };
+                        if progress.up_epoch > tokinfo.keys.up.epoch + MAX_RESUME_EPOCH
+                            || progress.down_epoch > tokinfo.keys.down.epoch + MAX_RESUME_EPOCH
+                        {
+                            tracing::warn!("ClientResume from {} with absurd key epochs", addr);
+                            return;
+                        }
 
-                        let write_socket = self.socket.clone();
-                        let locked_addrs = ShardedAddrs::new(shard_id, addr);
+                        let locked_addrs = ShardedAddrs::new(shard_id, addr, self.socket.clone());
                         let locked_addrs = Arc::new(RwLock::new(locked_addrs));
-                        let (mut session, session_back) = Session::new(SessionConfig {
-                            gather: Default::default(),
-                            version: tokinfo.version,
-                            session_key: tokinfo.sess_key.to_vec(),
-                            role: Role::Server,
-                        });
+                        let initial_frame_no = resume_frame_no(
+                            &self.session_table,
+                            &tokinfo.session_id,
+                            tokinfo.init_time_ms,
+                            tokinfo.version,
+                        );
+                        let token_issuer = tokinfo.issuer(self.token_key);
+                        let session_id = tokinfo.session_id;
+                        let (mut session, session_back) = Session::new(
+                            SessionConfig {
+                                gather: Default::default(),
+                                version: tokinfo.version,
+                                aead: tokinfo.aead,
+                                role: Role::Server,
+                                initial_frame_no,
+                                padding: self.padding.clone(),
+                                cover: self.cover.clone(),
+                                progress,
+                                rekey_frames: REKEY_FRAMES,
+                                ticket: None,
+                                token_issuer: Some(token_issuer),
+                            },
+                            tokinfo.into_keys(),
+                        );
                         let session_back = Arc::new(session_back);
                         let output_poller = {
                             let locked_addrs = locked_addrs.clone();
@@ -309,7 +733,9 @@ impl ListenerActor {
                                     match session_back.next_outgoing().await {
                                         Ok(data) => {
                                             // let start = Instant::now();
-                                            let remote_addr = locked_addrs.write().get_addr();
+                                            // the client may have migrated to another listener sharing our sessions
+                                            let (remote_addr, write_socket) =
+                                                locked_addrs.read().get_addr();
                                             if data.len() > 1400 {
                                                 tracing::warn!(
                                                     "dropping oversize session pkt of length {}",
@@ -324,24 +750,59 @@ impl ListenerActor {
                                 }
                             })
                         };
-                        let send_dead_clo = send_dead.clone();
-                        let resume_token_clo = resume_token.clone();
+                        let send_dead_clo = self.send_dead.clone();
                         session.on_drop(move || {
                             drop(output_poller);
-                            drop(send_dead_clo.try_send(resume_token_clo))
+                            let _ = send_dead_clo.try_send(session_id);
                         });
                         // spawn a task that writes to the socket.
-                        self.session_table.new_sess(
-                            resume_token.clone(),
-                            session_back,
-                            locked_addrs,
-                        );
-                        self.session_table.rebind(addr, shard_id, resume_token);
+                        self.session_table
+                            .new_sess(session_id, session_back, locked_addrs);
+                        self.session_table
+                            .rebind(addr, shard_id, session_id, self.socket.clone());
                         tracing::debug!("accept {}", addr);
                         let _ = accepted.try_send(session);
                     } else {
                         tracing::trace!("ClientResume from {} rebound", addr);
                     }
+                    // the client can't read a rebuilt session until it learns the salt, and any one answer may be lost
+                    if let Some(frame) = self
+                        .session_table
+                        .lookup(addr)
+                        .and_then(|session_back| session_back.salt_frame())
+                    {
+                        let reply = LegacyAead::new(&s2c_key).pad_encrypt_v1(
+                            &[frame],
+                            self.handshake_padding.sample().min(request_len),
+                        );
+                        if let Err(err) = self.socket.send_to(reply, addr).await {
+                            tracing::error!("weird socket error {:?}", err);
+                        }
+                    }
+                }
+            }
+            Some(PathProbe { nonce, mac }) => {
+                // answering anybody would make us a reflector
+                let session_back = match self.session_table.lookup(addr) {
+                    Some(session_back) => session_back,
+                    None => {
+                        tracing::debug!("ignoring path probe from unbound {}", addr);
+                        return;
+                    }
+                };
+                if blake3::Hash::from(mac) != session_back.probe_mac(false, nonce) {
+                    tracing::debug!("ignoring unauthenticated path probe from {}", addr);
+                    return;
+                }
+                let reply = LegacyAead::new(&s2c_key).pad_encrypt_v1(
+                    &[PathProbeReply {
+                        nonce,
+                        mac: *session_back.probe_mac(true, nonce).as_bytes(),
+                    }],
+                    self.handshake_padding.sample().min(request_len),
+                );
+                if let Err(err) = self.socket.send_to(reply, addr).await {
+                    tracing::error!("weird socket error {:?}", err);
                 }
             }
             _ => {}
@@ -349,8 +810,59 @@ impl ListenerActor {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Length of the plaintext of a ServerChallenge, which is much smaller than any ClientHello.
+const CHALLENGE_LEN: usize = 64;
+/// How often the challenge for a given address changes.
+const CHALLENGE_WINDOW_SECS: u64 = 30;
+
+/// Computes the stateless challenge for an address in a given time window.
+fn challenge_for(key: &[u8; 32], addr: SocketAddr, window: u64) -> [u8; 16] {
+    // only the IP address, since clients may retry from a different port
+    let mac = blake3::keyed_hash(key, format!("{}-{}", addr.ip(), window).as_bytes());
+    let mut challenge = [0u8; 16];
+    challenge.copy_from_slice(&mac.as_bytes()[..16]);
+    challenge
+}
+
+/// Where a session built from a resume token starts its frame numbers and nonce counters. Sessions with counter nonces send under a fresh salt, so their nonces never collide with those of earlier sessions from the same handshake, wherever this starts them. Starting past a session we deleted merely keeps the frame numbers the client sees moving forward. Older sessions pick random nonces, but their clients drop frame numbers below those they've seen, so they also skip past anything a previous incarnation of the listener could have sent, at no more than 1000 frames per millisecond since the handshake.
+fn resume_frame_no(
+    table: &SessionTable,
+    session_id: &SessionId,
+    init_time_ms: u64,
+    version: u64,
+) -> u64 {
+    let watermark = table.retired_watermark(session_id);
+    if version >= COUNTER_NONCE_VERSION {
+        watermark
+    } else {
+        let elapsed_ms = unix_time_ms().saturating_sub(init_time_ms);
+        (elapsed_ms * 1000).max(watermark)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct TokenInfo {
+    /// The session's keys when the token was issued.
+    keys: SessionKeys,
+    session_id: SessionId,
+    /// When the handshake happened. Reissued tokens keep the time of the first one.
+    init_time_ms: u64,
+    version: u64,
+    aead: AeadSuite,
+}
+
+/// Resume token contents from before tokens were reissued at every key ratchet, which carry the session key itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BaseKeyTokenInfo {
+    sess_key: Buff,
+    init_time_ms: u64,
+    version: u64,
+    aead: AeadSuite,
+}
+
+/// Resume token contents from before AEAD suites were negotiated. Such sessions always use ChaCha20/Poly1305.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LegacyTokenInfo {
     sess_key: Buff,
     init_time_ms: u64,
     version: u64,
@@ -361,7 +873,55 @@ impl TokenInfo {
         // first we decrypt
         let crypter = LegacyAead::new(key);
         let plain = crypter.decrypt(encrypted)?;
-        safe_deserialize(&plain).ok()
+        // bincode can't default missing trailing fields, so tokens issued before a field was added need their own struct
+        if let Ok(tokinfo) = safe_deserialize(&plain) {
+            return Some(tokinfo);
+        }
+        let (sess_key, init_time_ms, version, aead) =
+            if let Ok(old) = safe_deserialize::<BaseKeyTokenInfo>(&plain) {
+                (old.sess_key, old.init_time_ms, old.version, old.aead)
+            } else {
+                let legacy: LegacyTokenInfo = safe_deserialize(&plain).ok()?;
+                (
+                    legacy.sess_key,
+                    legacy.init_time_ms,
+                    legacy.version,
+                    AeadSuite::ChaCha20Poly1305,
+                )
+            };
+        // all tokens of such sessions are the same, so the token itself identifies them
+        Some(Self {
+            keys: SessionKeys::from_session_key(&sess_key),
+            session_id: *blake3::hash(encrypted).as_bytes(),
+            init_time_ms,
+            version,
+            aead,
+        })
+    }
+
+    /// What a session resumed from this token reissues tokens with. They carry the session's keys of the time, and everything else from this one.
+    fn issuer(&self, token_key: [u8; 32]) -> TokenIssuer {
+        let (session_id, init_time_ms, version, aead) =
+            (self.session_id, self.init_time_ms, self.version, self.aead);
+        Arc::new(move |keys: &SessionKeys| {
+            TokenInfo {
+                keys: keys.clone(),
+                session_id,
+                init_time_ms,
+                version,
+                aead,
+            }
+            .encrypt(&token_key)
+        })
+    }
+
+    /// The keys a session rebuilt from this token starts from. Its nonce counters start over, so with counter nonces it sends under a salt of its own.
+    fn into_keys(self) -> SessionKeys {
+        let mut keys = self.keys;
+        if self.version >= COUNTER_NONCE_VERSION {
+            keys.down.salt = Some(rand::random());
+        }
+        keys
     }
 
     fn encrypt(&self, key: &[u8]) -> Buff {
@@ -373,3 +933,203 @@ impl TokenInfo {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_token_decrypts() {
+        let key = [7u8; 32];
+        let sess_key = Buff::copy_from_slice(&[42; 32]);
+        let crypter = LegacyAead::new(&key);
+        let legacy = crypter.encrypt(
+            &bincode::serialize(&LegacyTokenInfo {
+                sess_key: sess_key.clone(),
+                init_time_ms: 1234,
+                version: 3,
+            })
+            .unwrap(),
+            rand::random(),
+        );
+        let tokinfo = TokenInfo::decrypt(&key, &legacy).unwrap();
+        let keys = SessionKeys::from_session_key(&sess_key);
+        assert_eq!(tokinfo.keys.up.key, keys.up.key);
+        assert_eq!(tokinfo.keys.resume_key, keys.resume_key);
+        assert_eq!(tokinfo.session_id, *blake3::hash(&legacy).as_bytes());
+        assert_eq!(tokinfo.init_time_ms, 1234);
+        assert_eq!(tokinfo.version, 3);
+        assert_eq!(tokinfo.aead, AeadSuite::ChaCha20Poly1305);
+    }
+
+    #[test]
+    fn base_key_token_decrypts() {
+        let key = [7u8; 32];
+        let sess_key = Buff::copy_from_slice(&[42; 32]);
+        let crypter = LegacyAead::new(&key);
+        let old = crypter.encrypt(
+            &bincode::serialize(&BaseKeyTokenInfo {
+                sess_key: sess_key.clone(),
+                init_time_ms: 1234,
+                version: 5,
+                aead: AeadSuite::Aes256Gcm,
+            })
+            .unwrap(),
+            rand::random(),
+        );
+        let tokinfo = TokenInfo::decrypt(&key, &old).unwrap();
+        let keys = SessionKeys::from_session_key(&sess_key);
+        assert_eq!(tokinfo.keys.down.key, keys.down.key);
+        assert_eq!((tokinfo.keys.up.epoch, tokinfo.keys.down.epoch), (0, 0));
+        assert_eq!(tokinfo.version, 5);
+        assert_eq!(tokinfo.aead, AeadSuite::Aes256Gcm);
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let key = [7u8; 32];
+        let mut keys = SessionKeys::from_session_key(&[42; 32]);
+        keys.advance_to(3, 1);
+        let tokinfo = TokenInfo {
+            keys,
+            session_id: [9; 32],
+            init_time_ms: 1234,
+            version: 5,
+            aead: AeadSuite::Aes256Gcm,
+        };
+        let token = tokinfo.encrypt(&key);
+        assert!(token.len() <= crate::protocol::MAX_RESUME_TOKEN_LEN);
+        let decrypted = TokenInfo::decrypt(&key, &token).unwrap();
+        assert_eq!(decrypted.keys.up.key, tokinfo.keys.up.key);
+        assert_eq!((decrypted.keys.up.epoch, decrypted.keys.down.epoch), (3, 1));
+        assert_eq!(decrypted.session_id, [9; 32]);
+        assert_eq!(decrypted.version, 5);
+        assert_eq!(decrypted.aead, AeadSuite::Aes256Gcm);
+        assert!(TokenInfo::decrypt(&[8u8; 32], &token).is_none());
+        // reissued tokens belong to the same session
+        let mut keys = tokinfo.keys.clone();
+        keys.advance_to(4, 1);
+        let reissued = TokenInfo::decrypt(&key, &tokinfo.issuer(key)(&keys)).unwrap();
+        assert_eq!(reissued.session_id, [9; 32]);
+        assert_eq!(reissued.init_time_ms, 1234);
+        assert_eq!(reissued.keys.up.epoch, 4);
+    }
+
+    #[test]
+    fn replies_never_exceed_hellos() {
+        let server_sk = x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>());
+        let cookie = crate::crypt::Cookie::new((&server_sk).into());
+        let listener = smol::block_on(Listener::listen_udp(
+            "127.0.0.1:0".parse().unwrap(),
+            server_sk,
+            |_, _| (),
+            |_, _| (),
+        ))
+        .unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+            .unwrap();
+        let crypter = LegacyAead::new(&cookie.generate_c2s().next().unwrap());
+        let hello = || {
+            let client_sk = x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>());
+            vec![
+                ClientHello {
+                    long_pk: (&client_sk).into(),
+                    eph_pk: (&client_sk).into(),
+                    version: crate::protocol::MIN_VERSION,
+                },
+                SupportedVersions {
+                    min: crate::protocol::MIN_VERSION,
+                    max: crate::protocol::MAX_VERSION,
+                },
+                AeadOffer {
+                    suites: AeadSuite::preferred(),
+                },
+            ]
+        };
+        let mut buf = [0u8; 2048];
+        // an unpadded hello is shorter than the reply, so it goes unanswered
+        socket
+            .send_to(&crypter.pad_encrypt_v1(&hello(), 0), listener.local_addr())
+            .unwrap();
+        assert!(socket.recv_from(&mut buf).is_err());
+        // padded like a client would, it gets a reply no longer than itself
+        let request =
+            crypter.pad_encrypt_v1(&hello(), crate::protocol::max_server_hello_len(false));
+        socket.send_to(&request, listener.local_addr()).unwrap();
+        let (reply_len, _) = socket.recv_from(&mut buf).unwrap();
+        assert!(reply_len <= request.len());
+    }
+
+    #[test]
+    fn rebuilt_sessions_never_reuse_nonces() {
+        smol::block_on(async {
+            let session_key = [42u8; 32];
+            // the token comes from a server whose clock is ahead of ours, so it seems to have been issued just now
+            let tokinfo = TokenInfo {
+                keys: SessionKeys::from_session_key(&session_key),
+                session_id: [7u8; 32],
+                init_time_ms: unix_time_ms() + 3000,
+                version: crate::protocol::MAX_VERSION,
+                aead: AeadSuite::ChaCha20Poly1305,
+            };
+            let down = tokinfo.keys.down.clone();
+            let mut nonces = FxHashSet::default();
+            // the session is rebuilt several times over by listeners that know nothing about each other, such as restarts or other relays
+            for _ in 0..5 {
+                let table = SessionTable::default();
+                let initial_frame_no = resume_frame_no(
+                    &table,
+                    &tokinfo.session_id,
+                    tokinfo.init_time_ms,
+                    tokinfo.version,
+                );
+                assert_eq!(initial_frame_no, 0);
+                let (session, session_back) = Session::new(
+                    SessionConfig {
+                        gather: Default::default(),
+                        version: tokinfo.version,
+                        aead: tokinfo.aead,
+                        role: Role::Server,
+                        initial_frame_no,
+                        padding: Default::default(),
+                        cover: None,
+                        progress: Default::default(),
+                        rekey_frames: REKEY_FRAMES,
+                        ticket: None,
+                        token_issuer: None,
+                    },
+                    tokinfo.clone().into_keys(),
+                );
+                let salt = match session_back.salt_frame() {
+                    Some(ResumeSalt { salt, mac }) => {
+                        assert_eq!(
+                            blake3::Hash::from(mac),
+                            crate::crypt::salt_mac(&tokinfo.keys.probe_key, &salt)
+                        );
+                        salt
+                    }
+                    _ => panic!("rebuilt session isn't salted"),
+                };
+                // nothing ratchets in a few packets, so everything is sent under the first downstream key, salted
+                let mut salted = down.clone();
+                salted.salt = Some(salt);
+                let salted = salted.crypt(AeadSuite::ChaCha20Poly1305);
+                for i in 0..10u8 {
+                    session
+                        .send_bytes(Buff::copy_from_slice(&[i]))
+                        .await
+                        .unwrap();
+                    let pkt = session_back.next_outgoing().await.unwrap();
+                    assert!(down
+                        .crypt(AeadSuite::ChaCha20Poly1305)
+                        .decrypt_counter(&pkt)
+                        .is_err());
+                    let (counter, _) = salted.decrypt_counter(&pkt).unwrap();
+                    assert!(nonces.insert((salt, counter)), "nonce {} reused", counter);
+                }
+            }
+        })
+    }
+}
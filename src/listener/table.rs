@@ -1,54 +1,66 @@
-use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::{buffer::Buff, SVec, SessionBack};
+use crate::{backhaul::Backhaul, SVec, SessionBack};
 
+use moka::sync::Cache;
 use parking_lot::RwLock;
 use rand::Rng;
 use rustc_hash::FxHashMap;
 
 pub struct ShardedAddrs {
-    // maps shard ID to socketaddr and last update time
-    map: FxHashMap<u8, (SocketAddr, Instant)>,
+    // maps shard ID to socketaddr, the backhaul it was seen on, and last update time
+    map: FxHashMap<u8, (SocketAddr, Arc<dyn Backhaul>, Instant)>,
 }
 
 impl ShardedAddrs {
     /// Creates a new table of shard addresses.
-    pub fn new(initial_shard: u8, initial_addr: SocketAddr) -> Self {
+    pub fn new(initial_shard: u8, initial_addr: SocketAddr, socket: Arc<dyn Backhaul>) -> Self {
         let mut map = FxHashMap::default();
-        map.insert(initial_shard, (initial_addr, Instant::now()));
+        map.insert(initial_shard, (initial_addr, socket, Instant::now()));
         Self { map }
     }
 
-    /// Gets the most appropriate address to send a packet down.
-    pub fn get_addr(&self) -> SocketAddr {
+    /// Gets the most appropriate address to send a packet down, along with the backhaul to send it through.
+    pub fn get_addr(&self) -> (SocketAddr, Arc<dyn Backhaul>) {
         // svec to prevent allocating in such an extremely hot path
         let recently_used_shards = self
             .map
-            .iter()
-            .filter(|(_, (_, usage))| usage.elapsed().as_millis() < 10000)
-            .map(|f| f.1 .0)
+            .values()
+            .filter(|(_, _, usage)| usage.elapsed().as_millis() < 10000)
             .collect::<SVec<_>>();
         // if no recently used, then push the most recently used one
-        if recently_used_shards.is_empty() {
-            let (most_recent, _) = self
+        let (addr, socket, _) = if recently_used_shards.is_empty() {
+            let most_recent = self
                 .map
                 .values()
-                .max_by_key(|v| v.1)
-                .copied()
+                .max_by_key(|v| v.2)
                 .expect("no shards at all");
-            tracing::trace!("sending down most recent {}", most_recent);
+            tracing::trace!("sending down most recent {}", most_recent.0);
             most_recent
         } else {
             let random =
                 recently_used_shards[rand::thread_rng().gen_range(0, recently_used_shards.len())];
-            tracing::trace!("sending down random {}", random);
+            tracing::trace!("sending down random {}", random.0);
             random
-        }
+        };
+        (*addr, socket.clone())
     }
 
-    /// Sets an index to a particular address
-    pub fn insert_addr(&mut self, index: u8, addr: SocketAddr) -> Option<SocketAddr> {
-        self.map.insert(index, (addr, Instant::now())).map(|v| v.0)
+    /// Sets an index to a particular address, reached through the given backhaul
+    pub fn insert_addr(
+        &mut self,
+        index: u8,
+        addr: SocketAddr,
+        socket: Arc<dyn Backhaul>,
+    ) -> Option<SocketAddr> {
+        self.map
+            .insert(index, (addr, socket, Instant::now()))
+            .map(|v| v.0)
     }
 }
 
@@ -57,70 +69,101 @@ struct SessEntry {
     addrs: Arc<RwLock<ShardedAddrs>>,
 }
 
-#[derive(Default, Clone)]
+/// Identifies a session across all the resume tokens issued for it.
+pub(crate) type SessionId = [u8; 32];
+
+#[derive(Clone)]
 pub(crate) struct SessionTable {
-    token_to_sess: Arc<RwLock<BTreeMap<Buff, SessEntry>>>,
-    addr_to_token: Arc<RwLock<BTreeMap<SocketAddr, Buff>>>,
+    id_to_sess: Arc<RwLock<BTreeMap<SessionId, SessEntry>>>,
+    addr_to_id: Arc<RwLock<BTreeMap<SocketAddr, SessionId>>>,
+    /// Send watermarks of deleted sessions, so that sessions rebuilt from their resume tokens carry on numbering where they left off.
+    retired: Cache<SessionId, u64>,
+}
+
+impl Default for SessionTable {
+    fn default() -> Self {
+        Self {
+            id_to_sess: Default::default(),
+            addr_to_id: Default::default(),
+            retired: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_idle(Duration::from_secs(86400))
+                .build(),
+        }
+    }
 }
 
 impl SessionTable {
-    pub fn rebind(&self, addr: SocketAddr, shard_id: u8, token: Buff) -> bool {
-        let token_to_sess = self.token_to_sess.write();
-        let mut addr_to_token = self.addr_to_token.write();
-        if let Some(entry) = token_to_sess.get(&token) {
-            let old = entry.addrs.write().insert_addr(shard_id, addr);
+    /// Binds a shard of the session with the given ID to an address, reached through the given backhaul. Returns false if there is no such session.
+    pub fn rebind(
+        &self,
+        addr: SocketAddr,
+        shard_id: u8,
+        id: SessionId,
+        socket: Arc<dyn Backhaul>,
+    ) -> bool {
+        let id_to_sess = self.id_to_sess.write();
+        let mut addr_to_id = self.addr_to_id.write();
+        if let Some(entry) = id_to_sess.get(&id) {
+            let old = entry.addrs.write().insert_addr(shard_id, addr, socket);
             tracing::trace!("binding {}=>{}", shard_id, addr);
             if let Some(old) = old {
-                addr_to_token.remove(&old);
+                addr_to_id.remove(&old);
             }
-            addr_to_token.insert(addr, token);
+            addr_to_id.insert(addr, id);
             true
         } else {
             tracing::debug!(
-                "[{:p}] token {:?} not in {:?}",
+                "[{:p}] session {} not in table of {}",
                 self,
-                token,
-                token_to_sess.keys().collect::<Vec<_>>()
+                hex::encode(id),
+                id_to_sess.len()
             );
             false
         }
     }
-    pub fn delete(&self, token: Buff) {
-        tracing::debug!("removing token {:?}", token);
-        let mut token_to_sess = self.token_to_sess.write();
-        let mut addr_to_token = self.addr_to_token.write();
-        if let Some(entry) = token_to_sess.remove(&token) {
-            for (addr, _) in entry.addrs.read().map.values() {
-                addr_to_token.remove(addr);
+    pub fn delete(&self, id: SessionId) {
+        tracing::debug!("removing session {}", hex::encode(id));
+        let mut id_to_sess = self.id_to_sess.write();
+        let mut addr_to_id = self.addr_to_id.write();
+        if let Some(entry) = id_to_sess.remove(&id) {
+            for (addr, _, _) in entry.addrs.read().map.values() {
+                addr_to_id.remove(addr);
             }
+            self.retired.insert(id, entry.session_back.send_watermark());
         }
     }
 
+    /// Where a session rebuilt with the given ID can start numbering, past everything that deleted sessions with the ID sent.
+    pub fn retired_watermark(&self, id: &SessionId) -> u64 {
+        self.retired.get(id).unwrap_or_default()
+    }
+
     pub fn lookup(&self, addr: SocketAddr) -> Option<Arc<SessionBack>> {
-        let token_to_sess = self.token_to_sess.read();
-        let addr_to_token = self.addr_to_token.read();
-        let token = addr_to_token.get(&addr)?;
-        let entry = token_to_sess.get(token)?;
+        let id_to_sess = self.id_to_sess.read();
+        let addr_to_id = self.addr_to_id.read();
+        let id = addr_to_id.get(&addr)?;
+        let entry = id_to_sess.get(id)?;
         Some(entry.session_back.clone())
     }
 
     pub fn new_sess(
         &self,
-        token: Buff,
+        id: SessionId,
         session_back: Arc<SessionBack>,
         locked_addrs: Arc<RwLock<ShardedAddrs>>,
     ) {
-        let mut token_to_sess = self.token_to_sess.write();
+        let mut id_to_sess = self.id_to_sess.write();
         let entry = SessEntry {
             session_back,
             addrs: locked_addrs,
         };
-        token_to_sess.insert(token.clone(), entry);
+        id_to_sess.insert(id, entry);
         tracing::debug!(
-            "[{:p}] token {:?} now in {:?}",
+            "[{:p}] session {} now in table of {}",
             self,
-            token,
-            token_to_sess.keys().collect::<Vec<_>>()
+            hex::encode(id),
+            id_to_sess.len()
         );
     }
 }
@@ -0,0 +1,432 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use rustc_hash::FxHashMap;
+use smol::{
+    channel::{Receiver, Sender},
+    prelude::*,
+};
+
+use crate::{backhaul::Backhaul, buffer::Buff, runtime};
+
+/// Packets that would wait longer than this behind a bandwidth cap are dropped, like a router with a full buffer.
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(500);
+
+/// Packets arriving at an endpoint that is not receiving them are dropped once this many are waiting.
+const RECV_BUFFER: usize = 1000;
+
+/// How packets are lost on a simulated link.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum LossModel {
+    /// No packets are lost.
+    #[default]
+    None,
+    /// Each packet is lost independently with the given probability.
+    Bernoulli(f64),
+    /// Bursty loss. The link switches between a good and a bad state after each packet with the given probabilities, and loses packets with a different probability in each state.
+    GilbertElliott {
+        good_to_bad: f64,
+        bad_to_good: f64,
+        good_loss: f64,
+        bad_loss: f64,
+    },
+}
+
+/// Impairments that a [MemoryBackhaul] applies to the packets it sends.
+#[derive(Debug, Clone, Default)]
+pub struct Impairments {
+    /// How packets are lost.
+    pub loss: LossModel,
+    /// One-way delay added to every packet.
+    pub delay: Duration,
+    /// Extra delay, uniformly distributed between zero and this, added to every packet. Jitter larger than the gap between packets reorders them.
+    pub jitter: Duration,
+    /// Probability that a packet is held back for an extra `delay`, arriving after packets sent later.
+    pub reorder: f64,
+    /// Probability that a packet is delivered twice.
+    pub duplicate: f64,
+    /// Bandwidth cap in bytes per second. Packets queue up behind it, and are dropped when the queue gets too long.
+    pub bandwidth: Option<u64>,
+}
+
+impl Impairments {
+    /// Creates a new impairment configuration for a perfect link.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// An in-process network that connects [MemoryBackhaul]s through channels, so that clients and [Listener](crate::Listener)s can talk without real sockets. Impairments are driven by a seeded RNG, so the same seed gives the same pattern of loss, delay, and duplication on every link.
+///
+/// Each backhaul's RNG is seeded from the network's seed and the address it's bound to, and every packet takes the same number of draws from it, so a link's pattern depends neither on the order endpoints are bound in nor on timing. Ephemeral addresses are handed out in the order [MemoryNetwork::bind_ephemeral] is called, though, so endpoints that need a fixed pattern should be bound to fixed addresses. Only packets dropped by a bandwidth cap's full queue depend on timing.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<NetworkInner>>,
+}
+
+struct NetworkInner {
+    endpoints: FxHashMap<SocketAddr, (u64, Sender<(Buff, SocketAddr)>)>,
+    seed: u64,
+    bound_count: u64,
+    /// How many times each address has been bound, so that rebinding an address gives a fresh but still reproducible RNG.
+    generations: FxHashMap<SocketAddr, u64>,
+    next_ephemeral: u32,
+}
+
+impl MemoryNetwork {
+    /// Creates a new network with the given RNG seed.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(NetworkInner {
+                endpoints: FxHashMap::default(),
+                seed,
+                bound_count: 0,
+                generations: FxHashMap::default(),
+                next_ephemeral: u32::from(Ipv4Addr::new(10, 128, 0, 1)),
+            })),
+        }
+    }
+
+    /// Binds a backhaul to the given address, replacing any backhaul already bound there. Packets it sends are impaired with the given impairments.
+    pub fn bind(&self, addr: SocketAddr, impairments: Impairments) -> MemoryBackhaul {
+        let mut inner = self.inner.lock();
+        let (send_incoming, incoming) = smol::channel::bounded(RECV_BUFFER);
+        let bind_id = inner.bound_count;
+        inner.bound_count += 1;
+        inner.endpoints.insert(addr, (bind_id, send_incoming));
+        let generation = {
+            let generation = inner.generations.entry(addr).or_default();
+            *generation += 1;
+            *generation
+        };
+        // every backhaul gets its own RNG, so that its impairments don't depend on how packets on other links interleave
+        let rng = ChaCha8Rng::from_seed(link_seed(inner.seed, addr, generation));
+        let (send_outgoing, outgoing) = smol::channel::unbounded();
+        let _deliver_task = runtime::spawn(deliver_loop(self.clone(), addr, outgoing));
+        MemoryBackhaul {
+            addr,
+            bind_id,
+            network: self.clone(),
+            incoming,
+            send_outgoing,
+            link: Mutex::new(LinkState {
+                rng,
+                bad: false,
+                next_free: Instant::now(),
+                seqno: 0,
+            }),
+            impairments,
+            _deliver_task,
+        }
+    }
+
+    /// Binds a backhaul to a fresh address in 10.128.0.0/9, like binding a socket to an ephemeral port. This is what clients usually want, for example in a [BackhaulGen](crate::BackhaulGen).
+    pub fn bind_ephemeral(&self, impairments: Impairments) -> MemoryBackhaul {
+        let addr = {
+            let mut inner = self.inner.lock();
+            let ip = Ipv4Addr::from(inner.next_ephemeral);
+            inner.next_ephemeral += 1;
+            SocketAddr::from((ip, 1))
+        };
+        self.bind(addr, impairments)
+    }
+
+    fn deliver(&self, pkt: Buff, from: SocketAddr, dest: SocketAddr) {
+        let endpoint = self.inner.lock().endpoints.get(&dest).cloned();
+        if let Some((_, endpoint)) = endpoint {
+            if endpoint.try_send((pkt, from)).is_err() {
+                tracing::trace!("memory network dropping packet to full endpoint {}", dest);
+            }
+        } else {
+            tracing::trace!("memory network dropping packet to unbound {}", dest);
+        }
+    }
+}
+
+/// Derives the RNG seed of the given generation of the backhaul bound to `addr`.
+fn link_seed(seed: u64, addr: SocketAddr, generation: u64) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key("sosistab-memory-link");
+    hasher.update(&seed.to_le_bytes());
+    hasher.update(addr.to_string().as_bytes());
+    hasher.update(&generation.to_le_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+/// A packet waiting on a link, ordered by delivery time and then by the order it was sent in.
+struct InFlight {
+    due: Instant,
+    seqno: u64,
+    pkt: Buff,
+    dest: SocketAddr,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seqno) == (other.due, other.seqno)
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.due, self.seqno).cmp(&(other.due, other.seqno))
+    }
+}
+
+/// Holds packets until their delivery times, then hands them to their destinations.
+async fn deliver_loop(network: MemoryNetwork, addr: SocketAddr, outgoing: Receiver<InFlight>) {
+    let mut in_flight: BinaryHeap<Reverse<InFlight>> = BinaryHeap::new();
+    loop {
+        let next_due = in_flight.peek().map(|pkt| pkt.0.due);
+        let event = async { outgoing.recv().await.map(Some) }.or(async {
+            match next_due {
+                Some(due) => smol::Timer::at(due).await,
+                None => smol::future::pending().await,
+            };
+            Ok(None)
+        });
+        match event.await {
+            Ok(Some(pkt)) => in_flight.push(Reverse(pkt)),
+            Ok(None) => {
+                let now = Instant::now();
+                while in_flight.peek().map(|pkt| pkt.0.due <= now) == Some(true) {
+                    let Reverse(pkt) = in_flight.pop().unwrap();
+                    network.deliver(pkt.pkt, addr, pkt.dest);
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// A [Backhaul] bound to an address on a [MemoryNetwork].
+pub struct MemoryBackhaul {
+    addr: SocketAddr,
+    bind_id: u64,
+    network: MemoryNetwork,
+    incoming: Receiver<(Buff, SocketAddr)>,
+    send_outgoing: Sender<InFlight>,
+    link: Mutex<LinkState>,
+    impairments: Impairments,
+    _deliver_task: smol::Task<()>,
+}
+
+struct LinkState {
+    rng: ChaCha8Rng,
+    bad: bool,
+    next_free: Instant,
+    seqno: u64,
+}
+
+impl MemoryBackhaul {
+    /// Returns the address this backhaul is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Decides when each copy of a packet of the given length should arrive. Returns nothing if the packet is lost.
+    fn schedule(&self, len: usize) -> Vec<Instant> {
+        let imp = &self.impairments;
+        let mut link = self.link.lock();
+        let link = &mut *link;
+        // every packet takes the same draws, whatever happens to it, so that each packet's fate only depends on how many packets came before it
+        let switch_draw: f64 = link.rng.gen();
+        let loss_draw: f64 = link.rng.gen();
+        let duplicate_draw: f64 = link.rng.gen();
+        let copy_draws: [(f64, f64); 2] = link.rng.gen();
+        let lost = match imp.loss {
+            LossModel::None => false,
+            LossModel::Bernoulli(p) => loss_draw < p,
+            LossModel::GilbertElliott {
+                good_to_bad,
+                bad_to_good,
+                good_loss,
+                bad_loss,
+            } => {
+                let switch = if link.bad { bad_to_good } else { good_to_bad };
+                if switch_draw < switch {
+                    link.bad = !link.bad;
+                }
+                loss_draw < if link.bad { bad_loss } else { good_loss }
+            }
+        };
+        let now = Instant::now();
+        // the packet occupies the link whether or not it's lost later on
+        let departure = if let Some(bandwidth) = imp.bandwidth {
+            let start = link.next_free.max(now);
+            if start - now > MAX_QUEUE_DELAY {
+                return vec![];
+            }
+            let departure = start + Duration::from_secs_f64(len as f64 / bandwidth.max(1) as f64);
+            link.next_free = departure;
+            departure
+        } else {
+            now
+        };
+        if lost {
+            return vec![];
+        }
+        let copies = if duplicate_draw < imp.duplicate { 2 } else { 1 };
+        copy_draws[..copies]
+            .iter()
+            .map(|&(jitter_draw, reorder_draw)| {
+                let mut arrival = departure + imp.delay + imp.jitter.mul_f64(jitter_draw);
+                if reorder_draw < imp.reorder {
+                    arrival += imp.delay;
+                }
+                arrival
+            })
+            .collect()
+    }
+}
+
+impl Drop for MemoryBackhaul {
+    fn drop(&mut self) {
+        let mut inner = self.network.inner.lock();
+        // only unbind if nobody has rebound the address since
+        if inner.endpoints.get(&self.addr).map(|(id, _)| *id) == Some(self.bind_id) {
+            inner.endpoints.remove(&self.addr);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Backhaul for MemoryBackhaul {
+    async fn send_to(&self, to_send: Buff, dest: SocketAddr) -> io::Result<()> {
+        for arrival in self.schedule(to_send.len()) {
+            let seqno = {
+                let mut link = self.link.lock();
+                link.seqno += 1;
+                link.seqno
+            };
+            // the delivery task only stops when we are dropped
+            let _ = self.send_outgoing.try_send(InFlight {
+                due: arrival,
+                seqno,
+                pkt: to_send.clone(),
+                dest,
+            });
+        }
+        Ok(())
+    }
+
+    async fn recv_from(&self) -> io::Result<(Buff, SocketAddr)> {
+        self.incoming
+            .recv()
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "memory backhaul unbound"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientConfig, Listener, Multiplex, Protocol};
+    use smol_timeout::TimeoutExt;
+
+    fn lossy() -> Impairments {
+        let mut impairments = Impairments::new();
+        impairments.loss = LossModel::Bernoulli(0.1);
+        impairments.delay = Duration::from_millis(5);
+        impairments.jitter = Duration::from_millis(5);
+        impairments
+    }
+
+    /// Which of `count` packets sent on the backhaul would be lost.
+    fn loss_pattern(backhaul: &MemoryBackhaul, count: usize) -> Vec<bool> {
+        (0..count)
+            .map(|_| backhaul.schedule(1000).is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn patterns_ignore_bind_order() {
+        let addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        let first = MemoryNetwork::new(7);
+        let first_backhaul = first.bind(addr, lossy());
+        let _ = first.bind(other, lossy());
+        let second = MemoryNetwork::new(7);
+        let _ = second.bind(other, lossy());
+        let _ = second.bind_ephemeral(lossy());
+        let second_backhaul = second.bind(addr, lossy());
+        let pattern = loss_pattern(&first_backhaul, 1000);
+        assert!(pattern.contains(&true));
+        assert_eq!(pattern, loss_pattern(&second_backhaul, 1000));
+        // another seed, or rebinding the address, gives another pattern
+        assert_ne!(
+            pattern,
+            loss_pattern(&MemoryNetwork::new(8).bind(addr, lossy()), 1000)
+        );
+        assert_ne!(pattern, loss_pattern(&first.bind(addr, lossy()), 1000));
+    }
+
+    #[test]
+    fn multiplex_over_lossy_network() {
+        smolscale::block_on(async {
+            let network = MemoryNetwork::new(0);
+            let server_sk = x25519_dalek::StaticSecret::from([42; 32]);
+            let server_addr: SocketAddr = "10.0.0.1:19999".parse().unwrap();
+            let listener = Listener::listen_custom(
+                server_addr,
+                network.bind(server_addr, lossy()),
+                server_sk.clone(),
+                |_, _| (),
+                |_, _| (),
+            )
+            .await
+            .unwrap();
+            let backhaul_gen: crate::BackhaulGen =
+                Arc::new(move || Arc::new(network.bind_ephemeral(lossy())));
+            let client = ClientConfig::new(
+                Protocol::Custom(backhaul_gen),
+                server_addr,
+                (&server_sk).into(),
+                Default::default(),
+            );
+            let (client, server) = smol::future::zip(client.connect(), listener.accept_session())
+                .timeout(Duration::from_secs(30))
+                .await
+                .unwrap();
+            let client = Multiplex::new(client.unwrap());
+            let server = Multiplex::new(server.unwrap());
+            let (upload, download) =
+                smol::future::zip(client.open_conn(None), server.accept_conn())
+                    .timeout(Duration::from_secs(30))
+                    .await
+                    .unwrap();
+            let (mut upload, mut download) = (upload.unwrap(), download.unwrap());
+            let payload: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+            let mut received = vec![0u8; payload.len()];
+            let (sent, read) = smol::future::zip(
+                async {
+                    upload.write_all(&payload).await?;
+                    upload.flush().await
+                },
+                download.read_exact(&mut received),
+            )
+            .timeout(Duration::from_secs(60))
+            .await
+            .unwrap();
+            sent.unwrap();
+            read.unwrap();
+            assert_eq!(received, payload);
+        })
+    }
+}
@@ -8,6 +8,7 @@ pub struct Pacer {
     timer: smol::Timer,
     interval: Duration,
     counter: u32,
+    quantum: u32,
 }
 
 impl Pacer {
@@ -18,16 +19,26 @@ impl Pacer {
             timer: smol::Timer::at(Instant::now()),
             interval,
             counter: 0,
+            quantum: QUANTUM,
+        }
+    }
+
+    /// Creates a new pacer that actually waits on every call, rather than letting through small bursts.
+    pub fn new_unbatched(interval: Duration) -> Self {
+        Self {
+            quantum: 1,
+            ..Self::new(interval)
         }
     }
 
     /// Waits until the next time.
     pub async fn wait_next(&mut self) {
         self.counter += 1;
-        if self.counter >= QUANTUM {
+        if self.counter >= self.quantum {
             self.counter = 0;
             (&mut self.timer).await;
-            self.next_pace_time = Instant::now().max(self.next_pace_time + self.interval * QUANTUM);
+            self.next_pace_time =
+                Instant::now().max(self.next_pace_time + self.interval * self.quantum);
             self.timer.set_at(self.next_pace_time);
         } else {
             smol::future::yield_now().await;
@@ -1,9 +1,85 @@
 use std::ops::DerefMut;
 
 use bincode::Options;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
-use crate::buffer::{Buff, BuffMut};
+use crate::{
+    buffer::{Buff, BuffMut},
+    crypt::{AeadSuite, KEM_CIPHERTEXT_LEN},
+};
+
+/// Oldest protocol version we still speak.
+pub const MIN_VERSION: u64 = 3;
+/// Newest protocol version we speak.
+pub const MAX_VERSION: u64 = 5;
+
+/// Picks the highest version that both we and a peer supporting `min..=max` speak.
+pub fn negotiate_version(min: u64, max: u64) -> Option<u64> {
+    let version = max.min(MAX_VERSION);
+    if version >= min.max(MIN_VERSION) {
+        Some(version)
+    } else {
+        None
+    }
+}
+
+/// How long handshake packets are padded to, so that the first packets of a flow don't have a fixed length. Each packet is padded to a length sampled uniformly from `min_len..=max_len`, unless its contents are already longer.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakePadding {
+    pub min_len: usize,
+    pub max_len: usize,
+}
+
+impl Default for HandshakePadding {
+    fn default() -> Self {
+        Self {
+            min_len: 600,
+            max_len: 1200,
+        }
+    }
+}
+
+impl HandshakePadding {
+    /// Samples a padded length.
+    pub fn sample(&self) -> usize {
+        rand::thread_rng().gen_range(self.min_len, self.max_len.max(self.min_len) + 1)
+    }
+
+    /// Samples a padded length for contents of the given length. Contents longer than `min_len` raise the bottom of the range, so that their padded lengths are still spread out, rather than all equal to the contents' length.
+    pub fn sample_above(&self, content_len: usize) -> usize {
+        HandshakePadding {
+            min_len: self.min_len.max(content_len),
+            max_len: self.max_len.max(content_len),
+        }
+        .sample()
+    }
+}
+
+/// Longest resume token a listener issues.
+pub(crate) const MAX_RESUME_TOKEN_LEN: usize = 256;
+
+/// Longest contents of a listener's answer to a ClientHello. Listeners don't answer hellos shorter than their answer, so clients pad their hellos to at least this.
+pub fn max_server_hello_len(post_quantum: bool) -> usize {
+    let pk = x25519_dalek::PublicKey::from([0; 32]);
+    let mut frames = vec![
+        HandshakeFrame::ServerHello {
+            long_pk: pk,
+            eph_pk: pk,
+            resume_token: Buff::copy_from_slice(&[0; MAX_RESUME_TOKEN_LEN]),
+        },
+        HandshakeFrame::ChosenVersion { version: 0 },
+        HandshakeFrame::ChosenAead {
+            suite: AeadSuite::default(),
+        },
+    ];
+    if post_quantum {
+        frames.push(HandshakeFrame::KemReply {
+            ciphertext: Buff::copy_from_slice(&[0; KEM_CIPHERTEXT_LEN]),
+        });
+    }
+    frames.iter().map(|frame| frame.to_bytes().len()).sum()
+}
 
 /// Frame sent as a session-negotiation message. This is always encrypted with the cookie.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -12,6 +88,7 @@ pub enum HandshakeFrame {
     ClientHello {
         long_pk: x25519_dalek::PublicKey,
         eph_pk: x25519_dalek::PublicKey,
+        /// Version used by servers that don't understand SupportedVersions. Always the oldest version the client speaks.
         version: u64,
     },
     /// Frame sent from server to client to give a cookie for finally opening a connection.
@@ -29,6 +106,68 @@ pub enum HandshakeFrame {
         /// Which shard is this
         shard_id: u8,
     },
+
+    /// Frame sent from server to client in response to a ClientHello, when the server requires clients to prove that they can receive packets at their source address before doing any expensive work.
+    ServerChallenge {
+        /// Opaque value bound to the client's address, which the client must echo back.
+        challenge: Buff,
+    },
+
+    /// Frame sent from client to server alongside a ClientHello, echoing a previously received challenge.
+    ChallengeEcho { challenge: Buff },
+
+    /// Frame sent from client to server alongside a ClientHello, requesting a hybrid post-quantum handshake.
+    KemOffer {
+        /// ML-KEM encapsulation key
+        encaps_key: Buff,
+    },
+
+    /// Frame sent from server to client alongside a ServerHello, answering a KemOffer. The session key then mixes in the encapsulated secret.
+    KemReply {
+        /// ML-KEM ciphertext
+        ciphertext: Buff,
+    },
+
+    /// Frame sent from client to server alongside a ClientHello, advertising the range of versions the client speaks.
+    SupportedVersions { min: u64, max: u64 },
+
+    /// Frame sent from server to client alongside a ServerHello, answering SupportedVersions with the version the session will use.
+    ChosenVersion { version: u64 },
+
+    /// Frame sent from client to server alongside a ClientHello, listing the AEAD suites the client supports.
+    AeadOffer { suites: Vec<AeadSuite> },
+
+    /// Frame sent from server to client alongside a ServerHello, answering AeadOffer with the suite the session will use.
+    ChosenAead { suite: AeadSuite },
+
+    /// Frame sent from client to server on one of a session's paths, to measure the path's round-trip time and loss. Servers only answer probes from addresses bound to a session, carrying a MAC under that session's probe key.
+    PathProbe { nonce: u64, mac: [u8; 32] },
+
+    /// Frame sent from server to client answering a PathProbe with the same nonce, and a MAC under the session's probe key.
+    PathProbeReply { nonce: u64, mac: [u8; 32] },
+
+    /// Frame sent from client to server alongside a ClientResume, saying where the session stands, so that a server that lost the session can resume it.
+    ResumeProgress {
+        /// Key epoch the client sends at.
+        up_epoch: u64,
+        /// Key epoch the client receives at.
+        down_epoch: u64,
+        /// Next frame number the client sends. Older frames are rejected as possible replays.
+        up_frame_no: u64,
+        /// Next nonce counter the client sends.
+        up_counter: u64,
+        /// When the client reported this, in milliseconds since the Unix epoch.
+        time_ms: u64,
+        /// MAC over all of the above under the session's resume key, since anybody can encrypt with the cookie.
+        mac: [u8; 32],
+    },
+
+    /// Frame sent from server to client answering a ClientResume, when the server rebuilt the session from the resume token. The server sends under its keys salted with this, so that it never reuses a nonce of an earlier session from the same handshake.
+    ResumeSalt {
+        salt: [u8; 32],
+        /// MAC over the salt under the session's probe key.
+        mac: [u8; 32],
+    },
 }
 
 impl HandshakeFrame {
@@ -36,12 +175,20 @@ impl HandshakeFrame {
         bincode::serialize(self).unwrap()
     }
 
-    pub fn from_bytes(bts: &[u8]) -> anyhow::Result<Self> {
-        Ok(bincode::DefaultOptions::new()
+    /// Parses frames one after another, stopping at the first thing that isn't a frame, such as 0xff padding.
+    pub fn from_bytes_many(mut bts: &[u8]) -> Vec<Self> {
+        let options = bincode::DefaultOptions::new()
             .with_fixint_encoding()
             .allow_trailing_bytes()
-            .with_limit(bts.len() as _)
-            .deserialize(bts)?)
+            .with_limit(bts.len() as _);
+        let mut frames = Vec::new();
+        while !bts.is_empty() {
+            match options.deserialize_from(&mut bts) {
+                Ok(frame) => frames.push(frame),
+                Err(_) => break,
+            }
+        }
+        frames
     }
 }
 
@@ -66,25 +213,37 @@ pub enum DataFrameV2 {
         pad_size: usize,
         body: Buff,
     },
+    /// Dummy frame sent as cover traffic, which the receiver drops.
+    Cover,
+    /// Frame sent under the previous key after the sender ratchets its key, telling the receiver to switch to the given key epoch.
+    Rekey { epoch: u64 },
+    /// Frame sent from server to client after either side ratchets its key, with a resume token sealed over the new keys. The client resumes with it from then on, so that tokens it sends don't reveal earlier keys.
+    NewToken {
+        resume_token: Buff,
+        /// Key that progress reported alongside this token must be authenticated with.
+        resume_key: [u8; 32],
+        /// Key epochs the token is at. Tokens only ever move forward, so clients ignore reordered frames carrying older ones.
+        up_epoch: u64,
+        down_epoch: u64,
+    },
 }
 
 impl DataFrameV2 {
-    /// Pads the frame to prepare for encryption.
-    pub fn pad(&self, hidden_data: u8) -> Buff {
+    /// Pads the frame to prepare for encryption. `target_len` is given the unpadded length, and returns the length to pad to.
+    pub fn pad(&self, hidden_data: u8, target_len: impl FnOnce(usize) -> usize) -> Buff {
         let options = bincode::DefaultOptions::new()
             .with_little_endian()
             .with_varint_encoding()
             .allow_trailing_bytes();
-        // TODO: padding
         let mut toret = BuffMut::new();
         options.serialize_into(toret.deref_mut(), self).unwrap();
         toret.extend_from_slice(&[hidden_data]);
-        let padd_amount = 32 - toret.len() % 32;
+        let padd_amount = target_len(toret.len()).saturating_sub(toret.len());
         toret.extend_from_slice(&vec![0xff; padd_amount]);
         toret.into()
     }
 
-    /// Depads a decrypted frame.
+    /// Depads a decrypted frame. Any amount of padding is accepted.
     pub fn depad(bts: &[u8]) -> Option<(Self, u8)> {
         let options = bincode::DefaultOptions::new()
             .with_little_endian()
@@ -118,3 +277,30 @@ pub struct DataFrameV1 {
     /// Body.
     pub body: Buff,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_version_picks_highest_common() {
+        assert_eq!(
+            negotiate_version(MIN_VERSION, MAX_VERSION),
+            Some(MAX_VERSION)
+        );
+        assert_eq!(negotiate_version(1, MAX_VERSION + 10), Some(MAX_VERSION));
+        assert_eq!(
+            negotiate_version(MIN_VERSION, MIN_VERSION),
+            Some(MIN_VERSION)
+        );
+        assert_eq!(negotiate_version(4, 4), Some(4));
+    }
+
+    #[test]
+    fn negotiate_version_rejects_disjoint_ranges() {
+        assert_eq!(negotiate_version(1, MIN_VERSION - 1), None);
+        assert_eq!(negotiate_version(MAX_VERSION + 1, MAX_VERSION + 5), None);
+        // an empty range has nothing in common with anything
+        assert_eq!(negotiate_version(MAX_VERSION, MIN_VERSION), None);
+    }
+}
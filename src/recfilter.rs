@@ -1,52 +1,114 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    sync::{atomic::Ordering, Arc},
     time::{Duration, Instant},
 };
 
-use dashmap::DashMap;
-use once_cell::sync::Lazy;
-use parking_lot::Mutex;
+use bloomfilter::Bloom;
 
-// recently seen tracker
+use crate::ListenerStats;
+
+/// False-positive rate of each generation of the filter, when full.
+const FP_RATE: f64 = 1e-6;
+
+/// A bounded, probabilistic filter for recently seen handshakes. It consists of two generations of bloom filters that rotate every `window`, so that every entry is remembered for at least `window` and at most twice that. Memory usage is fixed no matter how many handshakes are checked: when more than `capacity` handshakes arrive within a generation, the false-positive rate climbs instead.
 pub(crate) struct RecentFilter {
-    seen: HashMap<blake3::Hash, Instant>,
-    expiry: VecDeque<(Instant, blake3::Hash)>,
+    curr_bloom: Bloom<[u8; 32]>,
+    last_bloom: Bloom<[u8; 32]>,
+    curr_count: usize,
+    curr_time: Instant,
+    capacity: usize,
+    window: Duration,
+    stats: Arc<ListenerStats>,
 }
 
 impl RecentFilter {
-    fn new() -> Self {
+    /// Creates a new filter that remembers entries for at least the given window, reporting its fill level to the given stats.
+    pub fn new(capacity: usize, window: Duration, stats: Arc<ListenerStats>) -> Self {
+        let capacity = capacity.max(1);
+        stats
+            .replay_filter_capacity
+            .store(capacity, Ordering::Relaxed);
+        stats.replay_filter_items.store(0, Ordering::Relaxed);
         RecentFilter {
-            seen: Default::default(),
-            expiry: Default::default(),
+            curr_bloom: Bloom::new_for_fp_rate(capacity, FP_RATE),
+            last_bloom: Bloom::new_for_fp_rate(capacity, FP_RATE),
+            curr_count: 0,
+            curr_time: Instant::now(),
+            capacity,
+            window,
+            stats,
         }
     }
 
     pub fn check(&mut self, val: &[u8]) -> bool {
-        // clean up first
-        while let Some(to_delete) = self.expiry.front().and_then(|(expiry, hash)| {
-            if expiry.elapsed() > Duration::from_secs(600) {
-                Some(*hash)
+        // rotate first
+        let elapsed = self.curr_time.elapsed();
+        if elapsed > self.window {
+            let fresh = Bloom::new_for_fp_rate(self.capacity, FP_RATE);
+            let last = std::mem::replace(&mut self.curr_bloom, fresh);
+            // after a quiet spell, even the current generation may be too old to keep
+            self.last_bloom = if elapsed > self.window * 2 {
+                Bloom::new_for_fp_rate(self.capacity, FP_RATE)
             } else {
-                None
-            }
-        }) {
-            let _ = self.expiry.pop_front();
-            self.seen.remove(&to_delete);
+                last
+            };
+            self.curr_count = 0;
+            self.curr_time = Instant::now();
         }
         // then add
-        let key = blake3::hash(val);
-        if let Some(time) = self.seen.get(&key) {
-            tracing::error!("replay from {:?} ago", time.elapsed());
+        let key = *blake3::hash(val).as_bytes();
+        if self.curr_bloom.check(&key) || self.last_bloom.check(&key) {
+            tracing::error!("replay within the last {:?}", self.window * 2);
             false
         } else {
-            let now = Instant::now();
-            self.seen.insert(key, now);
-            self.expiry.push_back((now, key));
+            self.curr_bloom.set(&key);
+            self.curr_count += 1;
+            self.stats
+                .replay_filter_items
+                .store(self.curr_count, Ordering::Relaxed);
             true
         }
     }
 }
 
-/// A global recent filter.
-pub(crate) static RECENT_FILTER: Lazy<Mutex<RecentFilter>> =
-    Lazy::new(|| Mutex::new(RecentFilter::new()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn rejects_repeats() {
+        let stats = Arc::new(ListenerStats::default());
+        let mut filter = RecentFilter::new(1000, WINDOW, stats.clone());
+        assert_eq!(stats.replay_filter_capacity.load(Ordering::Relaxed), 1000);
+        assert!(filter.check(b"hello"));
+        assert!(filter.check(b"world"));
+        assert!(!filter.check(b"hello"));
+        assert_eq!(stats.replay_filter_items.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn remembers_for_two_windows() {
+        let stats = Arc::new(ListenerStats::default());
+        let mut filter = RecentFilter::new(1000, WINDOW, stats.clone());
+        assert!(filter.check(b"hello"));
+        // the first rotation moves it to the previous generation, where it's still remembered
+        std::thread::sleep(WINDOW + WINDOW / 4);
+        assert!(filter.check(b"world"));
+        assert_eq!(stats.replay_filter_items.load(Ordering::Relaxed), 1);
+        assert!(!filter.check(b"hello"));
+        // the second one forgets it
+        std::thread::sleep(WINDOW + WINDOW / 4);
+        assert!(filter.check(b"hello"));
+        assert!(!filter.check(b"world"));
+    }
+
+    #[test]
+    fn forgets_after_quiet_spell() {
+        let mut filter = RecentFilter::new(1000, WINDOW, Default::default());
+        assert!(filter.check(b"hello"));
+        std::thread::sleep(WINDOW * 2 + WINDOW / 4);
+        assert!(filter.check(b"hello"));
+    }
+}
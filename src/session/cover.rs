@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+use rand::prelude::*;
+
+/// Timing obfuscation for a session. Packets leave on a jittered schedule instead of as soon as they are ready, and dummy packets fill the slots where there is nothing to send, so an idle session looks like a busy one.
+#[derive(Debug, Clone)]
+pub struct CoverTraffic {
+    /// Average time between two slots. All packets waiting at a slot are sent together.
+    pub interval: Duration,
+    /// Fraction, between 0 and 1, by which each interval is randomly lengthened or shortened.
+    pub jitter: f64,
+}
+
+impl CoverTraffic {
+    /// Creates a new cover traffic configuration sending a packet every `interval` when idle, with 50% jitter.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            jitter: 0.5,
+        }
+    }
+
+    /// Samples the time until the next slot.
+    pub(crate) fn next_interval(&self) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter, jitter + f64::EPSILON);
+        self.interval.mul_f64(factor)
+    }
+}
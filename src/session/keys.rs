@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::crypt::{AeadSuite, NgAead};
+
+use super::Role;
+
+/// A per-direction key, at some epoch of its ratchet.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct EpochKey {
+    pub epoch: u64,
+    pub key: [u8; 32],
+    /// If set, packets are sealed under the key salted with this, as a server that rebuilt the session from a resume token sends them. The salt survives ratchets, but isn't sealed into tokens, so every rebuild salts the chain afresh.
+    #[serde(skip)]
+    pub salt: Option<[u8; 32]>,
+}
+
+impl EpochKey {
+    /// Ratchets forward one epoch, overwriting the old key.
+    pub fn ratchet(&mut self) {
+        let mut next = crate::crypt::ratchet_key(&self.key);
+        self.key.copy_from_slice(&next);
+        next.zeroize();
+        self.epoch += 1;
+    }
+
+    /// The AEAD that packets of this epoch are sealed with.
+    pub fn crypt(&self, aead: AeadSuite) -> NgAead {
+        self.crypt_for(&self.key, aead)
+    }
+
+    /// The AEAD that packets of the next epoch are sealed with.
+    pub fn next_crypt(&self, aead: AeadSuite) -> NgAead {
+        let mut next = crate::crypt::ratchet_key(&self.key);
+        let crypt = self.crypt_for(&next, aead);
+        next.zeroize();
+        crypt
+    }
+
+    fn crypt_for(&self, key: &[u8; 32], aead: AeadSuite) -> NgAead {
+        match &self.salt {
+            Some(salt) => {
+                let mut salted = crate::crypt::salt_key(key, salt);
+                let crypt = NgAead::with_suite(aead, &salted);
+                salted.zeroize();
+                crypt
+            }
+            None => NgAead::with_suite(aead, key),
+        }
+    }
+}
+
+impl Drop for EpochKey {
+    fn drop(&mut self) {
+        self.key.zeroize()
+    }
+}
+
+/// All the key material a session has: the current keys of both directions, and the keys that authenticate path probes and the progress clients report when resuming. Nothing here can be used to recover the keys of earlier epochs.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SessionKeys {
+    pub up: EpochKey,
+    pub down: EpochKey,
+    pub probe_key: [u8; 32],
+    /// Key that progress reported alongside the resume token these keys were sealed into is authenticated with. Every token gets a fresh one.
+    pub resume_key: [u8; 32],
+}
+
+impl SessionKeys {
+    /// Derives the keys of a new session, at the first epochs, from the key the handshake agreed on.
+    pub fn from_session_key(session_key: &[u8]) -> Self {
+        let derive = |purpose| *blake3::keyed_hash(purpose, session_key).as_bytes();
+        Self {
+            up: EpochKey {
+                epoch: 0,
+                key: derive(crate::crypt::UP_KEY),
+                salt: None,
+            },
+            down: EpochKey {
+                epoch: 0,
+                key: derive(crate::crypt::DN_KEY),
+                salt: None,
+            },
+            probe_key: derive(crate::crypt::PROBE_KEY),
+            resume_key: derive(crate::crypt::RESUME_KEY),
+        }
+    }
+
+    /// Ratchets both directions forward to at least the given epochs. Epochs behind the current ones are left alone, since keys can't go back.
+    pub fn advance_to(&mut self, up_epoch: u64, down_epoch: u64) {
+        while self.up.epoch < up_epoch {
+            self.up.ratchet()
+        }
+        while self.down.epoch < down_epoch {
+            self.down.ratchet()
+        }
+    }
+
+    /// The key of the direction we send in.
+    pub fn send(&mut self, role: Role) -> &mut EpochKey {
+        match role {
+            Role::Server => &mut self.down,
+            Role::Client => &mut self.up,
+        }
+    }
+
+    /// The key of the direction we receive in.
+    pub fn recv(&mut self, role: Role) -> &mut EpochKey {
+        match role {
+            Role::Server => &mut self.up,
+            Role::Client => &mut self.down,
+        }
+    }
+}
+
+impl Drop for SessionKeys {
+    fn drop(&mut self) {
+        self.probe_key.zeroize();
+        self.resume_key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advance_matches_ratchets() {
+        let mut keys = SessionKeys::from_session_key(&[42; 32]);
+        let mut down = keys.down.clone();
+        down.ratchet();
+        down.ratchet();
+        keys.advance_to(1, 2);
+        assert_eq!((keys.up.epoch, keys.down.epoch), (1, 2));
+        assert_eq!(keys.down.key, down.key);
+        assert_ne!(keys.up.key, keys.down.key);
+        // keys never go back
+        keys.advance_to(0, 0);
+        assert_eq!((keys.up.epoch, keys.down.epoch), (1, 2));
+    }
+
+    #[test]
+    fn salts_survive_ratchets() {
+        let keys = SessionKeys::from_session_key(&[42; 32]);
+        let mut salted = keys.down.clone();
+        salted.salt = Some([7; 32]);
+        let msg = b"hello";
+        // salted keys open nothing sealed under the plain ones
+        let sealed = keys
+            .down
+            .crypt(AeadSuite::ChaCha20Poly1305)
+            .encrypt_counter(1, msg);
+        assert!(salted
+            .crypt(AeadSuite::ChaCha20Poly1305)
+            .decrypt_counter(&sealed)
+            .is_err());
+        // the next epoch is salted too
+        let next = salted.next_crypt(AeadSuite::ChaCha20Poly1305);
+        salted.ratchet();
+        let sealed = salted
+            .crypt(AeadSuite::ChaCha20Poly1305)
+            .encrypt_counter(1, msg);
+        assert_eq!(&next.decrypt_counter(&sealed).unwrap().1[..], msg);
+        // the salt stays out of resume tokens
+        let restored: EpochKey =
+            bincode::deserialize(&bincode::serialize(&salted).unwrap()).unwrap();
+        assert!(restored.salt.is_none());
+        assert_eq!(restored.key, salted.key);
+    }
+}
@@ -3,15 +3,15 @@ use std::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
     buffer::Buff,
-    crypt::{AeadError, NgAead},
+    crypt::{AeadError, AeadSuite, NgAead},
     fec::{pre_encode, FrameDecoder},
     protocol::DataFrameV2,
-    Role, SVec,
+    SVec, StatsGatherer,
 };
 use cached::{Cached, SizedCache};
 use moka::sync::Cache;
@@ -19,15 +19,35 @@ use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use rustc_hash::{FxHashMap, FxHashSet};
 
-use super::{rloss::RecvLossCalc, stats::StatsCalculator};
+use super::{
+    keys::SessionKeys, rloss::RecvLossCalc, stats::StatsCalculator, ResumeTicket, Role,
+    COUNTER_NONCE_VERSION, REKEY_VERSION,
+};
+
+/// How long the previous key is still accepted after the remote side ratchets its key, to tolerate reordering and the Rekey frames sent under it.
+const REKEY_GRACE: Duration = Duration::from_secs(10);
 
 /// I/O-free receiving machine.
 pub(crate) struct RecvMachine {
     oob_decoder: OobDecoder,
     rloss: Arc<Mutex<RecvLossCalc>>,
+    gather: Arc<StatsGatherer>,
+    rekey: bool,
+    counter_nonces: bool,
+    aead: AeadSuite,
+    /// The session's keys, which we ratchet the receiving key of.
+    keys: Arc<Mutex<SessionKeys>>,
+    role: Role,
     recv_crypt: NgAead,
+    prev_crypt: Option<(NgAead, Instant)>,
+    next_crypt: Option<NgAead>,
     replay_filter: ReplayFilter,
+    counter_filter: ReplayFilter,
     ping_calc: Arc<StatsCalculator>,
+    /// The newest resume token the remote side sent us, not yet picked up by [RecvMachine::take_ticket].
+    new_ticket: Option<ResumeTicket>,
+    /// Every salt we have received under, which we never switch back to.
+    salts: FxHashSet<[u8; 32]>,
 }
 
 static TOTAL_MACHINES: AtomicUsize = AtomicUsize::new(0);
@@ -39,27 +59,42 @@ impl Drop for RecvMachine {
 }
 
 impl RecvMachine {
-    /// Creates a new machine based on a version and a down decrypter.
+    /// Creates a new machine based on a version and the session's keys, receiving at the current epoch of `role`'s receiving key.
     pub fn new(
         calculator: Arc<StatsCalculator>,
         rloss: Arc<Mutex<RecvLossCalc>>,
-        session_key: &[u8],
-        direction: Role,
+        gather: Arc<StatsGatherer>,
+        version: u64,
+        aead: AeadSuite,
+        keys: Arc<Mutex<SessionKeys>>,
+        role: Role,
     ) -> Self {
         let count = TOTAL_MACHINES.fetch_add(1, Ordering::Relaxed);
         eprintln!("***** {count} RecvMachines *****");
-        let recv_crypt_key = match direction {
-            Role::Server => blake3::keyed_hash(crate::crypt::UP_KEY, session_key),
-            Role::Client => blake3::keyed_hash(crate::crypt::DN_KEY, session_key),
+        let rekey = version >= REKEY_VERSION;
+        let (recv_crypt, next_crypt) = {
+            let mut keys = keys.lock();
+            let recv = keys.recv(role);
+            (recv.crypt(aead), rekey.then(|| recv.next_crypt(aead)))
         };
-        let recv_crypt = NgAead::new(recv_crypt_key.as_bytes());
 
         Self {
             oob_decoder: OobDecoder::new(),
             rloss,
+            gather,
+            rekey,
+            counter_nonces: version >= COUNTER_NONCE_VERSION,
+            aead,
+            keys,
+            role,
             recv_crypt,
+            prev_crypt: None,
+            next_crypt,
             replay_filter: ReplayFilter::default(),
+            counter_filter: ReplayFilter::default(),
             ping_calc: calculator,
+            new_ticket: None,
+            salts: FxHashSet::default(),
         }
     }
 
@@ -68,8 +103,106 @@ impl RecvMachine {
         self.process_ng(packet)
     }
 
+    /// Decrypts a packet with a particular key, returning its nonce counter if the session uses counter nonces.
+    fn open(&self, crypt: &NgAead, packet: &[u8]) -> Result<(Buff, Option<u64>), AeadError> {
+        if self.counter_nonces {
+            let (counter, plain) = crypt.decrypt_counter(packet)?;
+            Ok((plain, Some(counter)))
+        } else {
+            Ok((crypt.decrypt(packet)?, None))
+        }
+    }
+
+    /// Rejects every frame numbered below `frame_no`, and every packet whose nonce counter is below `counter`.
+    pub fn skip_to(&mut self, frame_no: u64, counter: u64) {
+        self.replay_filter = ReplayFilter::starting_at(frame_no);
+        self.counter_filter = ReplayFilter::starting_at(counter);
+    }
+
+    /// Key epoch we are receiving at.
+    pub fn recv_epoch(&self) -> u64 {
+        self.keys.lock().recv(self.role).epoch
+    }
+
+    /// Takes the newest resume token the remote side sent since the last call.
+    pub fn take_ticket(&mut self) -> Option<ResumeTicket> {
+        self.new_ticket.take()
+    }
+
+    /// Switches to receiving under keys salted with `salt`, which a server that rebuilt the session from a resume token sends under. Its frame numbers and nonce counters start over, so everything we knew about them is forgotten, and nothing under the previous keys is accepted anymore. Replaying the announcement of an earlier salt would let through replays of what was sent under it, so we never switch to a salt twice.
+    pub fn resalt(&mut self, salt: [u8; 32]) {
+        if !self.salts.insert(salt) {
+            return;
+        }
+        let (recv_crypt, next_crypt) = {
+            let mut keys = self.keys.lock();
+            let recv = keys.recv(self.role);
+            recv.salt = Some(salt);
+            (
+                recv.crypt(self.aead),
+                self.rekey.then(|| recv.next_crypt(self.aead)),
+            )
+        };
+        self.recv_crypt = recv_crypt;
+        self.next_crypt = next_crypt;
+        self.prev_crypt = None;
+        self.replay_filter = ReplayFilter::default();
+        self.counter_filter = ReplayFilter::default();
+        self.oob_decoder = OobDecoder::new();
+        *self.rloss.lock() = RecvLossCalc::new(1.0);
+        self.gather.increment("recv_resalts", 1.0);
+        tracing::debug!("remote side rebuilt the session under a new salt");
+    }
+
+    /// Moves on to the next key epoch, keeping the current key around for the grace period. The key itself is wiped, so only the grace period's AEAD can still open anything under it.
+    fn advance_epoch(&mut self) {
+        let (epoch, next_crypt) = {
+            let mut keys = self.keys.lock();
+            let recv = keys.recv(self.role);
+            recv.ratchet();
+            (recv.epoch, recv.next_crypt(self.aead))
+        };
+        let recv_crypt = self.next_crypt.replace(next_crypt).unwrap();
+        let prev_crypt = std::mem::replace(&mut self.recv_crypt, recv_crypt);
+        self.prev_crypt = Some((prev_crypt, Instant::now()));
+        self.gather.increment("recv_rekeys", 1.0);
+        tracing::debug!("remote side ratcheted its key to epoch {}", epoch);
+    }
+
+    /// Decrypts a packet under the current key, the previous one during the grace period, or the next one.
+    fn decrypt(&mut self, packet: &[u8]) -> Result<(Buff, Option<u64>), AeadError> {
+        let err = match self.open(&self.recv_crypt, packet) {
+            Ok(plain) => return Ok(plain),
+            Err(err) => err,
+        };
+        if let Some((prev_crypt, rekey_time)) = &self.prev_crypt {
+            if rekey_time.elapsed() < REKEY_GRACE {
+                if let Ok(plain) = self.open(prev_crypt, packet) {
+                    return Ok(plain);
+                }
+            } else {
+                // the grace period is over, so we forget the old key
+                self.prev_crypt = None;
+            }
+        }
+        // every Rekey announcement may have been lost, so a packet under the next key is just as good a sign that the remote side ratcheted
+        if let Some(next_crypt) = &self.next_crypt {
+            if let Ok(plain) = self.open(next_crypt, packet) {
+                self.advance_epoch();
+                return Ok(plain);
+            }
+        }
+        Err(err)
+    }
+
     fn process_ng(&mut self, packet: &[u8]) -> Result<Option<SVec<(Buff, u64)>>, AeadError> {
-        let plain_frame = self.recv_crypt.decrypt(packet)?;
+        let (plain_frame, counter) = self.decrypt(packet)?;
+        if let Some(counter) = counter {
+            // counters are unique per packet, including parity packets, so this catches every replay
+            if !self.counter_filter.add(counter) {
+                return Ok(None);
+            }
+        }
         let v2frame = DataFrameV2::depad(&plain_frame);
         match v2frame {
             Some((
@@ -132,6 +265,40 @@ impl RecvMachine {
                     Ok(None)
                 }
             }
+            Some((DataFrameV2::Cover, _)) => Ok(None),
+            Some((DataFrameV2::Rekey { epoch }, _)) => {
+                // announcements keep coming for a while after the first one, so we only act on the next epoch
+                if self.rekey && epoch == self.recv_epoch() + 1 {
+                    self.advance_epoch();
+                }
+                Ok(None)
+            }
+            Some((
+                DataFrameV2::NewToken {
+                    resume_token,
+                    resume_key,
+                    up_epoch,
+                    down_epoch,
+                },
+                _,
+            )) => {
+                let ticket = ResumeTicket {
+                    resume_token,
+                    resume_key,
+                    up_epoch,
+                    down_epoch,
+                };
+                // each token is sent several times, and frames may be reordered
+                if self
+                    .new_ticket
+                    .as_ref()
+                    .map(|newest| ticket.supersedes(newest))
+                    .unwrap_or(true)
+                {
+                    self.new_ticket = Some(ticket);
+                }
+                Ok(None)
+            }
             None => Ok(None),
         }
     }
@@ -146,6 +313,15 @@ struct ReplayFilter {
 }
 
 impl ReplayFilter {
+    /// Creates a filter that rejects everything below `seqno`.
+    fn starting_at(seqno: u64) -> Self {
+        Self {
+            top_seqno: seqno,
+            bottom_seqno: seqno,
+            seen_seqno: FxHashSet::default(),
+        }
+    }
+
     fn add(&mut self, seqno: u64) -> bool {
         if seqno < self.bottom_seqno {
             // out of range. we can't know, so we just say no
@@ -157,9 +333,17 @@ impl ReplayFilter {
         }
         self.seen_seqno.insert(seqno);
         self.top_seqno = seqno.max(self.top_seqno);
-        while self.top_seqno - self.bottom_seqno > 10000 {
-            self.seen_seqno.remove(&self.bottom_seqno);
-            self.bottom_seqno += 1;
+        if self.top_seqno - self.bottom_seqno > 10000 {
+            // the remote side may skip far ahead (e.g. when a server resumes a session after restarting), so we jump rather than walk
+            let new_bottom = self.top_seqno - 10000;
+            if new_bottom - self.bottom_seqno > 10000 {
+                self.seen_seqno.retain(|v| *v >= new_bottom);
+            } else {
+                for seqno in self.bottom_seqno..new_bottom {
+                    self.seen_seqno.remove(&seqno);
+                }
+            }
+            self.bottom_seqno = new_bottom;
         }
         true
     }
@@ -268,3 +452,96 @@ impl OobDecoder {
         vec![]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(key: [u8; 32]) -> RecvMachine {
+        let gather: Arc<StatsGatherer> = Default::default();
+        let mut keys = SessionKeys::from_session_key(&[0; 32]);
+        keys.up.key = key;
+        RecvMachine::new(
+            Arc::new(StatsCalculator::new(gather.clone())),
+            Arc::new(Mutex::new(RecvLossCalc::new(1.0))),
+            gather,
+            COUNTER_NONCE_VERSION,
+            AeadSuite::ChaCha20Poly1305,
+            Arc::new(Mutex::new(keys)),
+            Role::Server,
+        )
+    }
+
+    fn data_frame(frame_no: u64) -> Buff {
+        DataFrameV2::Data {
+            frame_no,
+            high_recv_frame_no: 0,
+            total_recv_frames: 0,
+            body: Buff::copy_from_slice(b"hello"),
+        }
+        .pad(0xff, |len| len)
+    }
+
+    #[test]
+    fn rejects_replayed_counters() {
+        let key = [1u8; 32];
+        let crypt = NgAead::with_suite(AeadSuite::ChaCha20Poly1305, &key);
+        let mut machine = machine(key);
+        let pkt = crypt.encrypt_counter(1, &data_frame(0));
+        assert_eq!(machine.process(&pkt).unwrap().unwrap().len(), 1);
+        assert!(machine.process(&pkt).unwrap().is_none());
+        // a fresh packet carrying a frame number we've seen is rejected too
+        let pkt = crypt.encrypt_counter(2, &data_frame(0));
+        assert!(machine.process(&pkt).unwrap().is_none());
+        let pkt = crypt.encrypt_counter(3, &data_frame(1));
+        assert_eq!(machine.process(&pkt).unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn skip_to_rejects_older() {
+        let key = [1u8; 32];
+        let crypt = NgAead::with_suite(AeadSuite::ChaCha20Poly1305, &key);
+        let mut machine = machine(key);
+        machine.skip_to(100, 100);
+        assert!(machine
+            .process(&crypt.encrypt_counter(99, &data_frame(100)))
+            .unwrap()
+            .is_none());
+        assert!(machine
+            .process(&crypt.encrypt_counter(100, &data_frame(99)))
+            .unwrap()
+            .is_none());
+        assert!(machine
+            .process(&crypt.encrypt_counter(101, &data_frame(100)))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn resalting_starts_over() {
+        let key = [1u8; 32];
+        let crypt = NgAead::with_suite(AeadSuite::ChaCha20Poly1305, &key);
+        let salted = |salt| {
+            NgAead::with_suite(
+                AeadSuite::ChaCha20Poly1305,
+                &crate::crypt::salt_key(&key, &salt),
+            )
+        };
+        let mut machine = machine(key);
+        let old = crypt.encrypt_counter(1, &data_frame(0));
+        assert!(machine.process(&old).unwrap().is_some());
+        // a rebuilt session numbers from scratch under its salted key, and the old key is done with
+        machine.resalt([2; 32]);
+        assert!(machine.process(&old).is_err());
+        let first = salted([2; 32]).encrypt_counter(1, &data_frame(0));
+        assert!(machine.process(&first).unwrap().is_some());
+        machine.resalt([3; 32]);
+        assert!(machine
+            .process(&salted([3; 32]).encrypt_counter(1, &data_frame(0)))
+            .unwrap()
+            .is_some());
+        // going back to an earlier salt would let its packets be replayed
+        machine.resalt([2; 32]);
+        assert!(machine.process(&first).is_err());
+    }
+}
@@ -1,6 +1,9 @@
 use crate::{buffer::Buff, fec::FrameEncoder};
-use crate::{crypt::AeadError, mux::Multiplex, runtime, StatsGatherer};
-use crate::{crypt::NgAead, protocol::DataFrameV2};
+use crate::{crypt::AeadError, mux::Multiplex, pacer::Pacer, runtime, StatsGatherer};
+use crate::{
+    crypt::{AeadSuite, NgAead},
+    protocol::{DataFrameV2, HandshakeFrame},
+};
 use machine::RecvMachine;
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
@@ -10,26 +13,140 @@ use smol::prelude::*;
 use stats::StatsCalculator;
 
 use std::{
+    collections::VecDeque,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
+mod cover;
+mod keys;
 mod machine;
+mod padding;
 mod rloss;
 mod stats;
+pub use cover::CoverTraffic;
+pub(crate) use keys::SessionKeys;
+use padding::Padder;
+pub use padding::PaddingProfile;
+
+/// Seals a session's keys into a resume token for its client. Servers reissue tokens with this whenever a key ratchets.
+pub(crate) type TokenIssuer = Arc<dyn Fn(&SessionKeys) -> Buff + Send + Sync>;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct SessionConfig {
     pub version: u64,
-    pub session_key: Vec<u8>,
+    /// AEAD suite negotiated during the handshake.
+    pub aead: AeadSuite,
     pub role: Role,
     pub gather: Arc<StatsGatherer>,
+    /// Frame number to start sending from.
+    pub initial_frame_no: u64,
+    /// How to pad outgoing packets.
+    pub padding: PaddingProfile,
+    /// If set, shapes the timing of outgoing packets and sends cover traffic when idle.
+    pub cover: Option<CoverTraffic>,
+    /// Where the session stands, when resuming a session that was lost. New sessions start from the default.
+    pub progress: SessionProgress,
+    /// Ratchet the sending key after this many frames, or after [REKEY_INTERVAL], whichever comes first. Normally [REKEY_FRAMES].
+    pub rekey_frames: u64,
+    /// On clients, the resume token from the ServerHello, which the session replaces with the newer ones the server sends.
+    pub ticket: Option<ResumeTicket>,
+    /// On servers, what the session reissues its client's resume token with.
+    pub token_issuer: Option<TokenIssuer>,
+}
+
+/// A resume token, along with what a client needs to resume with it.
+#[derive(Clone)]
+pub(crate) struct ResumeTicket {
+    pub resume_token: Buff,
+    /// Key that progress reported alongside the token is authenticated with.
+    pub resume_key: [u8; 32],
+    /// Key epochs the token is at.
+    pub up_epoch: u64,
+    pub down_epoch: u64,
+}
+
+impl ResumeTicket {
+    /// Whether this was issued after another ticket. Epochs only ever move forward, and every new ticket moves at least one.
+    pub fn supersedes(&self, other: &ResumeTicket) -> bool {
+        self.up_epoch >= other.up_epoch
+            && self.down_epoch >= other.down_epoch
+            && (self.up_epoch, self.down_epoch) != (other.up_epoch, other.down_epoch)
+    }
+}
+
+/// Where a session stands in its key ratchets and numbering. Clients send this alongside every ClientResume, so that a server that has lost the session, for example by restarting, can pick it back up from the resume token.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SessionProgress {
+    /// Key epoch the client sends at.
+    pub up_epoch: u64,
+    /// Key epoch the client receives at.
+    pub down_epoch: u64,
+    /// Next frame number the client sends. A resumed session rejects anything older, since it may be a replay. Servers don't know this, and report zero.
+    pub up_frame_no: u64,
+    /// Next nonce counter the client sends, which is likewise a floor for a resumed session.
+    pub up_counter: u64,
+}
+
+impl SessionProgress {
+    /// Authenticates this progress, as reported at `time_ms`, under a session's resume key. Hashes compare in constant time.
+    pub fn mac(&self, resume_key: &[u8; 32], time_ms: u64) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_keyed(resume_key);
+        for field in [
+            self.up_epoch,
+            self.down_epoch,
+            self.up_frame_no,
+            self.up_counter,
+            time_ms,
+        ] {
+            hasher.update(&field.to_be_bytes());
+        }
+        hasher.finalize()
+    }
+
+    /// Checks a progress that a client reported at `time_ms`, under the resume key of the token it came with. Old reports are rejected too, since replaying one would lower the replay floors below what the client has sent since.
+    pub fn verify(&self, resume_key: &[u8; 32], time_ms: u64, mac: [u8; 32]) -> bool {
+        unix_time_ms().abs_diff(time_ms) <= MAX_PROGRESS_SKEW_MS
+            && blake3::Hash::from(mac) == self.mac(resume_key, time_ms)
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn unix_time_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// How far the send loop has gotten, shared with the [SessionBack].
+#[derive(Debug, Default)]
+struct SendProgress {
+    epoch: AtomicU64,
+    frame_no: AtomicU64,
+    counter: AtomicU64,
 }
 
+/// The first session version that periodically ratchets its keys.
+pub(crate) const REKEY_VERSION: u64 = 4;
+/// The first session version that derives nonces from a per-direction counter instead of sending random nonces.
+pub(crate) const COUNTER_NONCE_VERSION: u64 = 5;
+/// How many frames sessions send under a key before ratcheting it...
+pub(crate) const REKEY_FRAMES: u64 = 1 << 20;
+/// ...or how long, whichever comes first.
+const REKEY_INTERVAL: Duration = Duration::from_secs(600);
+/// After ratcheting, this many frames are each preceded by a Rekey frame under the old key, so that the other side learns of the new key even if some are lost. Servers likewise send every new resume token this many times.
+const REKEY_ANNOUNCEMENTS: usize = 16;
+/// How many epochs past those of its resume token a session may be resumed at. Catching up takes one hash per epoch, so this bounds the work a ClientResume can cause. Tokens are reissued at every ratchet, so clients only fall this far behind if they miss every new token for weeks.
+pub(crate) const MAX_RESUME_EPOCH: u64 = 1 << 12;
+/// How far the time of a progress report may be from ours. This is the clock skew cookies tolerate.
+const MAX_PROGRESS_SKEW_MS: u64 =
+    crate::crypt::COOKIE_EPOCH_SECS * crate::crypt::COOKIE_EPOCH_SLACK * 1000;
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum Role {
     Server,
@@ -65,42 +182,73 @@ impl Drop for Session {
 }
 
 impl Session {
-    /// Creates a Session.
-    pub(crate) fn new(cfg: SessionConfig) -> (Self, SessionBack) {
+    /// Creates a Session, starting from the given keys.
+    pub(crate) fn new(mut cfg: SessionConfig, mut keys: SessionKeys) -> (Self, SessionBack) {
         let count = TOTAL_SESSIONS.fetch_add(1, Ordering::Relaxed);
         eprintln!("***** {count} Sessions *****");
         let (send_tosend, recv_tosend) = smol::channel::bounded(256);
         let gather = cfg.gather.clone();
         let calculator = Arc::new(StatsCalculator::new(gather.clone()));
         let rloss = Arc::new(Mutex::new(RecvLossCalc::new(1.0)));
-        let machine = Mutex::new(RecvMachine::new(
+        // a server rebuilding the session from a token may have to catch up with the client, and then owes it a token at the new epochs
+        let issued_epochs = (keys.up.epoch, keys.down.epoch);
+        keys.advance_to(cfg.progress.up_epoch, cfg.progress.down_epoch);
+        // only the keys of the current epochs are ever kept, and every ratchet wipes the keys it supersedes and gets the client a token sealed over the new ones. so once both directions have ratcheted, neither side holds anything the earlier keys can be recovered from
+        let send_key = keys.send(cfg.role);
+        let send_epoch = send_key.epoch;
+        let send_crypt = send_key.crypt(cfg.aead);
+        let keys = Arc::new(Mutex::new(keys));
+        let mut machine = RecvMachine::new(
             calculator.clone(),
             rloss.clone(),
-            &cfg.session_key,
+            gather.clone(),
+            cfg.version,
+            cfg.aead,
+            keys.clone(),
             cfg.role,
-        ));
+        );
+        if let Role::Server = cfg.role {
+            // anything the client sent before it asked us to resume may be replayed by an attacker
+            machine.skip_to(cfg.progress.up_frame_no, cfg.progress.up_counter);
+        }
+        let machine = Mutex::new(machine);
 
         let (send_decoded, recv_decoded) = smol::channel::bounded(256);
         let (send_outgoing, recv_outgoing) = smol::channel::bounded(256);
+        // sessions rebuilt from a resume token count from wherever their frame numbers start, since they send under a salt of their own
+        let nonce_counter = cfg.initial_frame_no;
+        let sent = Arc::new(SendProgress {
+            epoch: AtomicU64::new(send_epoch),
+            frame_no: AtomicU64::new(cfg.initial_frame_no),
+            counter: AtomicU64::new(nonce_counter + 1),
+        });
         let session_back = SessionBack {
             machine,
+            role: cfg.role,
+            sent: sent.clone(),
+            keys: keys.clone(),
+            ticket: Mutex::new(cfg.ticket.take()),
             send_decoded,
             recv_outgoing,
         };
         let count = TOTAL_BACKS.fetch_add(1, Ordering::Relaxed);
         eprintln!("***** {count} SessionBacks *****");
-        let send_crypt_key = match cfg.role {
-            Role::Server => blake3::keyed_hash(crate::crypt::DN_KEY, &cfg.session_key),
-            Role::Client => blake3::keyed_hash(crate::crypt::UP_KEY, &cfg.session_key),
-        };
-        let send_crypt = NgAead::new(send_crypt_key.as_bytes());
         let ctx = SessionSendCtx {
             cfg,
             statg: calculator,
             gather: gather.clone(),
             rloss,
             recv_tosend,
+            keys,
             send_crypt,
+            send_epoch,
+            sent,
+            issued_epochs,
+            announce: None,
+            token_announce: None,
+            frames_since_rekey: 0,
+            last_rekey: Instant::now(),
+            nonce_counter,
             send_outgoing,
         };
         let task = runtime::spawn(session_send_loop(ctx));
@@ -154,6 +302,11 @@ static TOTAL_BACKS: AtomicUsize = AtomicUsize::new(0);
 /// "Back side" of a Session.
 pub(crate) struct SessionBack {
     machine: Mutex<RecvMachine>,
+    role: Role,
+    sent: Arc<SendProgress>,
+    keys: Arc<Mutex<SessionKeys>>,
+    /// On clients, the newest resume token the server gave us.
+    ticket: Mutex<Option<ResumeTicket>>,
     send_decoded: Sender<Buff>,
     recv_outgoing: Receiver<Buff>,
 }
@@ -167,7 +320,19 @@ impl Drop for SessionBack {
 impl SessionBack {
     /// Given an incoming raw packet, injects it into the sessionback. If decryption fails, returns an error.
     pub fn inject_incoming(&self, pkt: &[u8]) -> Result<(), AeadError> {
-        let decoded = self.machine.lock().process(pkt)?;
+        let mut machine = self.machine.lock();
+        let decoded = machine.process(pkt)?;
+        if let (Role::Client, Some(new_ticket)) = (self.role, machine.take_ticket()) {
+            let mut ticket = self.ticket.lock();
+            if ticket
+                .as_ref()
+                .map(|ticket| new_ticket.supersedes(ticket))
+                .unwrap_or(true)
+            {
+                *ticket = Some(new_ticket);
+            }
+        }
+        drop(machine);
         if let Some(decoded) = decoded {
             for decoded in decoded {
                 let _ = self.send_decoded.try_send(decoded.0);
@@ -176,6 +341,79 @@ impl SessionBack {
         Ok(())
     }
 
+    /// Where the session stands, from the client's point of view.
+    pub fn progress(&self) -> SessionProgress {
+        let send_epoch = self.sent.epoch.load(Ordering::Relaxed);
+        let recv_epoch = self.machine.lock().recv_epoch();
+        match self.role {
+            Role::Server => SessionProgress {
+                up_epoch: recv_epoch,
+                down_epoch: send_epoch,
+                up_frame_no: 0,
+                up_counter: 0,
+            },
+            Role::Client => SessionProgress {
+                up_epoch: send_epoch,
+                down_epoch: recv_epoch,
+                up_frame_no: self.sent.frame_no.load(Ordering::Relaxed),
+                up_counter: self.sent.counter.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    /// On clients, the frames that bind a shard to the session on the server: a ClientResume with the newest resume token, and where the session stands, authenticated under the token's resume key, in case the server lost the session.
+    pub fn resume_frames(&self, shard_id: u8) -> Option<[HandshakeFrame; 2]> {
+        let ticket = self.ticket.lock().clone()?;
+        let progress = self.progress();
+        let time_ms = unix_time_ms();
+        Some([
+            HandshakeFrame::ClientResume {
+                resume_token: ticket.resume_token,
+                shard_id,
+            },
+            HandshakeFrame::ResumeProgress {
+                up_epoch: progress.up_epoch,
+                down_epoch: progress.down_epoch,
+                up_frame_no: progress.up_frame_no,
+                up_counter: progress.up_counter,
+                time_ms,
+                mac: *progress.mac(&ticket.resume_key, time_ms).as_bytes(),
+            },
+        ])
+    }
+
+    /// The lowest frame number and nonce counter this session hasn't sent with yet.
+    pub fn send_watermark(&self) -> u64 {
+        self.sent
+            .frame_no
+            .load(Ordering::Relaxed)
+            .max(self.sent.counter.load(Ordering::Relaxed))
+    }
+
+    /// On servers that rebuilt the session from a resume token, the frame telling the client what salt we send under.
+    pub fn salt_frame(&self) -> Option<HandshakeFrame> {
+        let mut keys = self.keys.lock();
+        let salt = keys.send(self.role).salt?;
+        Some(HandshakeFrame::ResumeSalt {
+            salt,
+            mac: *crate::crypt::salt_mac(&keys.probe_key, &salt).as_bytes(),
+        })
+    }
+
+    /// On clients, starts receiving under the salt from a server's ResumeSalt, if its MAC checks out.
+    pub fn adopt_salt(&self, salt: [u8; 32], mac: [u8; 32]) {
+        if blake3::Hash::from(mac) != crate::crypt::salt_mac(&self.keys.lock().probe_key, &salt) {
+            tracing::debug!("ignoring unauthenticated resume salt");
+            return;
+        }
+        self.machine.lock().resalt(salt);
+    }
+
+    /// Authenticates a path probe, or the reply to one, for this session.
+    pub fn probe_mac(&self, is_reply: bool, nonce: u64) -> blake3::Hash {
+        crate::crypt::probe_mac(&self.keys.lock().probe_key, is_reply, nonce)
+    }
+
     /// Wait for an outgoing packet from the session.
     pub async fn next_outgoing(&self) -> Result<Buff, SessionError> {
         self.recv_outgoing
@@ -192,10 +430,113 @@ struct SessionSendCtx {
     gather: Arc<StatsGatherer>,
     rloss: Arc<Mutex<RecvLossCalc>>,
     recv_tosend: Receiver<Buff>,
+    keys: Arc<Mutex<SessionKeys>>,
     send_crypt: NgAead,
+    send_epoch: u64,
+    sent: Arc<SendProgress>,
+    /// Key epochs of the newest resume token the client has, as far as we know.
+    issued_epochs: (u64, u64),
+    /// The previous key, and how many more Rekey frames to send under it.
+    announce: Option<(NgAead, usize)>,
+    /// A NewToken frame, and how many more times to send it.
+    token_announce: Option<(DataFrameV2, usize)>,
+    frames_since_rekey: u64,
+    last_rekey: Instant,
+    nonce_counter: u64,
     send_outgoing: Sender<Buff>,
 }
 
+impl SessionSendCtx {
+    /// Encrypts a padded frame, ratcheting the sending key first if it has been used for too long.
+    fn encrypt(&mut self, padded: &[u8]) -> Buff {
+        if self.cfg.version >= REKEY_VERSION
+            && (self.frames_since_rekey >= self.cfg.rekey_frames
+                || self.last_rekey.elapsed() >= REKEY_INTERVAL)
+        {
+            let send_crypt = {
+                let mut keys = self.keys.lock();
+                let send_key = keys.send(self.cfg.role);
+                send_key.ratchet();
+                self.send_epoch = send_key.epoch;
+                send_key.crypt(self.cfg.aead)
+            };
+            let prev_crypt = std::mem::replace(&mut self.send_crypt, send_crypt);
+            self.sent.epoch.store(self.send_epoch, Ordering::Relaxed);
+            self.announce = Some((prev_crypt, REKEY_ANNOUNCEMENTS));
+            self.frames_since_rekey = 0;
+            self.last_rekey = Instant::now();
+            self.gather.increment("send_rekeys", 1.0);
+            tracing::debug!("ratcheted sending key to epoch {}", self.send_epoch);
+        }
+        if let Some((prev_crypt, remaining)) = self.announce.take() {
+            // the other side only has the old key until it hears about the new one, so we tell it under the old key, padded like the frame it precedes
+            let announcement = DataFrameV2::Rekey {
+                epoch: self.send_epoch,
+            }
+            .pad(0xff, |_| padded.len());
+            let announcement = self.seal(&prev_crypt, &announcement);
+            let _ = self.send_outgoing.try_send(announcement);
+            if remaining > 1 {
+                self.announce = Some((prev_crypt, remaining - 1));
+            }
+        }
+        self.reissue_token();
+        let send_crypt = self.send_crypt.clone();
+        if let Some((new_token, remaining)) = self.token_announce.take() {
+            let new_token_padded = new_token.pad(0xff, |_| padded.len());
+            let new_token_sealed = self.seal(&send_crypt, &new_token_padded);
+            let _ = self.send_outgoing.try_send(new_token_sealed);
+            if remaining > 1 {
+                self.token_announce = Some((new_token, remaining - 1));
+            }
+        }
+        self.frames_since_rekey += 1;
+        self.seal(&send_crypt, padded)
+    }
+
+    /// On servers, seals the current keys into a new resume token for the client, if either direction has ratcheted since the last one.
+    fn reissue_token(&mut self) {
+        let token_issuer = if let Some(token_issuer) = &self.cfg.token_issuer {
+            token_issuer
+        } else {
+            return;
+        };
+        let mut keys = self.keys.lock();
+        let epochs = (keys.up.epoch, keys.down.epoch);
+        if epochs == self.issued_epochs {
+            return;
+        }
+        // a fresh resume key for every token, so that nothing about earlier tokens can authenticate progress for this one
+        keys.resume_key = rand::random();
+        let resume_token = token_issuer(&keys);
+        self.token_announce = Some((
+            DataFrameV2::NewToken {
+                resume_token,
+                resume_key: keys.resume_key,
+                up_epoch: epochs.0,
+                down_epoch: epochs.1,
+            },
+            REKEY_ANNOUNCEMENTS,
+        ));
+        self.issued_epochs = epochs;
+        self.gather.increment("send_new_tokens", 1.0);
+        tracing::debug!("reissued resume token at epochs {:?}", epochs);
+    }
+
+    /// Encrypts with a particular key.
+    fn seal(&mut self, crypt: &NgAead, padded: &[u8]) -> Buff {
+        if self.cfg.version >= COUNTER_NONCE_VERSION {
+            self.nonce_counter += 1;
+            self.sent
+                .counter
+                .store(self.nonce_counter + 1, Ordering::Relaxed);
+            crypt.encrypt_counter(self.nonce_counter, padded)
+        } else {
+            crypt.encrypt(padded)
+        }
+    }
+}
+
 // #[tracing::instrument(skip(ctx))]
 async fn session_send_loop(ctx: SessionSendCtx) {
     // sending loop
@@ -207,13 +548,17 @@ async fn session_send_loop(ctx: SessionSendCtx) {
 }
 
 const BURST_SIZE: usize = 16;
+/// With cover traffic, at most this many payloads wait for the next slot.
+const MAX_PENDING: usize = 256;
 
 #[tracing::instrument(skip(ctx))]
-async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<()> {
+async fn session_send_loop_nextgen(mut ctx: SessionSendCtx, version: u64) -> Option<()> {
     // let mut pacer = Pacer::new(Duration::from_millis(1) / 30);
     enum Event {
         NewPayload(Buff),
+        DelayedPayload(Buff),
         FecTimeout,
+        CoverTick,
     }
 
     const FEC_TIMEOUT_MS: u64 = 20;
@@ -223,7 +568,18 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
     // Vector of "unfecked" frames.
     let mut unfecked: Vec<(u64, Buff)> = Vec::new();
     let mut fec_encoder = FrameEncoder::new(10); // around 4 percent
-    let mut frame_no = 0;
+    let mut frame_no = ctx.cfg.initial_frame_no;
+    let mut padder = Padder::new(ctx.cfg.padding.clone());
+    // with cover traffic, payloads wait in a queue until the next slot, when they are all released
+    let shaping = ctx.cfg.cover.is_some();
+    let mut cover_pacer = ctx
+        .cfg
+        .cover
+        .as_ref()
+        .map(|cover| Pacer::new_unbatched(cover.next_interval()));
+    let mut pending: VecDeque<Buff> = VecDeque::new();
+    let mut released = 0;
+    let mut last_padded_len = 32;
     loop {
         // either we have something new to send, or the FEC timer expired.
         let event: Option<Event> = async {
@@ -236,7 +592,28 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
             }
             Some(Event::FecTimeout)
         }
-        .or(async { Some(Event::NewPayload(ctx.recv_tosend.recv().await.ok()?)) })
+        .or(async {
+            if released > 0 {
+                released -= 1;
+                return pending.pop_front().map(Event::NewPayload);
+            }
+            if !shaping {
+                return Some(Event::NewPayload(ctx.recv_tosend.recv().await.ok()?));
+            }
+            if pending.len() >= MAX_PENDING {
+                // apply backpressure until the next slot
+                smol::future::pending::<()>().await;
+            }
+            Some(Event::DelayedPayload(ctx.recv_tosend.recv().await.ok()?))
+        })
+        .or(async {
+            if let Some(pacer) = cover_pacer.as_mut() {
+                pacer.wait_next().await;
+                Some(Event::CoverTick)
+            } else {
+                smol::future::pending().await
+            }
+        })
         .await;
         let loss = ctx.rloss.lock().calculate_loss();
         let loss_u8 = (loss * 254.0) as u8;
@@ -250,18 +627,38 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
                     total_recv_frames: ctx.statg.total_recv_frames(),
                     body: send_payload.clone(),
                 };
-                let send_padded = send_framed.pad(loss_u8);
+                let send_padded = send_framed.pad(loss_u8, |len| padder.target_len(len));
+                ctx.gather
+                    .update("send_padding_overhead", padder.overhead() as f32);
+                last_padded_len = send_padded.len();
                 ctx.statg.ping_send(frame_no);
-                let send_encrypted = ctx.send_crypt.encrypt(&send_padded);
+                let send_encrypted = ctx.encrypt(&send_padded);
                 ctx.send_outgoing.send(send_encrypted).await.ok()?;
                 // we now add to unfecked
                 unfecked.push((frame_no, send_payload));
                 // increment frame no
                 frame_no += 1;
+                ctx.sent.frame_no.store(frame_no, Ordering::Relaxed);
                 // reset fec timer
                 fec_timer.set_after(Duration::from_millis(FEC_TIMEOUT_MS));
                 // pacer.wait_next().await;
             }
+            Event::DelayedPayload(send_payload) => pending.push_back(send_payload),
+            // a cover traffic slot arrived. we release what is waiting, or send a dummy frame that looks like the last real one.
+            Event::CoverTick => {
+                if let (Some(cover), Some(pacer)) = (&ctx.cfg.cover, cover_pacer.as_mut()) {
+                    pacer.set_interval(cover.next_interval());
+                }
+                if pending.is_empty() {
+                    let send_padded = DataFrameV2::Cover.pad(loss_u8, |_| last_padded_len);
+                    let send_encrypted = ctx.encrypt(&send_padded);
+                    if ctx.send_outgoing.try_send(send_encrypted).is_ok() {
+                        ctx.gather.increment("send_cover_frames", 1.0);
+                    }
+                } else {
+                    released = pending.len();
+                }
+            }
             // we have something to send, as a FEC packet.
             Event::FecTimeout => {
                 // reset fec timer
@@ -298,8 +695,8 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
                         body: parity.clone(),
                         pad_size,
                     };
-                    let send_padded = send_framed.pad(loss_u8);
-                    let send_encrypted = ctx.send_crypt.encrypt(&send_padded);
+                    let send_padded = send_framed.pad(loss_u8, |len| padder.target_len(len));
+                    let send_encrypted = ctx.encrypt(&send_padded);
                     if ctx.send_outgoing.try_send(send_encrypted).is_err() {
                         tracing::warn!("dropping send due to backpressure");
                     }
@@ -311,3 +708,243 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(role: Role, progress: SessionProgress, initial_frame_no: u64) -> SessionConfig {
+        SessionConfig {
+            version: crate::protocol::MAX_VERSION,
+            aead: AeadSuite::ChaCha20Poly1305,
+            role,
+            gather: Default::default(),
+            initial_frame_no,
+            padding: Default::default(),
+            cover: None,
+            progress,
+            // few enough that the tests see ratchets
+            rekey_frames: 64,
+            ticket: None,
+            token_issuer: None,
+        }
+    }
+
+    fn session(
+        role: Role,
+        progress: SessionProgress,
+        initial_frame_no: u64,
+    ) -> (Session, SessionBack) {
+        Session::new(
+            config(role, progress, initial_frame_no),
+            SessionKeys::from_session_key(&[42; 32]),
+        )
+    }
+
+    /// Sends `count` messages from one session to another, returning how many arrived.
+    async fn pump(
+        from: &Session,
+        from_back: &SessionBack,
+        to: &Session,
+        to_back: &SessionBack,
+        count: usize,
+    ) -> usize {
+        pump_recording(from, from_back, to, to_back, count, &mut Vec::new()).await
+    }
+
+    /// Like [pump], but also records every packet sent.
+    async fn pump_recording(
+        from: &Session,
+        from_back: &SessionBack,
+        to: &Session,
+        to_back: &SessionBack,
+        count: usize,
+        recording: &mut Vec<Buff>,
+    ) -> usize {
+        let mut received = 0;
+        for i in 0..count {
+            from.send_bytes(Buff::copy_from_slice(&(i as u64).to_be_bytes()))
+                .await
+                .unwrap();
+            // drain everything the sender emits, including announcements and parity
+            loop {
+                let pkt = async { from_back.next_outgoing().await.ok() }
+                    .or(async {
+                        smol::Timer::after(Duration::from_millis(5)).await;
+                        None
+                    })
+                    .await;
+                let pkt = if let Some(pkt) = pkt { pkt } else { break };
+                let _ = to_back.inject_incoming(&pkt);
+                recording.push(pkt);
+            }
+            while to.recv_decoded.try_recv().is_ok() {
+                received += 1;
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn resume_rejects_replays() {
+        smol::block_on(async {
+            let (client, client_back) = session(Role::Client, Default::default(), 0);
+            let (server, server_back) = session(Role::Server, Default::default(), 0);
+            assert_eq!(
+                pump(&client, &client_back, &server, &server_back, 10).await,
+                10
+            );
+            // an attacker records what the client sends...
+            let mut recorded = Vec::new();
+            assert_eq!(
+                pump_recording(
+                    &client,
+                    &client_back,
+                    &server,
+                    &server_back,
+                    10,
+                    &mut recorded
+                )
+                .await,
+                10
+            );
+            drop((server, server_back));
+            // ...and replays it to a server that lost the session
+            let (server, server_back) = session(Role::Server, client_back.progress(), 1 << 30);
+            for pkt in recorded {
+                let _ = server_back.inject_incoming(&pkt);
+            }
+            assert!(server.recv_decoded.try_recv().is_err());
+            assert_eq!(
+                pump(&client, &client_back, &server, &server_back, 10).await,
+                10
+            );
+        })
+    }
+
+    #[test]
+    fn ratchet_survives_lost_announcements() {
+        smol::block_on(async {
+            let (client, client_back) = session(Role::Client, Default::default(), 0);
+            let (server, server_back) = session(Role::Server, Default::default(), 0);
+            assert_eq!(
+                pump(&client, &client_back, &server, &server_back, 40).await,
+                40
+            );
+            // everything around the ratchet, including every announcement, is lost
+            let (void, void_back) = session(Role::Server, Default::default(), 0);
+            pump(&client, &client_back, &void, &void_back, 50).await;
+            assert_eq!(client_back.progress().up_epoch, 1);
+            assert_eq!(server_back.progress().up_epoch, 0);
+            assert_eq!(
+                pump(&client, &client_back, &server, &server_back, 20).await,
+                20
+            );
+            assert_eq!(server_back.progress().up_epoch, 1);
+        })
+    }
+
+    #[test]
+    fn resume_after_ratchets() {
+        smol::block_on(async {
+            let (client, client_back) = session(Role::Client, Default::default(), 0);
+            let (server, server_back) = session(Role::Server, Default::default(), 0);
+            assert_eq!(
+                pump(&client, &client_back, &server, &server_back, 300).await,
+                300
+            );
+            assert_eq!(
+                pump(&server, &server_back, &client, &client_back, 300).await,
+                300
+            );
+            let progress = client_back.progress();
+            assert!(progress.up_epoch >= 3 && progress.down_epoch >= 3);
+            assert_eq!(progress.up_epoch, server_back.progress().up_epoch);
+            assert_eq!(progress.down_epoch, server_back.progress().down_epoch);
+
+            // a server that lost the session and knows nothing but the first keys can't follow
+            drop((server, server_back));
+            let (stale, stale_back) = session(Role::Server, Default::default(), 1 << 30);
+            assert_eq!(
+                pump(&client, &client_back, &stale, &stale_back, 10).await,
+                0
+            );
+
+            // but it can when it learns the client's epochs
+            let (server, server_back) = session(Role::Server, client_back.progress(), 1 << 30);
+            assert_eq!(
+                pump(&client, &client_back, &server, &server_back, 200).await,
+                200
+            );
+            assert_eq!(
+                pump(&server, &server_back, &client, &client_back, 200).await,
+                200
+            );
+            assert!(client_back.progress().up_epoch > progress.up_epoch);
+        })
+    }
+
+    #[test]
+    fn clients_get_reissued_tokens() {
+        smol::block_on(async {
+            let keys = SessionKeys::from_session_key(&[42; 32]);
+            let mut client_cfg = config(Role::Client, Default::default(), 0);
+            client_cfg.ticket = Some(ResumeTicket {
+                resume_token: Buff::copy_from_slice(b"first token"),
+                resume_key: keys.resume_key,
+                up_epoch: 0,
+                down_epoch: 0,
+            });
+            let (client, client_back) = Session::new(client_cfg, keys);
+            // tokens that simply hold the keys in the clear
+            let mut server_cfg = config(Role::Server, Default::default(), 0);
+            server_cfg.token_issuer = Some(Arc::new(|keys: &SessionKeys| {
+                Buff::copy_from_slice(&bincode::serialize(keys).unwrap())
+            }));
+            let (server, server_back) =
+                Session::new(server_cfg, SessionKeys::from_session_key(&[42; 32]));
+            assert_eq!(
+                pump(&client, &client_back, &server, &server_back, 300).await,
+                300
+            );
+            assert_eq!(
+                pump(&server, &server_back, &client, &client_back, 300).await,
+                300
+            );
+            let progress = client_back.progress();
+            assert!(progress.up_epoch >= 3 && progress.down_epoch >= 3);
+
+            // the client resumes with a token for the current keys, not the first one
+            let (resume_token, time_ms, mac) = match client_back.resume_frames(0).unwrap() {
+                [HandshakeFrame::ClientResume { resume_token, .. }, HandshakeFrame::ResumeProgress { time_ms, mac, .. }] => {
+                    (resume_token, time_ms, mac)
+                }
+                _ => unreachable!(),
+            };
+            let keys: SessionKeys = bincode::deserialize(&resume_token).unwrap();
+            assert_eq!(
+                (keys.up.epoch, keys.down.epoch),
+                (progress.up_epoch, progress.down_epoch)
+            );
+            // and authenticates its progress under that token's resume key, which nothing before it could
+            assert!(progress.verify(&keys.resume_key, time_ms, mac));
+            assert!(!progress.verify(
+                &SessionKeys::from_session_key(&[42; 32]).resume_key,
+                time_ms,
+                mac
+            ));
+
+            // a server that lost the session can pick it up from that token alone
+            drop((server, server_back));
+            let (server, server_back) = Session::new(config(Role::Server, progress, 1 << 30), keys);
+            assert_eq!(
+                pump(&client, &client_back, &server, &server_back, 200).await,
+                200
+            );
+            assert_eq!(
+                pump(&server, &server_back, &client, &client_back, 200).await,
+                200
+            );
+        })
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use rand::prelude::*;
+
+/// Padded packets are never made longer than this, so that they still fit in the MTU after encryption.
+const MAX_PADDED_LEN: usize = 1340;
+
+/// How a session pads outgoing packets, to keep packet lengths from revealing the lengths of application packets. All lengths are of the plaintext; encryption adds a small fixed overhead on top.
+#[derive(Debug, Clone, Default)]
+pub enum PaddingProfile {
+    /// Only round packets up to a multiple of 32 bytes. This is the cheapest, but packet lengths closely track application lengths.
+    #[default]
+    Minimal,
+    /// Pad every packet to the same length, such as the MTU. Packets that are already longer are only rounded.
+    Fixed(usize),
+    /// Pad each packet to a length sampled from the given lengths, for example packet lengths observed in a protocol to mimic. Only lengths not shorter than the packet are sampled.
+    Sampled(Arc<[usize]>),
+    /// Add a random amount of padding, up to `max_padding` bytes, to each packet, as long as the total padding stays below `budget` times the unpadded traffic.
+    Random { max_padding: usize, budget: f64 },
+}
+
+/// Applies a padding profile to a stream of packets, keeping track of the overhead.
+pub(crate) struct Padder {
+    profile: PaddingProfile,
+    unpadded_bytes: u64,
+    padding_bytes: u64,
+}
+
+impl Padder {
+    pub fn new(profile: PaddingProfile) -> Self {
+        Self {
+            profile,
+            unpadded_bytes: 0,
+            padding_bytes: 0,
+        }
+    }
+
+    /// Decides how long a packet with the given unpadded length should be after padding.
+    pub fn target_len(&mut self, len: usize) -> usize {
+        let rounded = len + (32 - len % 32);
+        let target = match &self.profile {
+            PaddingProfile::Minimal => rounded,
+            PaddingProfile::Fixed(fixed) => *fixed,
+            PaddingProfile::Sampled(lengths) => lengths
+                .iter()
+                .copied()
+                .filter(|sampled| *sampled >= rounded)
+                .choose(&mut rand::thread_rng())
+                .unwrap_or(rounded),
+            PaddingProfile::Random {
+                max_padding,
+                budget,
+            } => {
+                if (self.padding_bytes as f64) < (self.unpadded_bytes as f64) * budget {
+                    rounded + rand::thread_rng().gen_range(0, max_padding + 1)
+                } else {
+                    rounded
+                }
+            }
+        };
+        let target = target.min(MAX_PADDED_LEN).max(rounded);
+        self.unpadded_bytes += len as u64;
+        self.padding_bytes += (target - len) as u64;
+        target
+    }
+
+    /// Padding sent so far, as a fraction of the unpadded traffic.
+    pub fn overhead(&self) -> f64 {
+        self.padding_bytes as f64 / (self.unpadded_bytes.max(1) as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal_rounds_up() {
+        let mut padder = Padder::new(PaddingProfile::Minimal);
+        assert_eq!(padder.target_len(0), 32);
+        assert_eq!(padder.target_len(31), 32);
+        // there is always at least some padding
+        assert_eq!(padder.target_len(32), 64);
+        assert_eq!(padder.target_len(1000), 1024);
+    }
+
+    #[test]
+    fn fixed_pads_to_length() {
+        let mut padder = Padder::new(PaddingProfile::Fixed(1000));
+        assert_eq!(padder.target_len(10), 1000);
+        assert_eq!(padder.target_len(900), 1000);
+        // longer packets are only rounded
+        assert_eq!(padder.target_len(1100), 1120);
+    }
+
+    #[test]
+    fn sampled_never_shortens() {
+        let mut padder = Padder::new(PaddingProfile::Sampled(Arc::from(&[100, 500, 800][..])));
+        for _ in 0..100 {
+            let target = padder.target_len(200);
+            assert!(target == 500 || target == 800, "sampled {}", target);
+        }
+        // nothing is long enough, so this is only rounded
+        assert_eq!(padder.target_len(900), 928);
+    }
+
+    #[test]
+    fn random_stays_within_budget() {
+        let mut padder = Padder::new(PaddingProfile::Random {
+            max_padding: 400,
+            budget: 0.1,
+        });
+        for _ in 0..1000 {
+            let target = padder.target_len(500);
+            assert!((512..=912).contains(&target), "padded to {}", target);
+        }
+        // one packet can overshoot the budget, after which padding stops until traffic catches up
+        assert!(padder.overhead() <= 0.1 + 412.0 / 500_000.0);
+        assert!(padder.overhead() > 0.05);
+
+        let mut padder = Padder::new(PaddingProfile::Random {
+            max_padding: 400,
+            budget: 0.0,
+        });
+        for _ in 0..100 {
+            assert_eq!(padder.target_len(500), 512);
+        }
+    }
+
+    #[test]
+    fn clamped_to_max_len() {
+        let mut padder = Padder::new(PaddingProfile::Fixed(5000));
+        assert_eq!(padder.target_len(100), MAX_PADDED_LEN);
+        let mut padder = Padder::new(PaddingProfile::Sampled(Arc::from(&[5000][..])));
+        assert_eq!(padder.target_len(100), MAX_PADDED_LEN);
+        let mut padder = Padder::new(PaddingProfile::Random {
+            max_padding: 5000,
+            budget: f64::INFINITY,
+        });
+        padder.target_len(100);
+        for _ in 0..100 {
+            assert!(padder.target_len(1000) <= MAX_PADDED_LEN);
+        }
+        // the clamp never cuts into the packet itself
+        assert_eq!(padder.target_len(1400), 1408);
+    }
+}
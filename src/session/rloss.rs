@@ -42,6 +42,10 @@ impl RecvLossCalc {
         // first try to fill a gap with this seqno
         if let Some(gap) = self.gap_seqnos.remove(&seqno) {
             self.good_seqnos.insert(seqno, gap);
+        } else if seqno > self.last_seen_seqno + 10000 {
+            // the remote side skipped far ahead; don't count this as a huge gap
+            self.last_seen_seqno = seqno;
+            self.good_seqnos.insert(seqno, Instant::now());
         } else if seqno > self.last_seen_seqno {
             for missing in (self.last_seen_seqno..seqno).skip(1) {
                 self.gap_seqnos.insert(missing, Instant::now());
@@ -1,4 +1,3 @@
-use async_native_tls::Protocol;
 use dashmap::DashMap;
 use rustc_hash::FxHashMap;
 use smol::channel::{Receiver, Sender};
@@ -13,16 +12,18 @@ use std::{
 
 use crate::{
     buffer::Buff,
-    crypt::{triple_ecdh, Cookie, NgAead},
-    protocol::HandshakeFrame,
+    crypt::{triple_ecdh, AeadSuite, Cookie, NgAead},
+    protocol::{HandshakeFrame, HandshakePadding, MIN_VERSION},
     runtime, Backhaul, Connector,
 };
 use anyhow::Context;
 use smol_timeout::TimeoutExt;
 
 use super::{
-    read_encrypted, write_encrypted, DynAsyncRead, DynAsyncWrite, ObfsTcp, CONN_LIFETIME,
-    TCP_DN_KEY, TCP_UP_KEY,
+    read_encrypted,
+    tls_helpers::{fake_domain, tls_connect, TlsClientConfig},
+    write_encrypted, DynAsyncRead, DynAsyncWrite, ObfsTcp, CONN_LIFETIME, TCP_DN_KEY,
+    TCP_HELLO_AEAD, TCP_UP_KEY,
 };
 
 /// A TCP-based backhaul, client-side.
@@ -35,6 +36,10 @@ pub struct TcpClientBackhaul {
 
     connect: Connector,
     tls: bool,
+    client_sk: Option<x25519_dalek::StaticSecret>,
+    handshake_padding: HandshakePadding,
+    tls_config: TlsClientConfig,
+    aead_suites: Vec<AeadSuite>,
 }
 
 impl TcpClientBackhaul {
@@ -53,6 +58,10 @@ impl TcpClientBackhaul {
                 Arc::new(move |addr| smol::net::TcpStream::connect(addr).boxed())
             }),
             tls,
+            client_sk: None,
+            handshake_padding: HandshakePadding::default(),
+            tls_config: TlsClientConfig::default(),
+            aead_suites: AeadSuite::preferred(),
         }
     }
 
@@ -62,6 +71,30 @@ impl TcpClientBackhaul {
         self
     }
 
+    /// Sets the long-term client key used to authenticate to the server. Otherwise, a random key is used for every connection.
+    pub fn set_client_key(mut self, sk: x25519_dalek::StaticSecret) -> Self {
+        self.client_sk = Some(sk);
+        self
+    }
+
+    /// Sets how long the hello sent on every new connection is padded to.
+    pub fn set_handshake_padding(mut self, padding: HandshakePadding) -> Self {
+        self.handshake_padding = padding;
+        self
+    }
+
+    /// Sets the AEAD suites connections may be encrypted with, most preferred first. The server picks one of them according to its own preferences.
+    pub fn set_aead_suites(mut self, suites: Vec<AeadSuite>) -> Self {
+        self.aead_suites = suites;
+        self
+    }
+
+    /// Sets how TLS is set up, if TLS is used.
+    pub fn set_tls_config(mut self, cfg: TlsClientConfig) -> Self {
+        self.tls_config = cfg;
+        self
+    }
+
     /// Gets a connection out of the pool of an address.
     fn get_conn_pooled(&self, addr: SocketAddr) -> Option<(ObfsTcp, SystemTime)> {
         let mut pool = self.conn_pool.entry(addr).or_default();
@@ -86,8 +119,11 @@ impl TcpClientBackhaul {
         if let Some(pooled) = self.get_conn_pooled(addr) {
             Ok(pooled)
         } else {
-            let my_long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
-            let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let my_long_sk = self
+                .client_sk
+                .clone()
+                .unwrap_or_else(|| x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>()));
+            let my_eph_sk = x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>());
 
             let pubkey = *self
                 .dest_to_key
@@ -98,21 +134,11 @@ impl TcpClientBackhaul {
             let (mut remote_write, mut remote_read): (DynAsyncWrite, DynAsyncRead) = if self.tls {
                 let tcp = (self.connect)(addr).await?;
                 tcp.set_nodelay(true)?;
-                let connector = async_native_tls::TlsConnector::new()
-                    .danger_accept_invalid_certs(true)
-                    .danger_accept_invalid_hostnames(true)
-                    .min_protocol_version(Some(Protocol::Tlsv12))
-                    .max_protocol_version(Some(Protocol::Tlsv12))
-                    .use_sni(true);
-                let fake_domain = format!(
-                    "{}.{}.com",
-                    eff_wordlist::large::random_word(),
-                    eff_wordlist::large::random_word()
-                );
+                let sni = self.tls_config.sni.clone().unwrap_or_else(fake_domain);
                 let tls = async_dup::Arc::new(async_dup::Mutex::new(
-                    connector.connect(&fake_domain, tcp).await?,
+                    tls_connect(tcp, &sni, &self.tls_config).await?,
                 ));
-                eprintln!("*** TLS ESTABLISHED YAAAY!!!! (fake domain {fake_domain}) ***");
+                tracing::debug!("TLS established with {} (sni {})", addr, sni);
                 (Box::new(tls.clone()), Box::new(tls))
             } else {
                 let tcp = (self.connect)(addr).await?;
@@ -124,33 +150,49 @@ impl TcpClientBackhaul {
             let init_c2s = cookie.generate_c2s().next().unwrap();
             let init_s2c = cookie.generate_s2c().next().unwrap();
             let init_up_key = blake3::keyed_hash(TCP_UP_KEY, &init_c2s);
-            let init_enc = NgAead::new(init_up_key.as_bytes());
+            let init_enc = NgAead::with_suite(TCP_HELLO_AEAD, init_up_key.as_bytes());
             let to_send = HandshakeFrame::ClientHello {
                 long_pk: (&my_long_sk).into(),
                 eph_pk: (&my_eph_sk).into(),
-                version: 3,
+                version: MIN_VERSION,
             };
+            // servers that don't negotiate AEAD suites only read the hello, and ignore the rest
             let mut to_send = to_send.to_bytes();
-            let random_padding = vec![0u8; rand::random::<usize>() % 1024];
-            to_send.extend_from_slice(&random_padding);
+            to_send.extend_from_slice(
+                &HandshakeFrame::AeadOffer {
+                    suites: self.aead_suites.clone(),
+                }
+                .to_bytes(),
+            );
+            to_send.resize(to_send.len().max(self.handshake_padding.sample()), 0xff);
             let mut buf = vec![];
             write_encrypted(init_enc, &to_send, &mut buf).await?;
             remote_write.write_all(&buf).await?;
             // now we wait for a response
             let init_dn_key = blake3::keyed_hash(TCP_DN_KEY, &init_s2c);
-            let init_dec = NgAead::new(init_dn_key.as_bytes());
+            let init_dec = NgAead::with_suite(TCP_HELLO_AEAD, init_dn_key.as_bytes());
             let raw_response = read_encrypted(init_dec, &mut remote_read)
                 .await
                 .context("can't read response from server")?;
-            let actual_response = HandshakeFrame::from_bytes(&raw_response)?;
-            if let HandshakeFrame::ServerHello {
+            let response = HandshakeFrame::from_bytes_many(&raw_response);
+            // servers that don't negotiate AEAD suites keep obfuscating with a bare keystream. an attacker stripping ChosenAead can force that too, but the session inside is authenticated on its own, so this only weakens the obfuscation
+            let aead = response.iter().find_map(|frame| match frame {
+                HandshakeFrame::ChosenAead { suite } => Some(*suite),
+                _ => None,
+            });
+            if let Some(aead) = aead {
+                if !self.aead_suites.contains(&aead) {
+                    anyhow::bail!("server chose an unsupported AEAD suite")
+                }
+            }
+            if let Some(HandshakeFrame::ServerHello {
                 long_pk,
                 eph_pk,
                 resume_token: _,
-            } = actual_response
+            }) = response.into_iter().next()
             {
                 let shared_sec = triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
-                let connection = ObfsTcp::new(shared_sec, false, remote_write, remote_read);
+                let connection = ObfsTcp::new(shared_sec, false, aead, remote_write, remote_read);
                 connection.write(&self.fake_addr.to_be_bytes()).await?;
                 let down_conn = connection.clone();
                 let send_incoming = self.send_incoming.clone();
@@ -12,16 +12,30 @@ use smol::prelude::*;
 mod client;
 mod tls_helpers;
 pub use client::*;
+pub use tls_helpers::{TlsClientConfig, TlsIdentity};
+mod proxy;
+pub use proxy::*;
 mod server;
 pub use server::*;
+mod websocket;
+pub use websocket::*;
 
-use crate::{buffer::Buff, crypt::NgAead};
+use crate::{
+    buffer::Buff,
+    crypt::{AeadSuite, NgAead},
+};
 
 const CONN_LIFETIME: Duration = Duration::from_secs(600);
 
+/// Longest HTTP header block we accept, for WebSocket upgrades and proxy responses.
+const MAX_HEAD_LEN: usize = 8192;
+
 const TCP_UP_KEY: &[u8; 32] = b"uploadtcp-----------------------";
 const TCP_DN_KEY: &[u8; 32] = b"downloadtcp---------------------";
 
+/// AEAD suite the hello and its response are encrypted with, since nothing has been negotiated yet.
+const TCP_HELLO_AEAD: AeadSuite = AeadSuite::ChaCha20Poly1305;
+
 type DynAsyncWrite = Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>;
 type DynAsyncRead = Box<dyn AsyncRead + Unpin + Send + Sync + 'static>;
 
@@ -30,61 +44,114 @@ type DynAsyncRead = Box<dyn AsyncRead + Unpin + Send + Sync + 'static>;
 struct ObfsTcp {
     write: async_dup::Arc<async_dup::Mutex<DynAsyncWrite>>,
     read: async_dup::Arc<async_dup::Mutex<BufReader<DynAsyncRead>>>,
-    send_chacha: Arc<Mutex<ChaCha8>>,
-    recv_chacha: Arc<Mutex<ChaCha8>>,
+    cipher: ObfsCipher,
+}
+
+/// How an [ObfsTcp] obfuscates the stream.
+#[derive(Clone)]
+enum ObfsCipher {
+    /// A bare ChaCha8 keystream, for peers that don't negotiate an AEAD suite.
+    Legacy {
+        send_chacha: Arc<Mutex<ChaCha8>>,
+        recv_chacha: Arc<Mutex<ChaCha8>>,
+    },
+    /// Every write is a record framed like the hello, in the negotiated suite. Decrypted bytes that haven't been read yet wait in `pending`.
+    Aead {
+        send: NgAead,
+        recv: NgAead,
+        pending: Arc<Mutex<Vec<u8>>>,
+    },
 }
 
 impl ObfsTcp {
-    /// creates an ObfsTCP given a shared secret and direction
-    fn new(ss: blake3::Hash, is_server: bool, write: DynAsyncWrite, read: DynAsyncRead) -> Self {
-        let up_chacha = Arc::new(Mutex::new(
-            ChaCha8::new_var(
-                blake3::keyed_hash(TCP_UP_KEY, ss.as_bytes()).as_bytes(),
-                &[0; 8],
-            )
-            .unwrap(),
-        ));
-        let dn_chacha = Arc::new(Mutex::new(
-            ChaCha8::new_var(
-                blake3::keyed_hash(TCP_DN_KEY, ss.as_bytes()).as_bytes(),
-                &[0; 8],
-            )
-            .unwrap(),
-        ));
-        let buf_read =
-            async_dup::Arc::new(async_dup::Mutex::new(BufReader::with_capacity(65536, read)));
-        if is_server {
-            Self {
-                write: async_dup::Arc::new(async_dup::Mutex::new(write)),
-                read: buf_read,
-                send_chacha: dn_chacha,
-                recv_chacha: up_chacha,
+    /// creates an ObfsTCP given a shared secret, direction, and the AEAD suite negotiated during the hello, if any
+    fn new(
+        ss: blake3::Hash,
+        is_server: bool,
+        aead: Option<AeadSuite>,
+        write: DynAsyncWrite,
+        read: DynAsyncRead,
+    ) -> Self {
+        let up_key = blake3::keyed_hash(TCP_UP_KEY, ss.as_bytes());
+        let dn_key = blake3::keyed_hash(TCP_DN_KEY, ss.as_bytes());
+        let (send_key, recv_key) = if is_server {
+            (dn_key, up_key)
+        } else {
+            (up_key, dn_key)
+        };
+        let cipher = if let Some(aead) = aead {
+            ObfsCipher::Aead {
+                send: NgAead::with_suite(aead, send_key.as_bytes()),
+                recv: NgAead::with_suite(aead, recv_key.as_bytes()),
+                pending: Default::default(),
             }
         } else {
-            Self {
-                write: async_dup::Arc::new(async_dup::Mutex::new(write)),
-                read: buf_read,
-                send_chacha: up_chacha,
-                recv_chacha: dn_chacha,
+            ObfsCipher::Legacy {
+                send_chacha: Arc::new(Mutex::new(
+                    ChaCha8::new_var(send_key.as_bytes(), &[0; 8]).unwrap(),
+                )),
+                recv_chacha: Arc::new(Mutex::new(
+                    ChaCha8::new_var(recv_key.as_bytes(), &[0; 8]).unwrap(),
+                )),
             }
+        };
+        Self {
+            write: async_dup::Arc::new(async_dup::Mutex::new(write)),
+            read: async_dup::Arc::new(async_dup::Mutex::new(BufReader::with_capacity(65536, read))),
+            cipher,
         }
     }
 
     async fn write(&self, msg: &[u8]) -> std::io::Result<()> {
         assert!(msg.len() <= 2048);
-        let mut buf = [0u8; 2048];
-        let buf = &mut buf[..msg.len()];
-        buf.copy_from_slice(msg);
-        self.send_chacha.lock().apply_keystream(buf);
         let mut inner = self.write.clone();
-        inner.write_all(buf).await?;
+        match &self.cipher {
+            ObfsCipher::Legacy { send_chacha, .. } => {
+                let mut buf = [0u8; 2048];
+                let buf = &mut buf[..msg.len()];
+                buf.copy_from_slice(msg);
+                send_chacha.lock().apply_keystream(buf);
+                inner.write_all(buf).await?;
+            }
+            ObfsCipher::Aead { send, .. } => {
+                let mut buf = Vec::with_capacity(msg.len() + 2 * NgAead::overhead() + 2);
+                write_encrypted(send.clone(), msg, &mut buf)
+                    .await
+                    .map_err(std::io::Error::other)?;
+                inner.write_all(&buf).await?;
+            }
+        }
         inner.flush().await?;
         Ok(())
     }
 
     async fn read_exact(&self, buf: &mut [u8]) -> std::io::Result<()> {
-        self.read.lock().read_exact(buf).await?;
-        self.recv_chacha.lock().apply_keystream(buf);
+        match &self.cipher {
+            ObfsCipher::Legacy { recv_chacha, .. } => {
+                self.read.lock().read_exact(buf).await?;
+                recv_chacha.lock().apply_keystream(buf);
+            }
+            ObfsCipher::Aead { recv, pending, .. } => {
+                let mut filled = 0;
+                while filled < buf.len() {
+                    let available = pending.lock().len();
+                    if available == 0 {
+                        let record = read_encrypted(recv.clone(), &mut *self.read.lock())
+                            .await
+                            .map_err(|err| {
+                                std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+                            })?;
+                        pending.lock().extend_from_slice(&record);
+                        continue;
+                    }
+                    let mut pending = pending.lock();
+                    let take = available.min(buf.len() - filled);
+                    buf[filled..filled + take].copy_from_slice(&pending[..take]);
+                    pending.drain(..take);
+                    filled += take;
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -119,3 +186,46 @@ async fn write_encrypted<W: AsyncWrite + Unpin>(
     writer.write_all(&to_send).await?;
     Ok(())
 }
+
+/// Reads an HTTP request or response head one byte at a time, so that nothing after it is consumed. Returns its lines without the terminating empty line.
+async fn read_http_head<R: AsyncRead + Unpin>(rdr: &mut R) -> std::io::Result<Vec<String>> {
+    Ok(parse_http_head(&read_http_head_raw(rdr).await?))
+}
+
+/// Like [read_http_head], but returns the raw bytes.
+async fn read_http_head_raw<R: AsyncRead + Unpin>(rdr: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "HTTP head too long",
+            ));
+        }
+        rdr.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+    Ok(head)
+}
+
+/// Splits a raw HTTP head into lines.
+fn parse_http_head(head: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(head)
+        .split("\r\n")
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Finds the value of a header in an HTTP head, case-insensitively.
+fn find_header<'a>(head: &'a [String], name: &str) -> Option<&'a str> {
+    head.iter().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
@@ -0,0 +1,331 @@
+use smol::{net::TcpStream, prelude::*};
+use std::{io, net::SocketAddr, sync::Arc};
+
+use crate::Connector;
+
+use super::read_http_head;
+
+/// Username and password for an upstream proxy.
+#[derive(Clone)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// An upstream proxy that TCP-based protocols can connect through.
+#[derive(Clone)]
+pub enum UpstreamProxy {
+    /// An HTTP proxy supporting the CONNECT method, at the given host:port. Authentication uses the basic scheme.
+    HttpConnect {
+        addr: String,
+        auth: Option<ProxyAuth>,
+    },
+    /// A SOCKS5 proxy at the given host:port. Authentication uses username/password.
+    Socks5 {
+        addr: String,
+        auth: Option<ProxyAuth>,
+    },
+}
+
+impl UpstreamProxy {
+    /// Returns a connector that opens TCP connections through this proxy.
+    pub fn connector(&self) -> Connector {
+        let proxy = self.clone();
+        Arc::new(move |dest| {
+            let proxy = proxy.clone();
+            async move { proxy.connect(dest).await }.boxed()
+        })
+    }
+
+    /// Opens a TCP connection to the given destination through this proxy.
+    pub async fn connect(&self, dest: SocketAddr) -> io::Result<TcpStream> {
+        match self {
+            UpstreamProxy::HttpConnect { addr, auth } => {
+                let mut stream = TcpStream::connect(addr.as_str()).await?;
+                http_connect(&mut stream, dest, auth.as_ref()).await?;
+                Ok(stream)
+            }
+            UpstreamProxy::Socks5 { addr, auth } => {
+                let mut stream = TcpStream::connect(addr.as_str()).await?;
+                socks5_connect(&mut stream, dest, auth.as_ref()).await?;
+                Ok(stream)
+            }
+        }
+    }
+}
+
+fn proxy_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg)
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    dest: SocketAddr,
+    auth: Option<&ProxyAuth>,
+) -> io::Result<()> {
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", dest, dest);
+    if let Some(auth) = auth {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode(format!("{}:{}", auth.username, auth.password))
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let head = read_http_head(stream).await?;
+    let status = head.first().map(|line| line.as_str()).unwrap_or_default();
+    if status
+        .split_whitespace()
+        .nth(1)
+        .map(|code| code.starts_with('2'))
+        .unwrap_or_default()
+    {
+        Ok(())
+    } else {
+        Err(proxy_error(format!(
+            "HTTP proxy refused CONNECT: {}",
+            status
+        )))
+    }
+}
+
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    dest: SocketAddr,
+    auth: Option<&ProxyAuth>,
+) -> io::Result<()> {
+    const NO_AUTH: u8 = 0x00;
+    const USERPASS_AUTH: u8 = 0x02;
+    // greeting, offering username/password only if we have them
+    if auth.is_some() {
+        stream.write_all(&[5, 2, NO_AUTH, USERPASS_AUTH]).await?;
+    } else {
+        stream.write_all(&[5, 1, NO_AUTH]).await?;
+    }
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    match (choice, auth) {
+        ([5, NO_AUTH], _) => {}
+        ([5, USERPASS_AUTH], Some(auth)) => {
+            if auth.username.len() > 255 || auth.password.len() > 255 {
+                return Err(proxy_error("SOCKS5 credentials too long".into()));
+            }
+            let mut request = vec![1, auth.username.len() as u8];
+            request.extend_from_slice(auth.username.as_bytes());
+            request.push(auth.password.len() as u8);
+            request.extend_from_slice(auth.password.as_bytes());
+            stream.write_all(&request).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(proxy_error("SOCKS5 proxy rejected our credentials".into()));
+            }
+        }
+        _ => {
+            return Err(proxy_error(format!(
+                "SOCKS5 proxy chose an unsupported auth method {:?}",
+                choice
+            )))
+        }
+    }
+    // connect request
+    let mut request = vec![5, 1, 0];
+    match dest {
+        SocketAddr::V4(v4) => {
+            request.push(1);
+            request.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            request.push(4);
+            request.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(proxy_error(format!(
+            "SOCKS5 proxy failed to connect (reply {})",
+            reply[1]
+        )));
+    }
+    // skip the bound address, which we don't need
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        other => {
+            return Err(proxy_error(format!(
+                "SOCKS5 proxy sent unknown address type {}",
+                other
+            )))
+        }
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::find_header;
+    use smol::net::TcpListener;
+
+    const DEST: &str = "1.2.3.4:80";
+    /// Sent by the mock proxies right after their replies, to check that the client reads exactly the reply.
+    const PAYLOAD: &[u8] = b"through the proxy";
+
+    fn auth() -> ProxyAuth {
+        ProxyAuth {
+            username: "user".into(),
+            password: "hunter2".into(),
+        }
+    }
+
+    /// Starts a proxy on loopback that serves one connection with the given handler, returning its address.
+    async fn mock_proxy<F: Future<Output = ()> + Send + 'static>(
+        handler: impl FnOnce(TcpStream) -> F + Send + 'static,
+    ) -> (String, smol::Task<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let task = smol::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            handler(conn).await
+        });
+        (addr, task)
+    }
+
+    /// An HTTP proxy that wants the credentials from [auth].
+    async fn http_proxy(mut conn: TcpStream) {
+        let head = read_http_head(&mut conn).await.unwrap();
+        assert_eq!(head[0], format!("CONNECT {} HTTP/1.1", DEST));
+        let expected = format!("Basic {}", base64::encode("user:hunter2"));
+        if find_header(&head, "proxy-authorization") == Some(expected.as_str()) {
+            conn.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            conn.write_all(PAYLOAD).await.unwrap();
+        } else {
+            conn.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        }
+    }
+
+    /// A SOCKS5 proxy that wants the given credentials, if any, and replies with the given bound address.
+    async fn socks5_proxy(mut conn: TcpStream, creds: Option<ProxyAuth>, bound: &[u8]) {
+        let mut greeting = [0u8; 2];
+        conn.read_exact(&mut greeting).await.unwrap();
+        let mut methods = vec![0u8; greeting[1] as usize];
+        conn.read_exact(&mut methods).await.unwrap();
+        if let Some(creds) = creds {
+            assert!(methods.contains(&2));
+            conn.write_all(&[5, 2]).await.unwrap();
+            let mut header = [0u8; 2];
+            conn.read_exact(&mut header).await.unwrap();
+            let mut username = vec![0u8; header[1] as usize];
+            conn.read_exact(&mut username).await.unwrap();
+            let mut password_len = [0u8; 1];
+            conn.read_exact(&mut password_len).await.unwrap();
+            let mut password = vec![0u8; password_len[0] as usize];
+            conn.read_exact(&mut password).await.unwrap();
+            if username != creds.username.as_bytes() || password != creds.password.as_bytes() {
+                conn.write_all(&[1, 1]).await.unwrap();
+                return;
+            }
+            conn.write_all(&[1, 0]).await.unwrap();
+        } else {
+            assert!(methods.contains(&0));
+            conn.write_all(&[5, 0]).await.unwrap();
+        }
+        let mut request = [0u8; 10];
+        conn.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, [5, 1, 0, 1, 1, 2, 3, 4, 0, 80]);
+        conn.write_all(&[5, 0, 0]).await.unwrap();
+        conn.write_all(bound).await.unwrap();
+        conn.write_all(PAYLOAD).await.unwrap();
+    }
+
+    /// Connects through a proxy, returning what the proxy sent after its reply.
+    async fn connect_through(proxy: UpstreamProxy) -> io::Result<Vec<u8>> {
+        let mut stream = proxy.connect(DEST.parse().unwrap()).await?;
+        let mut got = vec![0u8; PAYLOAD.len()];
+        stream.read_exact(&mut got).await?;
+        Ok(got)
+    }
+
+    #[test]
+    fn http_connect() {
+        smol::block_on(async {
+            let (addr, _task) = mock_proxy(http_proxy).await;
+            let proxy = UpstreamProxy::HttpConnect {
+                addr,
+                auth: Some(auth()),
+            };
+            assert_eq!(connect_through(proxy).await.unwrap(), PAYLOAD);
+            // without credentials, the proxy answers 407
+            let (addr, _task) = mock_proxy(http_proxy).await;
+            let proxy = UpstreamProxy::HttpConnect { addr, auth: None };
+            assert!(connect_through(proxy).await.is_err());
+        })
+    }
+
+    /// A bound IPv4 address and port, as in a SOCKS5 reply.
+    const BOUND_V4: &[u8] = &[1, 10, 0, 0, 1, 0x1f, 0x90];
+
+    #[test]
+    fn socks5_no_auth() {
+        smol::block_on(async {
+            let (addr, _task) = mock_proxy(|conn| socks5_proxy(conn, None, BOUND_V4)).await;
+            let proxy = UpstreamProxy::Socks5 { addr, auth: None };
+            assert_eq!(connect_through(proxy).await.unwrap(), PAYLOAD);
+        })
+    }
+
+    #[test]
+    fn socks5_userpass() {
+        smol::block_on(async {
+            let (addr, _task) = mock_proxy(|conn| socks5_proxy(conn, Some(auth()), BOUND_V4)).await;
+            let proxy = UpstreamProxy::Socks5 {
+                addr,
+                auth: Some(auth()),
+            };
+            assert_eq!(connect_through(proxy).await.unwrap(), PAYLOAD);
+            // the wrong password is rejected
+            let (addr, _task) = mock_proxy(|conn| socks5_proxy(conn, Some(auth()), BOUND_V4)).await;
+            let proxy = UpstreamProxy::Socks5 {
+                addr,
+                auth: Some(ProxyAuth {
+                    password: "hunter3".into(),
+                    ..auth()
+                }),
+            };
+            assert!(connect_through(proxy).await.is_err());
+        })
+    }
+
+    #[test]
+    fn socks5_bound_addresses() {
+        smol::block_on(async {
+            let mut bound_domain = vec![3, 11];
+            bound_domain.extend_from_slice(b"example.com");
+            bound_domain.extend_from_slice(&[0x1f, 0x90]);
+            let mut bound_v6 = vec![4];
+            bound_v6.extend_from_slice(&[0x20; 16]);
+            bound_v6.extend_from_slice(&[0x1f, 0x90]);
+            for bound in [BOUND_V4.to_vec(), bound_domain, bound_v6] {
+                let (addr, _task) =
+                    mock_proxy(move |conn| async move { socks5_proxy(conn, None, &bound).await })
+                        .await;
+                let proxy = UpstreamProxy::Socks5 { addr, auth: None };
+                assert_eq!(connect_through(proxy).await.unwrap(), PAYLOAD);
+            }
+        })
+    }
+}
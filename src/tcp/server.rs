@@ -1,8 +1,10 @@
 use anyhow::Context;
 use dashmap::DashMap;
+use parking_lot::Mutex;
 use smol::prelude::*;
 use smol::{
     channel::{Receiver, Sender},
+    io::BufReader,
     net::{TcpListener, TcpStream},
 };
 use std::{
@@ -12,19 +14,47 @@ use std::{
     time::{Duration, Instant},
 };
 
+use smol_timeout::TimeoutExt;
+
 use crate::{
     buffer::Buff,
-    crypt::{triple_ecdh, Cookie, NgAead},
-    protocol::HandshakeFrame,
-    recfilter::RECENT_FILTER,
-    runtime, Backhaul,
+    crypt::{triple_ecdh, AeadSuite, NgAead},
+    protocol::{HandshakeFrame, HandshakePadding, MAX_VERSION, MIN_VERSION},
+    recfilter::RecentFilter,
+    runtime, Backhaul, ClientAuthorizer, KeyRing,
 };
 
 use super::{
-    tls_helpers::opportunistic_tls_serve, write_encrypted, ObfsTcp, CONN_LIFETIME, TCP_DN_KEY,
-    TCP_UP_KEY,
+    tls_helpers::{opportunistic_tls_serve, TlsIdentity},
+    websocket::websocket_serve,
+    write_encrypted, ObfsTcp, CONN_LIFETIME, TCP_DN_KEY, TCP_HELLO_AEAD, TCP_UP_KEY,
 };
 
+/// How long we wait for a new connection to send the start of its hello before handing it to the fallback.
+const FALLBACK_WAIT: Duration = Duration::from_secs(10);
+
+/// How long a connection may stall before its hello length decrypts, when there is a fallback to hand it to. Real clients send the length at once, while a short request to the fallback may stop before it.
+const FALLBACK_STALL: Duration = Duration::from_millis(500);
+
+/// Starts of HTTP requests, which we hand to the fallback as soon as we see them. An encrypted hello length starts with one of these with negligible probability.
+const HTTP_METHODS: &[&[u8; 4]] = &[
+    b"GET ", b"HEAD", b"POST", b"PUT ", b"DELE", b"CONN", b"OPTI", b"TRAC", b"PATC", b"PRI ",
+];
+
+/// Listener-wide state needed to accept TCP connections.
+#[derive(Clone)]
+pub struct TcpServerCtx {
+    pub keys: KeyRing,
+    pub client_auth: Option<ClientAuthorizer>,
+    pub recent_filter: Arc<Mutex<RecentFilter>>,
+    pub handshake_padding: HandshakePadding,
+    /// AEAD suites connections may be encrypted with, most preferred first.
+    pub aead_suites: Vec<AeadSuite>,
+    pub websocket_path: Option<String>,
+    pub tls_identity: Option<TlsIdentity>,
+    pub fallback: Option<String>,
+}
+
 /// A TCP-based backhaul, server-side.
 pub struct TcpServerBackhaul {
     down_table: Arc<DownTable>,
@@ -34,12 +64,12 @@ pub struct TcpServerBackhaul {
 
 impl TcpServerBackhaul {
     /// Creates a new TCP server-side backhaul.
-    pub fn new(listener: TcpListener, seckey: x25519_dalek::StaticSecret) -> Self {
+    pub fn new(listener: TcpListener, ctx: TcpServerCtx) -> Self {
         let down_table = Arc::new(DownTable::default());
         let table_cloned = down_table.clone();
         let (send_upcoming, recv_upcoming) = smol::channel::bounded(1000);
         let _task = runtime::spawn(async move {
-            if let Err(err) = backhaul_loop(listener, seckey, table_cloned, send_upcoming).await {
+            if let Err(err) = backhaul_loop(listener, ctx, table_cloned, send_upcoming).await {
                 tracing::debug!("backhaul_loop exited: {:?}", err)
             }
         });
@@ -68,7 +98,7 @@ impl Backhaul for TcpServerBackhaul {
 
 async fn backhaul_loop(
     listener: TcpListener,
-    seckey: x25519_dalek::StaticSecret,
+    ctx: TcpServerCtx,
     down_table: Arc<DownTable>,
     send_upcoming: Sender<(Buff, SocketAddr)>,
 ) -> anyhow::Result<()> {
@@ -77,9 +107,9 @@ async fn backhaul_loop(
         client.set_nodelay(true)?;
         let down_table = down_table.clone();
         let send_upcoming = send_upcoming.clone();
-        let seckey = seckey.clone();
+        let ctx = ctx.clone();
         smolscale::spawn(async move {
-            if let Err(err) = backhaul_one(client, seckey.clone(), down_table, send_upcoming)
+            if let Err(err) = backhaul_one(client, ctx, down_table, send_upcoming)
                 .or(async {
                     smol::Timer::after(CONN_LIFETIME * 2).await;
                     Ok(())
@@ -95,24 +125,120 @@ async fn backhaul_loop(
 
 /// handle a TCP stream
 async fn backhaul_one(
-    mut client: TcpStream,
-    seckey: x25519_dalek::StaticSecret,
+    client: TcpStream,
+    ctx: TcpServerCtx,
     down_table: Arc<DownTable>,
     send_upcoming: Sender<(Buff, SocketAddr)>,
 ) -> anyhow::Result<()> {
-    let mut client = async_dup::Arc::new(async_dup::Mutex::new(
-        opportunistic_tls_serve(client).await?,
-    ));
-
-    let cookie = Cookie::new((&seckey).into());
-    // read the initial length
-    let mut encrypted_hello_length = vec![0u8; NgAead::overhead() + 2];
-    client.read_exact(&mut encrypted_hello_length).await?;
-    for (possible_c2s, possible_s2c) in cookie.generate_c2s().zip(cookie.generate_s2c()) {
+    let mut client = BufReader::with_capacity(
+        65536,
+        opportunistic_tls_serve(client, ctx.tls_identity.as_ref()).await?,
+    );
+    if let Some(path) = &ctx.websocket_path {
+        if client.fill_buf().await?.starts_with(b"GET ") {
+            return websocket_serve(
+                client,
+                path,
+                ctx.fallback.as_deref(),
+                &down_table,
+                &send_upcoming,
+            )
+            .await;
+        }
+    }
+    let mut client = async_dup::Arc::new(async_dup::Mutex::new(client));
+
+    let mut consumed = Vec::new();
+    let (seckey, s2c_enc, long_pk, eph_pk, aead_offer) =
+        match authenticate(&mut client, &ctx, &mut consumed).await {
+            Ok(auth) => auth,
+            Err(err) => {
+                return if let Some(fallback) = &ctx.fallback {
+                    tracing::debug!("splicing to fallback after failed handshake: {:?}", err);
+                    splice_fallback(client, &consumed, fallback).await
+                } else {
+                    Err(err)
+                }
+            }
+        };
+    // now the client has passed checks. we send back a server response using the downstream key.
+    // there is an "attack" where the adversary can confuse the server and the client by replaying a different response to the client.
+    // the client will be able to decrypt this, and will establish a session with bad info.
+    // this is "fine" because the result is that the session breaks (nobody can decrypt anything), not anything leaking.
+    let my_eph_sk = x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>());
+    let response = HandshakeFrame::ServerHello {
+        long_pk: (&seckey).into(),
+        eph_pk: (&my_eph_sk).into(),
+        resume_token: Buff::new(),
+    };
+    // clients that don't offer AEAD suites get the bare keystream they expect
+    let aead = aead_offer.and_then(|offered| {
+        ctx.aead_suites
+            .iter()
+            .copied()
+            .find(|suite| offered.contains(suite))
+    });
+    let mut response = response.to_bytes();
+    if let Some(aead) = aead {
+        response.extend_from_slice(&HandshakeFrame::ChosenAead { suite: aead }.to_bytes());
+    }
+    response.resize(response.len().max(ctx.handshake_padding.sample()), 0xff);
+    write_encrypted(s2c_enc, &response, &mut client).await?;
+    let ss = triple_ecdh(&seckey, &my_eph_sk, &long_pk, &eph_pk);
+    let obfs_tcp = ObfsTcp::new(ss, true, aead, Box::new(client.clone()), Box::new(client));
+    let mut fake_addr = [0u8; 16];
+    obfs_tcp
+        .read_exact(&mut fake_addr)
+        .await
+        .context("cannot read fakeaddr")?;
+    let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::from(fake_addr)), 0);
+    backhaul_one_inner_obfs(obfs_tcp, addr, &down_table, &send_upcoming).await
+}
+
+/// Reads and checks the client's hello, before we have sent anything, returning the AEAD suites it offers along with the keys. Everything read from the client is appended to `consumed`, so that it can be replayed to the fallback if this fails.
+async fn authenticate<R: AsyncRead + Unpin>(
+    client: &mut R,
+    ctx: &TcpServerCtx,
+    consumed: &mut Vec<u8>,
+) -> anyhow::Result<(
+    x25519_dalek::StaticSecret,
+    NgAead,
+    x25519_dalek::PublicKey,
+    x25519_dalek::PublicKey,
+    Option<Vec<AeadSuite>>,
+)> {
+    // read the initial length. real clients send it right away, so with a fallback to hand probers to, waiting long for it only helps them
+    let length_len = NgAead::overhead() + 2;
+    let (wait, stall) = if ctx.fallback.is_some() {
+        (Some(FALLBACK_WAIT), Some(FALLBACK_STALL))
+    } else {
+        (None, None)
+    };
+    read_recorded(client, 1, consumed, wait).await?;
+    read_recorded(client, 3, consumed, stall).await?;
+    if HTTP_METHODS
+        .iter()
+        .any(|method| consumed.starts_with(&method[..]))
+    {
+        anyhow::bail!("got an HTTP request instead of a hello")
+    }
+    read_recorded(client, length_len - 4, consumed, stall).await?;
+    let encrypted_hello_length = consumed[..length_len].to_vec();
+    let possible_keys = ctx
+        .keys
+        .snapshot()
+        .into_iter()
+        .flat_map(|(cookie, seckey)| {
+            cookie
+                .generate_c2s()
+                .zip(cookie.generate_s2c())
+                .map(move |(c2s, s2c)| (c2s, s2c, seckey.clone()))
+        });
+    for (possible_c2s, possible_s2c, seckey) in possible_keys {
         let c2s_key = blake3::keyed_hash(TCP_UP_KEY, &possible_c2s);
-        let c2s_dec = NgAead::new(c2s_key.as_bytes());
+        let c2s_dec = NgAead::with_suite(TCP_HELLO_AEAD, c2s_key.as_bytes());
         let s2c_key = blake3::keyed_hash(TCP_DN_KEY, &possible_s2c);
-        let s2c_enc = NgAead::new(s2c_key.as_bytes());
+        let s2c_enc = NgAead::with_suite(TCP_HELLO_AEAD, s2c_key.as_bytes());
         // if we can succesfully decrypt the hello length, that's awesome! it means that we got the right up/down key
         if let Ok(hello_length) = c2s_dec.decrypt(&encrypted_hello_length) {
             let hello_length = u16::from_be_bytes(
@@ -120,47 +246,88 @@ async fn backhaul_one(
                     .try_into()
                     .context("hello length is the wrong size")?,
             ) as usize;
-            let mut encrypted_hello = vec![0u8; hello_length];
-            client.read_exact(&mut encrypted_hello).await?;
+            // the length decrypted, so this is a real client, whose hello may well span segments that get lost
+            read_recorded(client, hello_length, consumed, None).await?;
             let raw_hello = c2s_dec
-                .decrypt(&encrypted_hello)
+                .decrypt(&consumed[length_len..])
                 .context("cannot decrypt hello")?;
-            if !RECENT_FILTER.lock().check(&raw_hello) {
+            if !ctx.recent_filter.lock().check(&raw_hello) {
                 anyhow::bail!("hello failed replay check")
             }
-            let real_hello = HandshakeFrame::from_bytes(&raw_hello)?;
-            // now the client has passed checks. we send back a server response using the downstream key.
-            // there is an "attack" where the adversary can confuse the server and the client by replaying a different response to the client.
-            // the client will be able to decrypt this, and will establish a session with bad info.
-            // this is "fine" because the result is that the session breaks (nobody can decrypt anything), not anything leaking.
-            if let HandshakeFrame::ClientHello {
+            let real_hello = HandshakeFrame::from_bytes_many(&raw_hello);
+            let aead_offer = real_hello.iter().find_map(|frame| match frame {
+                HandshakeFrame::AeadOffer { suites } => Some(suites.clone()),
+                _ => None,
+            });
+            if let Some(HandshakeFrame::ClientHello {
                 long_pk,
                 eph_pk,
-                version: 3,
-            } = real_hello
+                version: MIN_VERSION..=MAX_VERSION,
+            }) = real_hello.into_iter().next()
             {
-                let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
-                let response = HandshakeFrame::ServerHello {
-                    long_pk: (&seckey).into(),
-                    eph_pk: (&my_eph_sk).into(),
-                    resume_token: Buff::new(),
-                };
-                write_encrypted(s2c_enc, &response.to_bytes(), &mut client).await?;
-                let ss = triple_ecdh(&seckey, &my_eph_sk, &long_pk, &eph_pk);
-                let obfs_tcp = ObfsTcp::new(ss, true, Box::new(client.clone()), Box::new(client));
-                let mut fake_addr = [0u8; 16];
-                obfs_tcp
-                    .read_exact(&mut fake_addr)
-                    .await
-                    .context("cannot read fakeaddr")?;
-                let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::from(fake_addr)), 0);
-                return backhaul_one_inner_obfs(obfs_tcp, addr, &down_table, &send_upcoming).await;
+                if let Some(client_auth) = &ctx.client_auth {
+                    if !client_auth(&long_pk) {
+                        anyhow::bail!("client not authorized")
+                    }
+                }
+                return Ok((seckey, s2c_enc, long_pk, eph_pk, aead_offer));
             }
         }
     }
     anyhow::bail!("could not interpret the initial handshake")
 }
 
+/// Reads exactly `len` bytes, appending them to `consumed`, failing if no bytes arrive for `stall`, if given. Bytes are appended as they arrive, so they are kept even if this fails.
+async fn read_recorded<R: AsyncRead + Unpin>(
+    client: &mut R,
+    len: usize,
+    consumed: &mut Vec<u8>,
+    stall: Option<Duration>,
+) -> std::io::Result<()> {
+    let target = consumed.len() + len;
+    let mut buf = [0u8; 4096];
+    while consumed.len() < target {
+        let to_read = (target - consumed.len()).min(buf.len());
+        let read = client.read(&mut buf[..to_read]);
+        let n = match stall {
+            Some(stall) => read
+                .timeout(stall)
+                .await
+                .ok_or(std::io::ErrorKind::TimedOut)??,
+            None => read.await?,
+        };
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        consumed.extend_from_slice(&buf[..n]);
+    }
+    Ok(())
+}
+
+/// Forwards a connection that failed the handshake to the fallback server, replaying what it has sent so far. To the client, it looks like it was talking to the fallback all along.
+pub(super) async fn splice_fallback<S: AsyncRead + AsyncWrite + Clone + Unpin>(
+    client: S,
+    consumed: &[u8],
+    fallback: &str,
+) -> anyhow::Result<()> {
+    let mut upstream = TcpStream::connect(fallback)
+        .await
+        .context("cannot connect to fallback")?;
+    upstream.write_all(consumed).await?;
+    let mut upstream_write = upstream.clone();
+    let up_loop = async {
+        smol::io::copy(client.clone(), &mut upstream_write).await?;
+        // the client is done sending, but the fallback may still be answering
+        upstream_write.close().await?;
+        smol::future::pending().await
+    };
+    let dn_loop = async {
+        smol::io::copy(upstream.clone(), &mut client.clone()).await?;
+        Ok(())
+    };
+    up_loop.race(dn_loop).await
+}
+
 /// handle an already initialized TCP stream
 async fn backhaul_one_inner_obfs(
     obfs_tcp: ObfsTcp,
@@ -206,14 +373,14 @@ async fn backhaul_one_inner_obfs(
 }
 
 #[derive(Default)]
-struct DownTable {
+pub(super) struct DownTable {
     /// maps fake IPv6 addresses (u128, 0) back through a channel to a connection actor. only keeps track of the connection that had the *latest* activity.
     mapping: DashMap<SocketAddr, (Sender<Buff>, Instant)>,
 }
 
 impl DownTable {
     /// Creates/overwrites a new entry in the table.
-    fn set(&self, addr: SocketAddr, sender: Sender<Buff>) {
+    pub fn set(&self, addr: SocketAddr, sender: Sender<Buff>) {
         if rand::random::<usize>() % self.mapping.len().max(1000) == 0 {
             self.gc()
         }
@@ -247,3 +414,123 @@ impl DownTable {
         }
     }
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::{crypt::cookie_window, tcp::TcpClientBackhaul, ListenerStats};
+
+    /// Starts a server-side backhaul on loopback, returning it with its address and public key.
+    pub(crate) async fn test_server(
+        aead_suites: Vec<AeadSuite>,
+        websocket_path: Option<String>,
+        fallback: Option<String>,
+    ) -> (TcpServerBackhaul, SocketAddr, x25519_dalek::PublicKey) {
+        let long_sk = x25519_dalek::StaticSecret::from(rand::random::<[u8; 32]>());
+        let long_pk = (&long_sk).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ctx = TcpServerCtx {
+            keys: KeyRing::new(long_sk, vec![]),
+            client_auth: None,
+            recent_filter: Arc::new(Mutex::new(RecentFilter::new(
+                1000,
+                cookie_window(),
+                Arc::new(ListenerStats::default()),
+            ))),
+            handshake_padding: HandshakePadding::default(),
+            aead_suites,
+            websocket_path,
+            tls_identity: None,
+            fallback,
+        };
+        (TcpServerBackhaul::new(listener, ctx), addr, long_pk)
+    }
+
+    /// Sends a packet each way between a client and a server.
+    async fn round_trip(client_suites: Vec<AeadSuite>, server_suites: Vec<AeadSuite>) {
+        let (server, addr, long_pk) = test_server(server_suites, None, None).await;
+        let client = TcpClientBackhaul::new(None, false)
+            .add_remote_key(addr, long_pk)
+            .set_aead_suites(client_suites);
+        let up = Buff::copy_from_slice(b"hello from the client");
+        client.send_to(up.clone(), addr).await.unwrap();
+        let (got, client_addr) = server
+            .recv_from()
+            .timeout(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(got, up);
+        // several packets per record exercise the buffering of decrypted bytes
+        for i in 0..10u8 {
+            server
+                .send_to(Buff::copy_from_slice(&[i; 100]), client_addr)
+                .await
+                .unwrap();
+        }
+        for i in 0..10u8 {
+            let (got, _) = client
+                .recv_from()
+                .timeout(Duration::from_secs(5))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&got[..], &[i; 100][..]);
+        }
+    }
+
+    #[test]
+    fn aead_round_trip() {
+        smol::block_on(round_trip(
+            vec![AeadSuite::Aes256Gcm],
+            vec![AeadSuite::ChaCha20Poly1305, AeadSuite::Aes256Gcm],
+        ))
+    }
+
+    #[test]
+    fn legacy_round_trip() {
+        // an empty offer has nothing in common with the server, which falls back to the bare keystream
+        smol::block_on(round_trip(vec![], AeadSuite::preferred()))
+    }
+
+    /// Sends a short request to a server with a fallback, returning what the fallback got and what came back.
+    async fn fallback_exchange(request: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback_addr = fallback.local_addr().unwrap();
+        let (_server, addr, _) = test_server(
+            AeadSuite::preferred(),
+            None,
+            Some(fallback_addr.to_string()),
+        )
+        .await;
+        let request_len = request.len();
+        let fallback_task = smol::spawn(async move {
+            let (mut conn, _) = fallback.accept().await.unwrap();
+            let mut got = vec![0u8; request_len];
+            conn.read_exact(&mut got).await.unwrap();
+            conn.write_all(b"HTTP/1.0 200 OK\r\n\r\nhi").await.unwrap();
+            got
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        (fallback_task.await, response)
+    }
+
+    #[test]
+    fn short_requests_reach_fallback() {
+        smol::block_on(async {
+            // HTTP requests are spliced as soon as they start, and anything else once it stalls, both well before the full wait
+            for request in [&b"GET / HTTP/1.0\r\n\r\n"[..], b"hello\n"] {
+                let (got, response) = fallback_exchange(request)
+                    .timeout(FALLBACK_WAIT / 2)
+                    .await
+                    .expect("fallback took the full wait");
+                assert_eq!(got, request);
+                assert_eq!(response, b"HTTP/1.0 200 OK\r\n\r\nhi");
+            }
+        })
+    }
+}
@@ -1,9 +1,99 @@
 use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt};
+use futures_rustls::rustls;
 use rcgen::generate_simple_self_signed;
+use rustls_pemfile::Item;
 use smol::{io::BufReader, net::TcpStream, prelude::*};
+use std::sync::Arc;
 
-/// Negotiates an *optional* TLS connection.
-pub async fn opportunistic_tls_serve(client: TcpStream) -> anyhow::Result<CompositeReadWrite> {
+/// Generates a plausible-looking random domain name.
+pub fn fake_domain() -> String {
+    format!(
+        "{}.{}.com",
+        eff_wordlist::large::random_word(),
+        eff_wordlist::large::random_word()
+    )
+}
+
+/// How TLS-based protocols set up TLS, client-side. TLS 1.2 and 1.3 are supported.
+#[derive(Clone, Default)]
+pub struct TlsClientConfig {
+    /// Server name sent in the SNI, and checked against the certificate when verifying. If this is not set, a random domain is used for every connection.
+    pub sni: Option<String>,
+    /// Whether to verify the server's certificate chain and name, like a browser would. This only makes sense if `sni` is set to a name the listener's certificate is valid for.
+    pub verify: bool,
+    /// Extra PEM-encoded root certificates to trust when verifying, for example that of a private CA.
+    pub extra_roots: Vec<Vec<u8>>,
+}
+
+/// A certificate chain and private key that TCP listeners present to TLS clients. Listeners speak TLS 1.2 and 1.3.
+#[derive(Clone)]
+pub struct TlsIdentity(Arc<rustls::ServerConfig>);
+
+impl TlsIdentity {
+    /// Loads an identity from a PEM certificate chain, leaf first, and a PEM private key in PKCS #8, PKCS #1, or SEC1 format.
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> std::io::Result<Self> {
+        let certs = rustls_pemfile::certs(&mut &cert_chain[..])?;
+        let key = rustls_pemfile::read_all(&mut &key[..])?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "no private key found")
+            })?;
+        Self::from_der(certs, key)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+    }
+
+    fn from_der(certs: Vec<Vec<u8>>, key: Vec<u8>) -> Result<Self, rustls::Error> {
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                certs.into_iter().map(rustls::Certificate).collect(),
+                rustls::PrivateKey(key),
+            )?;
+        Ok(Self(Arc::new(config)))
+    }
+
+    /// Generates a self-signed identity for a random domain.
+    fn self_signed() -> anyhow::Result<Self> {
+        let names = vec![format!(
+            "{}{}.com",
+            eff_wordlist::large::random_word(),
+            eff_wordlist::large::random_word()
+        )];
+        let cert = generate_simple_self_signed(names)?;
+        Ok(Self::from_der(
+            vec![cert.serialize_der()?],
+            cert.serialize_private_key_der(),
+        )?)
+    }
+}
+
+/// Establishes a client-side TLS connection.
+pub async fn tls_connect(
+    tcp: TcpStream,
+    sni: &str,
+    cfg: &TlsClientConfig,
+) -> anyhow::Result<async_native_tls::TlsStream<TcpStream>> {
+    let mut connector = async_native_tls::TlsConnector::new()
+        .danger_accept_invalid_certs(!cfg.verify)
+        .danger_accept_invalid_hostnames(!cfg.verify)
+        .min_protocol_version(Some(native_tls::Protocol::Tlsv12))
+        .use_sni(true);
+    for pem in cfg.extra_roots.iter() {
+        connector = connector.add_root_certificate(native_tls::Certificate::from_pem(pem)?);
+    }
+    Ok(connector.connect(sni, tcp).await?)
+}
+
+/// Negotiates an *optional* TLS connection. Without an identity, a self-signed certificate for a random domain is used.
+pub async fn opportunistic_tls_serve(
+    client: TcpStream,
+    identity: Option<&TlsIdentity>,
+) -> anyhow::Result<CompositeReadWrite> {
     let mut client_up = BufReader::with_capacity(65536, client.clone());
     let initial = client_up.fill_buf().await?;
     // Checks to see whether the initial bit looks like a clienthello at all
@@ -20,18 +110,11 @@ pub async fn opportunistic_tls_serve(client: TcpStream) -> anyhow::Result<Compos
             reader: Box::new(client_up),
             writer: Box::new(client),
         };
-        let names = vec![format!(
-            "{}{}.com",
-            eff_wordlist::large::random_word(),
-            eff_wordlist::large::random_word()
-        )];
-        let cert = generate_simple_self_signed(names)?;
-        let cert_pem = cert.serialize_pem()?;
-        let cert_key = cert.serialize_private_key_pem();
-        let identity = native_tls::Identity::from_pkcs8(cert_pem.as_bytes(), cert_key.as_bytes())
-            .expect("wtf cannot decode id???");
-        let acceptor: async_native_tls::TlsAcceptor =
-            native_tls::TlsAcceptor::new(identity)?.into();
+        let identity = match identity {
+            Some(identity) => identity.clone(),
+            None => TlsIdentity::self_signed()?,
+        };
+        let acceptor = futures_rustls::TlsAcceptor::from(identity.0);
         let client = acceptor.accept(composite).await?;
         let client = async_dup::Arc::new(async_dup::Mutex::new(client));
         Ok(CompositeReadWrite {
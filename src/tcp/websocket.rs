@@ -0,0 +1,617 @@
+use anyhow::Context;
+use dashmap::DashMap;
+use smol::{
+    channel::{Receiver, Sender},
+    io::BufReader,
+    lock::Mutex,
+    prelude::*,
+};
+use smol_timeout::TimeoutExt;
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{buffer::Buff, runtime, Backhaul, Connector};
+
+use super::{
+    find_header, parse_http_head, read_http_head, read_http_head_raw,
+    server::{splice_fallback, DownTable},
+    tls_helpers::{fake_domain, tls_connect, TlsClientConfig},
+    DynAsyncRead, DynAsyncWrite, CONN_LIFETIME,
+};
+
+/// Longest WebSocket message we accept. Sosistab packets are much shorter than this.
+const MAX_MESSAGE_LEN: usize = 65536;
+
+/// How long writing a datagram to a connection may take before the connection is considered stuck and closed. A datagram held up longer than this is useless anyway.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Appended to the client's key to compute the server's accept key, as specified by RFC 6455.
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// A pooled client connection.
+#[derive(Clone)]
+struct WsConn {
+    writer: Arc<Mutex<DynAsyncWrite>>,
+    /// The TCP connection underneath, to close the whole thing with, including the task reading from it.
+    tcp: smol::net::TcpStream,
+}
+
+/// A WebSocket-based backhaul, client-side. Every datagram travels as one binary message, so this works through CDNs and HTTP proxies that pass WebSockets through.
+pub struct WsClientBackhaul {
+    conn_pool: DashMap<SocketAddr, VecDeque<(WsConn, SystemTime)>>,
+    fake_addr: u128,
+    incoming: Receiver<(Buff, SocketAddr)>,
+    send_incoming: Sender<(Buff, SocketAddr)>,
+
+    connect: Connector,
+    tls: bool,
+    host: Option<String>,
+    path: String,
+    tls_config: TlsClientConfig,
+}
+
+impl WsClientBackhaul {
+    /// Creates a new WebSocket client backhaul.
+    pub fn new(connect: Option<Connector>, tls: bool) -> Self {
+        let (send_incoming, incoming) = smol::channel::unbounded();
+        Self {
+            conn_pool: Default::default(),
+            fake_addr: rand::random(),
+            incoming,
+            send_incoming,
+            connect: connect.unwrap_or_else(move || {
+                Arc::new(move |addr| smol::net::TcpStream::connect(addr).boxed())
+            }),
+            tls,
+            host: None,
+            path: "/".into(),
+            tls_config: TlsClientConfig::default(),
+        }
+    }
+
+    /// Sets the Host header, which is also the SNI when using TLS unless the TLS configuration sets one. Otherwise, a random domain is used for every connection.
+    pub fn set_host(mut self, host: String) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// Sets the path to upgrade at.
+    pub fn set_path(mut self, path: String) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Sets how TLS is set up, if TLS is used.
+    pub fn set_tls_config(mut self, cfg: TlsClientConfig) -> Self {
+        self.tls_config = cfg;
+        self
+    }
+
+    /// Gets a connection out of the pool of an address.
+    fn get_conn_pooled(&self, addr: SocketAddr) -> Option<(WsConn, SystemTime)> {
+        let mut pool = self.conn_pool.entry(addr).or_default();
+        while let Some((conn, time)) = pool.pop_front() {
+            if let Ok(age) = time.elapsed() {
+                if age < CONN_LIFETIME {
+                    return Some((conn, time));
+                }
+            }
+        }
+        None
+    }
+
+    /// Puts a connection back into the pool of an address.
+    fn put_conn(&self, addr: SocketAddr, conn: WsConn, time: SystemTime) {
+        let mut pool = self.conn_pool.entry(addr).or_default();
+        pool.push_back((conn, time));
+    }
+
+    /// Opens a connection or gets a connection from the pool.
+    async fn get_conn(&self, addr: SocketAddr) -> anyhow::Result<(WsConn, SystemTime)> {
+        if let Some(pooled) = self.get_conn_pooled(addr) {
+            return Ok(pooled);
+        }
+        let host = self
+            .host
+            .clone()
+            .or_else(|| self.tls_config.sni.clone())
+            .unwrap_or_else(fake_domain);
+        let sni = self.tls_config.sni.clone().unwrap_or_else(|| host.clone());
+        let tcp = (self.connect)(addr).await?;
+        tcp.set_nodelay(true)?;
+        let (mut remote_write, remote_read): (DynAsyncWrite, DynAsyncRead) = if self.tls {
+            let tls = async_dup::Arc::new(async_dup::Mutex::new(
+                tls_connect(tcp.clone(), &sni, &self.tls_config).await?,
+            ));
+            (Box::new(tls.clone()), Box::new(tls))
+        } else {
+            (Box::new(tcp.clone()), Box::new(tcp.clone()))
+        };
+        let mut remote_read = BufReader::with_capacity(65536, remote_read);
+
+        // do the HTTP upgrade
+        let key = base64::encode(rand::random::<[u8; 16]>());
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            self.path, host, key
+        );
+        remote_write.write_all(request.as_bytes()).await?;
+        remote_write.flush().await?;
+        let head = read_http_head(&mut remote_read)
+            .await
+            .context("can't read upgrade response from server")?;
+        if !head
+            .first()
+            .map(|status| status.split_whitespace().nth(1) == Some("101"))
+            .unwrap_or_default()
+        {
+            anyhow::bail!("server refused upgrade: {:?}", head.first());
+        }
+        if find_header(&head, "sec-websocket-accept") != Some(&accept_key(&key)) {
+            anyhow::bail!("server sent a bad accept key")
+        }
+
+        // the first message tells the server who we are, across connections
+        let writer = Arc::new(Mutex::new(remote_write));
+        write_frame(
+            &mut *writer.lock().await,
+            OP_BINARY,
+            &self.fake_addr.to_be_bytes(),
+            true,
+        )
+        .await?;
+
+        // spawn a thread that reads from the connection
+        let send_incoming = self.send_incoming.clone();
+        let pong_writer = writer.clone();
+        let mut remote_read = MessageReader::new(remote_read);
+        runtime::spawn(async move {
+            let main = async {
+                loop {
+                    let (opcode, msg) = remote_read.read_message().await?;
+                    match opcode {
+                        OP_BINARY => {
+                            let _ = send_incoming.try_send((Buff::copy_from_slice(&msg), addr));
+                        }
+                        OP_PING => {
+                            write_frame(&mut *pong_writer.lock().await, OP_PONG, &msg, true).await?
+                        }
+                        OP_CLOSE => anyhow::bail!("server closed the websocket"),
+                        _ => {}
+                    }
+                }
+            };
+            let _: anyhow::Result<()> = main
+                .or(async {
+                    smol::Timer::after(CONN_LIFETIME).await;
+                    Ok(())
+                })
+                .await;
+        })
+        .detach();
+
+        Ok((WsConn { writer, tcp }, SystemTime::now()))
+    }
+}
+
+#[async_trait::async_trait]
+impl Backhaul for WsClientBackhaul {
+    async fn send_to(&self, to_send: Buff, dest: SocketAddr) -> std::io::Result<()> {
+        let res: anyhow::Result<()> = async {
+            let (conn, time) = self
+                .get_conn(dest)
+                .timeout(Duration::from_secs(10))
+                .await
+                .ok_or_else(|| anyhow::anyhow!("timeout"))??;
+            let written = async {
+                write_frame(&mut *conn.writer.lock().await, OP_BINARY, &to_send, true).await
+            }
+            .timeout(WRITE_TIMEOUT)
+            .await
+            .unwrap_or_else(|| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "websocket write timed out",
+                ))
+            });
+            if let Err(err) = written {
+                // the write may have stopped halfway through a frame, after which nothing else can be sent on the connection
+                let _ = conn.tcp.shutdown(std::net::Shutdown::Both);
+                anyhow::bail!("throwing websocket connection away: {:?}", err)
+            }
+            self.put_conn(dest, conn, time);
+            Ok(())
+        }
+        .await;
+
+        if let Err(err) = res {
+            tracing::debug!("error in WsClientBackhaul: {:?}", err);
+        }
+
+        Ok(())
+    }
+
+    async fn recv_from(&self) -> std::io::Result<(Buff, SocketAddr)> {
+        Ok(self.incoming.recv().await.unwrap())
+    }
+}
+
+/// Handles a connection that made an HTTP request to a TCP listener. If it upgrades to a WebSocket at the right path, it becomes a backhaul connection; everything else is handed to the fallback, or gets a 404 if there is none.
+pub(super) async fn websocket_serve<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    client: BufReader<S>,
+    path: &str,
+    fallback: Option<&str>,
+    down_table: &DownTable,
+    send_upcoming: &Sender<(Buff, SocketAddr)>,
+) -> anyhow::Result<()> {
+    let client = async_dup::Arc::new(async_dup::Mutex::new(client));
+    let mut reader = client.clone();
+    let raw_head = read_http_head_raw(&mut reader).await?;
+    let mut reader = MessageReader::new(reader);
+    let head = parse_http_head(&raw_head);
+    let request_line: Vec<&str> = head
+        .first()
+        .map(|line| line.split_whitespace().collect())
+        .unwrap_or_default();
+    let key = find_header(&head, "sec-websocket-key");
+    let is_upgrade = find_header(&head, "upgrade")
+        .map(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+        .unwrap_or_default();
+    let mut writer = client.clone();
+    let key = match key {
+        Some(key) if request_line.get(1) == Some(&path) && is_upgrade => key,
+        _ if fallback.is_some() => {
+            tracing::debug!(
+                "splicing non-websocket request to fallback: {:?}",
+                head.first()
+            );
+            return splice_fallback(client, &raw_head, fallback.unwrap()).await;
+        }
+        _ => {
+            writer
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+            writer.flush().await?;
+            anyhow::bail!(
+                "not a websocket upgrade to the right path: {:?}",
+                head.first()
+            )
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    writer.write_all(response.as_bytes()).await?;
+    writer.flush().await?;
+    let writer = Mutex::new(writer);
+
+    // the first message is the client's fake address
+    let (opcode, fake_addr) = reader.read_message().await?;
+    let fake_addr: [u8; 16] = fake_addr
+        .as_slice()
+        .try_into()
+        .ok()
+        .filter(|_| opcode == OP_BINARY)
+        .context("cannot read fakeaddr")?;
+    let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::from(fake_addr)), 0);
+
+    let (send_down, recv_down) = smol::channel::bounded(100);
+    let up_loop = async {
+        loop {
+            down_table.set(addr, send_down.clone());
+            let (opcode, msg) = reader.read_message().await?;
+            match opcode {
+                OP_BINARY => {
+                    send_upcoming
+                        .send((Buff::copy_from_slice(&msg), addr))
+                        .await?
+                }
+                OP_PING => write_frame(&mut *writer.lock().await, OP_PONG, &msg, false).await?,
+                OP_CLOSE => break Ok(()),
+                _ => {}
+            }
+        }
+    };
+    let dn_loop = async {
+        loop {
+            let down: Buff = recv_down.recv().await?;
+            write_frame(&mut *writer.lock().await, OP_BINARY, &down, false).await?;
+        }
+    };
+    up_loop.race(dn_loop).await
+}
+
+/// Computes the Sec-WebSocket-Accept value for a Sec-WebSocket-Key.
+fn accept_key(key: &str) -> String {
+    base64::encode(
+        sha1_smol::Sha1::from(format!("{}{}", key, WS_GUID))
+            .digest()
+            .bytes(),
+    )
+}
+
+/// Writes a single unfragmented frame. Clients must mask their frames; servers must not.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+    masked: bool,
+) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    let mask_bit = if masked { 0x80 } else { 0 };
+    if payload.len() < 126 {
+        frame.push(mask_bit | payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(mask_bit | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(mask_bit | 127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    if masked {
+        let mask: [u8; 4] = rand::random();
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    } else {
+        frame.extend_from_slice(payload);
+    }
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Reads messages off a connection, reassembling fragmented ones.
+struct MessageReader<R> {
+    inner: R,
+    /// Opcode and payload so far of a fragmented message, which control frames may interrupt.
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            partial: None,
+        }
+    }
+
+    /// Reads a message. Control frames are returned as they come, even in the middle of a fragmented message, which the next call carries on with.
+    async fn read_message(&mut self) -> anyhow::Result<(u8, Vec<u8>)> {
+        let rdr = &mut self.inner;
+        loop {
+            let mut header = [0u8; 2];
+            rdr.read_exact(&mut header).await?;
+            let fin = header[0] & 0x80 != 0;
+            let opcode = header[0] & 0x0f;
+            let masked = header[1] & 0x80 != 0;
+            let length = match header[1] & 0x7f {
+                126 => {
+                    let mut buf = [0u8; 2];
+                    rdr.read_exact(&mut buf).await?;
+                    u16::from_be_bytes(buf) as usize
+                }
+                127 => {
+                    let mut buf = [0u8; 8];
+                    rdr.read_exact(&mut buf).await?;
+                    u64::from_be_bytes(buf).try_into().unwrap_or(usize::MAX)
+                }
+                length => length as usize,
+            };
+            let so_far = match &self.partial {
+                Some((_, msg)) if opcode < OP_CLOSE => msg.len(),
+                _ => 0,
+            };
+            if length.saturating_add(so_far) > MAX_MESSAGE_LEN {
+                anyhow::bail!("websocket message too long ({})", length)
+            }
+            let mut mask = [0u8; 4];
+            if masked {
+                rdr.read_exact(&mut mask).await?;
+            }
+            let mut payload = vec![0u8; length];
+            rdr.read_exact(&mut payload).await?;
+            if masked {
+                payload
+                    .iter_mut()
+                    .enumerate()
+                    .for_each(|(i, b)| *b ^= mask[i % 4]);
+            }
+            if opcode >= OP_CLOSE {
+                return Ok((opcode, payload));
+            }
+            let (_, msg) = if opcode == OP_CONTINUATION {
+                self.partial
+                    .as_mut()
+                    .context("continuation frame without a message")?
+            } else {
+                self.partial.insert((opcode, Vec::new()))
+            };
+            msg.extend_from_slice(&payload);
+            if fin {
+                return Ok(self.partial.take().unwrap());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::AeadSuite;
+    use smol::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn accept_key_matches_rfc() {
+        // the example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        smol::block_on(async {
+            // the largest with a 7-bit length, the smallest with a 16-bit one, and one needing a 64-bit one
+            for (len, length_field) in [(125, 125), (126, 126), (65536, 127)] {
+                for masked in [false, true] {
+                    let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                    let mut frame = Vec::new();
+                    write_frame(&mut frame, OP_BINARY, &payload, masked)
+                        .await
+                        .unwrap();
+                    assert_eq!(frame[1] & 0x7f, length_field);
+                    assert_eq!(frame[1] & 0x80 != 0, masked);
+                    let got = MessageReader::new(&frame[..]).read_message().await.unwrap();
+                    assert_eq!(got, (OP_BINARY, payload));
+                }
+            }
+        })
+    }
+
+    /// Encodes a frame by hand, with control over the FIN bit.
+    fn raw_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode, payload.len() as u8];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn fragments_reassemble_around_pings() {
+        smol::block_on(async {
+            let stream = [
+                raw_frame(false, OP_BINARY, b"hello "),
+                raw_frame(true, OP_PING, b"ping"),
+                raw_frame(false, OP_CONTINUATION, b"fragmented "),
+                raw_frame(true, OP_CONTINUATION, b"world"),
+            ]
+            .concat();
+            let mut reader = MessageReader::new(&stream[..]);
+            assert_eq!(
+                reader.read_message().await.unwrap(),
+                (OP_PING, b"ping".to_vec())
+            );
+            assert_eq!(
+                reader.read_message().await.unwrap(),
+                (OP_BINARY, b"hello fragmented world".to_vec())
+            );
+        })
+    }
+
+    #[test]
+    fn long_messages_rejected() {
+        smol::block_on(async {
+            let mut frame = Vec::new();
+            write_frame(&mut frame, OP_BINARY, &[0; MAX_MESSAGE_LEN + 1], false)
+                .await
+                .unwrap();
+            assert!(MessageReader::new(&frame[..]).read_message().await.is_err());
+            // fragments can't get around the limit
+            let half = [0; MAX_MESSAGE_LEN / 2 + 1];
+            let mut stream = Vec::new();
+            write_frame(&mut stream, OP_BINARY, &half, false)
+                .await
+                .unwrap();
+            stream[0] &= 0x7f;
+            write_frame(&mut stream, OP_CONTINUATION, &half, false)
+                .await
+                .unwrap();
+            assert!(MessageReader::new(&stream[..])
+                .read_message()
+                .await
+                .is_err());
+        })
+    }
+
+    #[test]
+    fn client_round_trip() {
+        smol::block_on(async {
+            let (server, addr, _) = super::super::server::tests::test_server(
+                AeadSuite::preferred(),
+                Some("/ws".into()),
+                None,
+            )
+            .await;
+            let client = WsClientBackhaul::new(None, false).set_path("/ws".into());
+            let up = Buff::copy_from_slice(b"hello from the client");
+            client.send_to(up.clone(), addr).await.unwrap();
+            let (got, client_addr) = server
+                .recv_from()
+                .timeout(Duration::from_secs(5))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(got, up);
+            let down = Buff::copy_from_slice(b"hello from the server");
+            server.send_to(down.clone(), client_addr).await.unwrap();
+            let (got, _) = client
+                .recv_from()
+                .timeout(Duration::from_secs(5))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(got, down);
+        })
+    }
+
+    /// Makes a plain HTTP request to a websocket server, returning the response.
+    async fn plain_request(fallback: Option<String>) -> Vec<u8> {
+        let (_server, addr, _) = super::super::server::tests::test_server(
+            AeadSuite::preferred(),
+            Some("/ws".into()),
+            fallback,
+        )
+        .await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client
+            .read_to_end(&mut response)
+            .timeout(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        response
+    }
+
+    #[test]
+    fn plain_requests_reach_fallback() {
+        smol::block_on(async {
+            let response = plain_request(None).await;
+            assert!(response.starts_with(b"HTTP/1.1 404 Not Found"));
+
+            let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let fallback_addr = fallback.local_addr().unwrap();
+            let _fallback_task = smol::spawn(async move {
+                let (mut conn, _) = fallback.accept().await.unwrap();
+                let head = read_http_head_raw(&mut conn).await.unwrap();
+                assert!(head.starts_with(b"GET /index.html"));
+                conn.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+                )
+                .await
+                .unwrap();
+            });
+            let response = plain_request(Some(fallback_addr.to_string())).await;
+            assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+            assert!(response.ends_with(b"hi"));
+        })
+    }
+}
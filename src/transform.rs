@@ -0,0 +1,90 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use crate::{
+    backhaul::Backhaul,
+    buffer::{Buff, BuffMut},
+};
+
+mod quic;
+pub use quic::*;
+
+/// A reversible transformation applied to every packet sent and received over a backhaul. This is used to disguise sosistab traffic, for example by making packets look like those of another protocol.
+///
+/// Transforms should keep packets small: UDP packets longer than 1472 bytes are dropped, and sosistab itself sends packets of up to 1400 bytes.
+pub trait PacketTransform: Send + Sync + 'static {
+    /// Transforms an outgoing packet.
+    fn encode(&self, pkt: Buff, dest: SocketAddr) -> Buff;
+
+    /// Reverses [PacketTransform::encode] on an incoming packet. Returns None to drop the packet.
+    fn decode(&self, pkt: Buff, src: SocketAddr) -> Option<Buff>;
+}
+
+/// A transform that prepends a fixed header to every packet, dropping incoming packets that don't start with it.
+pub struct HeaderTransform {
+    header: Buff,
+}
+
+impl HeaderTransform {
+    /// Creates a new header transform.
+    pub fn new(header: &[u8]) -> Self {
+        Self {
+            header: Buff::copy_from_slice(header),
+        }
+    }
+}
+
+impl PacketTransform for HeaderTransform {
+    fn encode(&self, pkt: Buff, _dest: SocketAddr) -> Buff {
+        let mut out = BuffMut::new();
+        out.extend_from_slice(&self.header);
+        out.extend_from_slice(&pkt);
+        out.freeze()
+    }
+
+    fn decode(&self, pkt: Buff, _src: SocketAddr) -> Option<Buff> {
+        if pkt.starts_with(&self.header) {
+            Some(pkt.slice(self.header.len()..))
+        } else {
+            None
+        }
+    }
+}
+
+/// A structure that wraps a Backhaul with a stack of packet transforms. Outgoing packets go through the transforms in order, and incoming packets in reverse order.
+pub struct TransformBackhaul<B: Backhaul + ?Sized + 'static> {
+    haul: Arc<B>,
+    transforms: Vec<Arc<dyn PacketTransform>>,
+}
+
+impl<B: Backhaul + ?Sized + 'static> TransformBackhaul<B> {
+    /// Wraps a backhaul with the given transforms.
+    pub fn new(haul: Arc<B>, transforms: Vec<Arc<dyn PacketTransform>>) -> Self {
+        Self { haul, transforms }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backhaul + ?Sized + 'static> Backhaul for TransformBackhaul<B> {
+    async fn send_to(&self, to_send: Buff, dest: SocketAddr) -> io::Result<()> {
+        let to_send = self
+            .transforms
+            .iter()
+            .fold(to_send, |pkt, transform| transform.encode(pkt, dest));
+        self.haul.send_to(to_send, dest).await
+    }
+
+    async fn recv_from(&self) -> io::Result<(Buff, SocketAddr)> {
+        loop {
+            let (bts, addr) = self.haul.recv_from().await?;
+            let decoded = self
+                .transforms
+                .iter()
+                .rev()
+                .try_fold(bts, |pkt, transform| transform.decode(pkt, addr));
+            if let Some(bts) = decoded {
+                return Ok((bts, addr));
+            }
+            tracing::trace!("dropping packet from {} rejected by a transform", addr);
+        }
+    }
+}
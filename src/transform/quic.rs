@@ -0,0 +1,249 @@
+use std::{net::SocketAddr, time::Duration};
+
+use moka::sync::Cache;
+use rand::prelude::*;
+
+use crate::buffer::{Buff, BuffMut};
+
+use super::PacketTransform;
+
+/// QUIC version 1.
+const QUIC_V1: u32 = 1;
+
+/// Length of the connection IDs we use. Short headers don't carry the length, so both sides must agree on it.
+const CID_LEN: usize = 8;
+
+/// Datagrams carrying Initial packets are padded to at least this length, as QUIC requires of both clients and servers (RFC 9000, section 14.1).
+const MIN_INITIAL_LEN: usize = 1200;
+
+/// What we remember about a peer.
+#[derive(Clone, Copy)]
+struct PeerState {
+    /// Whether to send short-header packets. Clients switch once they hear from the server, and servers once they get a short-header packet.
+    established: bool,
+    /// Destination connection ID for packets to the peer.
+    peer_cid: [u8; CID_LEN],
+    /// Our connection ID towards the peer, which it uses as the destination connection ID. Every peer gets its own, like separate QUIC connections.
+    my_cid: [u8; CID_LEN],
+}
+
+/// A transform that gives every packet a QUIC v1 header, for networks that block UDP they don't recognize. Each side sends Initial long-header packets until it hears back, then short-header packets, with per-connection IDs and randomized header bits.
+///
+/// Only the headers are mimicked. Payloads are sosistab packets rather than TLS, and there are no Handshake packets, so anything that decrypts Initial packets or follows the QUIC handshake can tell. [Protocol::Quic](crate::Protocol::Quic) uses this on the client; listeners must add [QuicTransform::server] as the *last* of their transforms.
+pub struct QuicTransform {
+    is_server: bool,
+    peers: Cache<SocketAddr, PeerState>,
+}
+
+impl QuicTransform {
+    /// Creates a client-side QUIC transform.
+    pub fn client() -> Self {
+        Self::new(false)
+    }
+
+    /// Creates a listener-side QUIC transform.
+    pub fn server() -> Self {
+        Self::new(true)
+    }
+
+    fn new(is_server: bool) -> Self {
+        Self {
+            is_server,
+            peers: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_idle(Duration::from_secs(3600))
+                .build(),
+        }
+    }
+
+    fn peer(&self, addr: SocketAddr) -> PeerState {
+        self.peers.get(&addr).unwrap_or_else(|| PeerState {
+            established: false,
+            peer_cid: rand::random(),
+            my_cid: rand::random(),
+        })
+    }
+}
+
+impl PacketTransform for QuicTransform {
+    fn encode(&self, pkt: Buff, dest: SocketAddr) -> Buff {
+        let peer = self.peer(dest);
+        self.peers.insert(dest, peer);
+        let mut rng = rand::thread_rng();
+        // in real QUIC, the low bits of the first byte and the packet number are masked by header protection, so they look random
+        let pn_len = rng.gen_range(1, 5);
+        let mut out = BuffMut::new();
+        if peer.established {
+            out.push(0x40 | (rng.gen::<u8>() & 0x1c) | (pn_len as u8 - 1));
+            out.extend_from_slice(&peer.peer_cid);
+        } else {
+            out.push(0xc0 | (rng.gen::<u8>() & 0x0c) | (pn_len as u8 - 1));
+            out.extend_from_slice(&QUIC_V1.to_be_bytes());
+            out.push(CID_LEN as u8);
+            out.extend_from_slice(&peer.peer_cid);
+            out.push(CID_LEN as u8);
+            out.extend_from_slice(&peer.my_cid);
+            // empty token
+            out.push(0);
+            // two-byte varint length, covering the packet number and payload
+            let length = (pn_len + pkt.len()) as u16;
+            out.extend_from_slice(&(0x4000 | length).to_be_bytes());
+        }
+        for _ in 0..pn_len {
+            out.push(rng.gen());
+        }
+        out.extend_from_slice(&pkt);
+        if !peer.established && out.len() < MIN_INITIAL_LEN {
+            out.resize(MIN_INITIAL_LEN, 0);
+        }
+        out.freeze()
+    }
+
+    fn decode(&self, pkt: Buff, src: SocketAddr) -> Option<Buff> {
+        let first = *pkt.first()?;
+        if first & 0x40 == 0 {
+            return None;
+        }
+        let pn_len = (first & 0x03) as usize + 1;
+        if first & 0x80 == 0 {
+            // short header
+            let start = 1 + CID_LEN + pn_len;
+            if pkt.len() < start {
+                return None;
+            }
+            if self.is_server {
+                let mut peer = self.peer(src);
+                if !peer.established {
+                    peer.established = true;
+                    self.peers.insert(src, peer);
+                }
+            }
+            return Some(pkt.slice(start..));
+        }
+        // long header, which must be an Initial
+        if first & 0x30 != 0 || pkt.get(1..5)? != QUIC_V1.to_be_bytes() {
+            return None;
+        }
+        let mut pos = 5;
+        let dcid_len = *pkt.get(pos)? as usize;
+        pos += 1 + dcid_len;
+        let scid_len = *pkt.get(pos)? as usize;
+        let scid = pkt.get(pos + 1..pos + 1 + scid_len)?;
+        pos += 1 + scid_len;
+        let (token_len, varint_len) = read_varint(pkt.get(pos..)?)?;
+        pos += varint_len + token_len as usize;
+        let (length, varint_len) = read_varint(pkt.get(pos..)?)?;
+        pos += varint_len;
+        let end = pos.checked_add(length as usize)?;
+        if dcid_len > 20 || scid_len != CID_LEN || pn_len > length as usize || end > pkt.len() {
+            return None;
+        }
+        let mut peer = self.peer(src);
+        peer.peer_cid.copy_from_slice(scid);
+        // clients are done with Initials once the server answers
+        peer.established |= !self.is_server;
+        self.peers.insert(src, peer);
+        // anything after the packet is padding
+        Some(pkt.slice(pos + pn_len..end))
+    }
+}
+
+/// Reads a QUIC variable-length integer, returning it and how many bytes it took.
+fn read_varint(bts: &[u8]) -> Option<(u64, usize)> {
+    let len = 1 << (bts.first()? >> 6);
+    let bts = bts.get(..len)?;
+    let value = bts[1..]
+        .iter()
+        .fold((bts[0] & 0x3f) as u64, |acc, b| (acc << 8) | *b as u64);
+    Some((value, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_long(pkt: &[u8]) -> bool {
+        pkt[0] & 0x80 != 0
+    }
+
+    /// The source connection ID of a long-header packet.
+    fn scid(pkt: &[u8]) -> &[u8] {
+        &pkt[7 + CID_LEN..7 + 2 * CID_LEN]
+    }
+
+    #[test]
+    fn round_trip() {
+        let client = QuicTransform::client();
+        let server = QuicTransform::server();
+        let client_addr: SocketAddr = "10.0.0.2:1234".parse().unwrap();
+        let server_addr: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let payload = Buff::copy_from_slice(b"hello");
+        // both sides start with Initials padded to the minimum length
+        let hello = client.encode(payload.clone(), server_addr);
+        assert!(is_long(&hello) && hello.len() >= MIN_INITIAL_LEN);
+        assert_eq!(server.decode(hello, client_addr).unwrap(), payload);
+        let reply = server.encode(payload.clone(), client_addr);
+        assert!(is_long(&reply) && reply.len() >= MIN_INITIAL_LEN);
+        let server_cid = scid(&reply).to_vec();
+        assert_eq!(client.decode(reply, server_addr).unwrap(), payload);
+        // the client then switches to short headers addressed to the server's connection ID
+        let short = client.encode(payload.clone(), server_addr);
+        assert!(!is_long(&short));
+        assert_eq!(&short[1..1 + CID_LEN], &server_cid[..]);
+        assert_eq!(server.decode(short, client_addr).unwrap(), payload);
+        // and so does the server, once it hears a short header
+        let short = server.encode(payload.clone(), client_addr);
+        assert!(!is_long(&short));
+        assert_eq!(client.decode(short, server_addr).unwrap(), payload);
+    }
+
+    #[test]
+    fn connection_ids_per_peer() {
+        let server = QuicTransform::server();
+        let first = server.encode(Buff::new(), "10.0.0.2:1".parse().unwrap());
+        let second = server.encode(Buff::new(), "10.0.0.3:1".parse().unwrap());
+        assert_ne!(scid(&first), scid(&second));
+        // but stay the same towards one peer
+        let again = server.encode(Buff::new(), "10.0.0.2:1".parse().unwrap());
+        assert_eq!(scid(&first), scid(&again));
+    }
+
+    #[test]
+    fn rejects_non_quic() {
+        let server = QuicTransform::server();
+        let addr: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        assert!(server.decode(Buff::new(), addr).is_none());
+        // fixed bit unset
+        assert!(server
+            .decode(Buff::copy_from_slice(&[0x00; 100]), addr)
+            .is_none());
+        // long header with another version
+        let mut hello = QuicTransform::client().encode(Buff::new(), addr).to_vec();
+        hello[4] = 2;
+        assert!(server.decode(Buff::copy_from_slice(&hello), addr).is_none());
+        // truncated long header
+        assert!(server
+            .decode(Buff::copy_from_slice(&hello[..10]), addr)
+            .is_none());
+    }
+
+    #[test]
+    fn varint_examples() {
+        // the examples from RFC 9000, appendix A.1
+        assert_eq!(
+            read_varint(&[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c]),
+            Some((151_288_809_941_952_652, 8))
+        );
+        assert_eq!(
+            read_varint(&[0x9d, 0x7f, 0x3e, 0x7d]),
+            Some((494_878_333, 4))
+        );
+        assert_eq!(read_varint(&[0x7b, 0xbd]), Some((15293, 2)));
+        assert_eq!(read_varint(&[0x25]), Some((37, 1)));
+        assert_eq!(read_varint(&[0x40, 0x25]), Some((37, 2)));
+        // trailing bytes are left alone, and truncated varints rejected
+        assert_eq!(read_varint(&[0x25, 0xff]), Some((37, 1)));
+        assert_eq!(read_varint(&[0x9d, 0x7f, 0x3e]), None);
+        assert_eq!(read_varint(&[]), None);
+    }
+}